@@ -0,0 +1,30 @@
+//! How long it takes to clone a big set of store paths.
+//!
+//! Run with `cargo bench --bench clone`.
+
+use std::time::Instant;
+
+use nix_remote::{NixString, StorePath, StorePathSet};
+
+fn main() {
+    let set = StorePathSet {
+        paths: (0..100_000)
+            .map(|i| {
+                StorePath(NixString::from(format!(
+                    "/nix/store/{i:032}-some-package-1.2.3"
+                )))
+            })
+            .collect(),
+    };
+
+    const ITERS: u32 = 100;
+    let start = Instant::now();
+    for _ in 0..ITERS {
+        std::hint::black_box(set.clone());
+    }
+    println!(
+        "cloning a set of {} store paths: {:?} per clone",
+        set.paths.len(),
+        start.elapsed() / ITERS
+    );
+}
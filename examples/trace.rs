@@ -0,0 +1,241 @@
+//! Decode a captured worker protocol conversation, for debugging.
+//!
+//! ```text
+//! cargo run --example trace -- CLIENT [DAEMON]
+//! ```
+//!
+//! `CLIENT` holds the raw bytes that a client sent, and `DAEMON` (if there is one) the
+//! raw bytes that the daemon sent back. `socat -r CLIENT -R DAEMON ...` between the
+//! client and the daemon's socket records them. The handshake, every op, and (given
+//! `DAEMON`) the stderr messages and reply for each op are printed to stdout.
+//!
+//! Decoding stops at the first thing that doesn't make sense, after printing the bytes
+//! that it choked on.
+
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{BufReader, Read, Write},
+};
+
+use anyhow::Context;
+use nix_remote::{
+    framed_data::FramedReader,
+    stderr,
+    worker_op::{self, SelfCheckPolicy, Stream, WorkerOp},
+    DaemonVersion, NixReadExt, NixString, StringSet,
+};
+
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+const WORKER_MAGIC_2: u64 = 0x6478696f;
+
+/// Longer values are cut off, so that an op with thousands of paths doesn't drown
+/// everything else.
+const MAX_SUMMARY: usize = 200;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args_os().skip(1);
+    let (Some(client), daemon, None) = (args.next(), args.next(), args.next()) else {
+        anyhow::bail!("usage: trace CLIENT [DAEMON]");
+    };
+    let open = |path| -> anyhow::Result<_> {
+        let file = File::open(&path).with_context(|| format!("opening {path:?}"))?;
+        Ok(BufReader::new(file))
+    };
+    let mut client = open(client)?;
+    let mut daemon = daemon.map(open).transpose()?;
+    trace(
+        &mut client,
+        daemon.as_mut().map(|d| d as &mut dyn Read),
+        &mut std::io::stdout().lock(),
+    )
+}
+
+fn trace(
+    mut client: &mut dyn Read,
+    mut daemon: Option<&mut dyn Read>,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let magic: u64 = client.read_nix()?;
+    if magic != WORKER_MAGIC_1 {
+        return raw(out, &format!("unexpected client magic {magic:x}"), client);
+    }
+    let client_version = DaemonVersion::from(client.read_nix::<u64>()?);
+    writeln!(out, "client: version {client_version}")?;
+
+    let mut version = client_version;
+    if let Some(daemon) = daemon.as_mut() {
+        let magic: u64 = daemon.read_nix()?;
+        if magic != WORKER_MAGIC_2 {
+            return raw(out, &format!("unexpected daemon magic {magic:x}"), daemon);
+        }
+        let daemon_version = DaemonVersion::from(daemon.read_nix::<u64>()?);
+        writeln!(out, "daemon: version {daemon_version}")?;
+        version = version.min(daemon_version);
+    }
+    writeln!(out, "negotiated version {version}")?;
+
+    if version.minor >= 38 {
+        let features: StringSet = client.read_nix()?;
+        writeln!(out, "client: features {}", summary(&features.paths))?;
+        if let Some(daemon) = daemon.as_mut() {
+            let features: StringSet = daemon.read_nix()?;
+            writeln!(out, "daemon: features {}", summary(&features.paths))?;
+        }
+    }
+    // The obsolete cpu affinity (followed by the cpu, if set) and reserve space fields.
+    if client.read_nix::<u64>()? != 0 {
+        client.read_nix::<u64>()?;
+    }
+    client.read_nix::<u64>()?;
+    if let Some(daemon) = daemon.as_mut() {
+        if version.minor >= 33 {
+            let identity: NixString = daemon.read_nix()?;
+            writeln!(out, "daemon: identity {identity:?}")?;
+        }
+        if version.minor >= 35 {
+            let trusted = match daemon.read_nix::<u64>()? {
+                0 => "unknown",
+                1 => "trusted",
+                2 => "not trusted",
+                n => return raw(out, &format!("unexpected trust flag {n}"), daemon),
+            };
+            writeln!(out, "daemon: trust {trusted}")?;
+        }
+        if !stderr_messages(daemon, out)? {
+            return Ok(());
+        }
+    }
+
+    loop {
+        let mut opcode = [0; 8];
+        match client.read_exact(&mut opcode) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                writeln!(out, "end of client stream")?;
+                return Ok(());
+            }
+            r => r?,
+        }
+        let n = u64::from_le_bytes(opcode);
+        if !worker_op::supported_opcodes().contains(&n) {
+            return raw(out, &format!("unknown opcode {n}"), client);
+        }
+        let op: WorkerOp = match (&opcode[..])
+            .chain(&mut *client)
+            .read_nix_versioned(version)
+        {
+            Ok(op) => op,
+            Err(e) => return raw(out, &format!("failed to decode opcode {n}: {e}"), client),
+        };
+        writeln!(out, "op {}", op_summary(&op))?;
+        if op.has_framed_source() {
+            let len = std::io::copy(&mut FramedReader::new(&mut *client), &mut std::io::sink())?;
+            writeln!(out, "  framed source: {len} bytes")?;
+        }
+
+        let Some(daemon) = daemon.as_mut() else {
+            continue;
+        };
+        if !stderr_messages(daemon, out)? {
+            continue;
+        }
+        let mut reply = Vec::new();
+        let decoded = op
+            .proxy_response_with(
+                &mut *daemon,
+                &mut reply,
+                version,
+                None,
+                SelfCheckPolicy::Ignore,
+            )
+            .and_then(|()| op.decode_response(&reply, version));
+        match decoded {
+            Ok(reply) => writeln!(out, "  reply: {}", summary(&reply))?,
+            Err(e) => return raw(out, &format!("failed to decode reply: {e}"), daemon),
+        }
+    }
+}
+
+/// Print the daemon's stderr messages up to the final one.
+///
+/// Returns whether a reply follows, which it doesn't after an error.
+fn stderr_messages(mut daemon: &mut dyn Read, out: &mut dyn Write) -> anyhow::Result<bool> {
+    loop {
+        let msg: stderr::Msg = daemon.read_nix()?;
+        match msg {
+            stderr::Msg::Last(()) => return Ok(true),
+            stderr::Msg::Error(e) => {
+                writeln!(out, "  error: {e}")?;
+                return Ok(false);
+            }
+            stderr::Msg::Next(s) => writeln!(out, "  log: {s:?}")?,
+            msg => writeln!(out, "  stderr: {}", summary(&msg))?,
+        }
+    }
+}
+
+/// Give up on decoding, and show what comes next.
+fn raw(out: &mut dyn Write, what: &str, read: &mut dyn Read) -> anyhow::Result<()> {
+    let mut bytes = Vec::new();
+    read.take(64).read_to_end(&mut bytes)?;
+    let hex: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    writeln!(out, "{what}, raw bytes: {}", hex.join(" "))?;
+    Ok(())
+}
+
+/// The op's request, without the marker for its reply type.
+fn op_summary(op: &WorkerOp) -> String {
+    let mut s = format!("{op:?}");
+    if let Some(resp) = s.rfind(", Resp {") {
+        s.replace_range(resp.., ")");
+    }
+    truncate(s)
+}
+
+fn summary(value: &impl Debug) -> String {
+    truncate(format!("{value:?}"))
+}
+
+fn truncate(mut s: String) -> String {
+    if s.len() > MAX_SUMMARY {
+        let mut end = MAX_SUMMARY;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+        s.push_str("...");
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_trace() {
+        let client = include_bytes!("../tests/data/trace/client.bin");
+        let daemon = include_bytes!("../tests/data/trace/daemon.bin");
+        let mut out = Vec::new();
+        trace(&mut &client[..], Some(&mut &daemon[..]), &mut out).unwrap();
+        expect_test::expect_file!["../tests/data/trace/expected.txt"]
+            .assert_eq(&String::from_utf8(out).unwrap());
+
+        // Without the daemon's side, only the ops are decoded.
+        let mut out = Vec::new();
+        trace(&mut &client[..], None, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("op IsValidPath("), "{out}");
+        assert!(!out.contains("reply"), "{out}");
+    }
+    #[test]
+    fn golden_trace_trusted() {
+        // From 1.35 on, the daemon's identity is followed by whether it trusts the client.
+        let client = include_bytes!("../tests/data/trace/client-1.35.bin");
+        let daemon = include_bytes!("../tests/data/trace/daemon-1.35.bin");
+        let mut out = Vec::new();
+        trace(&mut &client[..], Some(&mut &daemon[..]), &mut out).unwrap();
+        expect_test::expect_file!["../tests/data/trace/expected-1.35.txt"]
+            .assert_eq(&String::from_utf8(out).unwrap());
+    }
+}
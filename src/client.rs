@@ -0,0 +1,432 @@
+//! A client for the nix daemon.
+//!
+//! This is the other side of [`NixProxy`](crate::NixProxy): instead of accepting
+//! worker ops from a nix client, it sends them to a daemon and reads back the replies.
+
+use std::io::{Read, Write};
+
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    serialize::NixDeserializer,
+    stderr,
+    worker_op::{
+        CollectGarbage, CollectGarbageResponse, Plain, QueryMissing, QueryMissingResponse,
+        QueryPathInfoResponse, QueryValidPaths, Resp, ValidPathInfo, VerifyStore, WorkerOp,
+    },
+    Error, NixReadExt, NixString, NixWriteExt, OptionalStorePath, Result, StorePath, StorePathSet,
+    PROTOCOL_VERSION, WORKER_MAGIC_1, WORKER_MAGIC_2,
+};
+
+#[cfg(feature = "tokio")]
+mod async_client;
+#[cfg(feature = "tokio")]
+pub use async_client::AsyncNixClient;
+
+/// A connection to a nix daemon.
+pub struct NixClient<R, W> {
+    pub read: R,
+    pub write: W,
+}
+
+impl<R: Read, W: Write> NixClient<R, W> {
+    pub fn new(read: R, write: W) -> Self {
+        Self { read, write }
+    }
+
+    /// Perform the version negotiation with the daemon.
+    ///
+    /// Returns the daemon's protocol version.
+    pub fn handshake(&mut self) -> Result<u64> {
+        self.write.write_nix(&WORKER_MAGIC_1)?;
+        self.write.flush()?;
+        let magic: u64 = self.read.read_nix()?;
+        if magic != WORKER_MAGIC_2 {
+            Err(anyhow!("unexpected WORKER_MAGIC_2: got {magic:x}"))?;
+        }
+        let daemon_version: u64 = self.read.read_nix()?;
+        if daemon_version < PROTOCOL_VERSION.into() {
+            Err(anyhow!("daemon version {daemon_version} is too old"))?;
+        }
+
+        self.write.write_nix(&u64::from(PROTOCOL_VERSION))?;
+        self.write.write_nix(&0u64)?; // cpu affinity, obsolete
+        self.write.write_nix(&0u64)?; // reserve space, obsolete
+        self.write.flush()?;
+        let _daemon_identity: NixString = self.read.read_nix()?;
+        self.process_stderr(|_| {})?;
+        Ok(daemon_version)
+    }
+
+    /// Read stderr messages from the daemon until the final one.
+    ///
+    /// Each message (other than the final one) is passed to `on_msg`. If the daemon
+    /// sends an error, it is returned as an error.
+    fn process_stderr(&mut self, mut on_msg: impl FnMut(stderr::Msg)) -> Result<()> {
+        loop {
+            let msg: stderr::Msg = self.read.read_nix()?;
+            match msg {
+                stderr::Msg::Last(()) => return Ok(()),
+                stderr::Msg::Error(e) => return Err(Error::UpstreamDaemon(e)),
+                msg => on_msg(msg),
+            }
+        }
+    }
+
+    /// Send a worker op (without any framed source), and read back its reply.
+    fn op<T: DeserializeOwned>(&mut self, op: WorkerOp) -> Result<T> {
+        self.op_with_progress(op, |_| {})
+    }
+
+    /// Like [`NixClient::op`], but passes the daemon's stderr messages to `on_progress`.
+    fn op_with_progress<T: DeserializeOwned>(
+        &mut self,
+        op: WorkerOp,
+        on_progress: impl FnMut(stderr::Msg),
+    ) -> Result<T> {
+        self.write.write_nix(&op)?;
+        self.write.flush()?;
+        self.process_stderr(on_progress)?;
+        Ok(self.read.read_nix()?)
+    }
+
+    /// Look up a store path from the hash part of its name.
+    ///
+    /// Returns `None` if the daemon doesn't know of any path with that hash.
+    pub fn query_path_from_hash_part(&mut self, hash: NixString) -> Result<Option<StorePath>> {
+        let path: OptionalStorePath =
+            self.op(WorkerOp::QueryPathFromHashPart(Plain(hash), Resp::new()))?;
+        Ok(path.into())
+    }
+
+    /// Query the daemon's information about a store path.
+    ///
+    /// Returns `None` if the path isn't valid.
+    pub fn query_path_info(&mut self, path: StorePath) -> Result<Option<ValidPathInfo>> {
+        let resp: QueryPathInfoResponse =
+            self.op(WorkerOp::QueryPathInfo(Plain(path), Resp::new()))?;
+        Ok(resp.path)
+    }
+
+    /// Find out which of `paths` are valid.
+    ///
+    /// If `substitute` is true, the daemon first tries to substitute the ones that
+    /// aren't. The reply is passed on as the daemon sent it, which can include repeats
+    /// (if `paths` did); see [`NixClient::query_valid_paths_deduped`].
+    pub fn query_valid_paths(
+        &mut self,
+        paths: Vec<StorePath>,
+        substitute: bool,
+    ) -> Result<StorePathSet> {
+        self.op(WorkerOp::QueryValidPaths(
+            Plain(QueryValidPaths {
+                paths: StorePathSet { paths },
+                builders_use_substitutes: substitute,
+            }),
+            Resp::new(),
+        ))
+    }
+
+    /// Like [`NixClient::query_valid_paths`], but with the reply sorted and without
+    /// repeats (see [`StorePathSet::dedup_sorted`]).
+    pub fn query_valid_paths_deduped(
+        &mut self,
+        paths: Vec<StorePath>,
+        substitute: bool,
+    ) -> Result<StorePathSet> {
+        let mut valid = self.query_valid_paths(paths, substitute)?;
+        valid.dedup_sorted();
+        Ok(valid)
+    }
+
+    /// Pass every valid path in the store to `f`.
+    ///
+    /// The daemon sends all of them in a single reply, which can be huge; this decodes
+    /// the paths one at a time instead of collecting them into a [`StorePathSet`].
+    pub fn query_all_valid_paths_streaming(&mut self, f: impl FnMut(StorePath)) -> Result<()> {
+        self.write
+            .write_nix(&WorkerOp::QueryAllValidPaths(Plain(()), Resp::new()))?;
+        self.write.flush()?;
+        self.process_stderr(|_| {})?;
+        NixDeserializer::new(&mut self.read).read_seq_with(f)?;
+        Ok(())
+    }
+
+    /// Fetch the NAR serialization of a store path, unless it is bigger than `max_size`.
+    ///
+    /// The protocol doesn't send the size ahead of the NAR, so we first look it up with
+    /// [`NixClient::query_path_info`] and bail out with [`Error::NarTooLarge`] before
+    /// requesting anything big. The returned reader yields exactly the NAR; it must be
+    /// read to the end before the connection is used again.
+    pub fn nar_from_path_checked(
+        &mut self,
+        path: StorePath,
+        max_size: u64,
+    ) -> Result<impl Read + '_> {
+        let Some(info) = self.query_path_info(path.clone())? else {
+            Err(anyhow!("path {path:?} is not valid"))?
+        };
+        if info.nar_size > max_size {
+            return Err(Error::NarTooLarge {
+                path,
+                size: info.nar_size,
+                max_size,
+            });
+        }
+
+        self.write
+            .write_nix(&WorkerOp::NarFromPath(Plain(path), Resp::new()))?;
+        self.write.flush()?;
+        self.process_stderr(|_| {})?;
+        Ok((&mut self.read).take(info.nar_size))
+    }
+
+    /// Find out what would need to be built or substituted to get `paths`.
+    ///
+    /// The paths in the response are in the daemon's order, which can change from one
+    /// call to the next; see [`NixClient::query_missing_sorted`].
+    pub fn query_missing(&mut self, paths: Vec<StorePath>) -> Result<QueryMissingResponse> {
+        self.op(WorkerOp::QueryMissing(
+            Plain(QueryMissing { paths }),
+            Resp::new(),
+        ))
+    }
+
+    /// Like [`NixClient::query_missing`], but with the paths in each set sorted.
+    pub fn query_missing_sorted(&mut self, paths: Vec<StorePath>) -> Result<QueryMissingResponse> {
+        Ok(self.query_missing(paths)?.sorted())
+    }
+
+    /// Ask the daemon to collect garbage.
+    ///
+    /// The request is checked with [`CollectGarbage::validate`] first, and isn't sent
+    /// if it's malformed.
+    pub fn collect_garbage(&mut self, gc: CollectGarbage) -> Result<CollectGarbageResponse> {
+        gc.validate()?;
+        self.op(WorkerOp::CollectGarbage(Plain(gc), Resp::new()))
+    }
+
+    /// Ask the daemon to deduplicate files in the store.
+    ///
+    /// This can take a long time; the daemon's log messages and activities are passed
+    /// to `on_progress` as they arrive.
+    pub fn optimise_store(&mut self, on_progress: impl FnMut(stderr::Msg)) -> Result<()> {
+        let _: u64 =
+            self.op_with_progress(WorkerOp::OptimiseStore(Plain(()), Resp::new()), on_progress)?;
+        Ok(())
+    }
+
+    /// Ask the daemon to check the consistency of the store.
+    ///
+    /// Returns true if any errors were found. As with [`NixClient::optimise_store`],
+    /// progress messages are passed to `on_progress`.
+    pub fn verify_store(
+        &mut self,
+        check_contents: bool,
+        repair: bool,
+        on_progress: impl FnMut(stderr::Msg),
+    ) -> Result<bool> {
+        let op = WorkerOp::VerifyStore(
+            Plain(VerifyStore {
+                check_contents,
+                repair,
+            }),
+            Resp::new(),
+        );
+        self.op_with_progress(op, on_progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn client(reply: &impl serde::Serialize) -> NixClient<Cursor<Vec<u8>>, Vec<u8>> {
+        client_with_stderr(&[], reply)
+    }
+
+    fn client_with_stderr(
+        msgs: &[stderr::Msg],
+        reply: &impl serde::Serialize,
+    ) -> NixClient<Cursor<Vec<u8>>, Vec<u8>> {
+        let mut daemon = Vec::new();
+        for msg in msgs {
+            daemon.write_nix(msg).unwrap();
+        }
+        daemon.write_nix(&stderr::Msg::Last(())).unwrap();
+        daemon.write_nix(reply).unwrap();
+        NixClient::new(Cursor::new(daemon), Vec::new())
+    }
+
+    fn start_activity(act: u64, typ: stderr::ActivityType) -> stderr::Msg {
+        stderr::Msg::StartActivity(stderr::StderrStartActivity {
+            act,
+            lvl: 3,
+            typ,
+            s: Default::default(),
+            fields: stderr::LoggerFields { fields: vec![] },
+            parent: 0,
+        })
+    }
+
+    #[test]
+    fn query_path_from_hash_part() {
+        let path = StorePath(NixString::from(
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo".to_vec(),
+        ));
+        let hash = NixString::from(b"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q".to_vec());
+
+        let mut found = client(&path);
+        assert_eq!(
+            found.query_path_from_hash_part(hash.clone()).unwrap(),
+            Some(path)
+        );
+
+        let mut missing = client(&NixString::default());
+        assert_eq!(missing.query_path_from_hash_part(hash).unwrap(), None);
+    }
+
+    fn path_info(nar_size: u64) -> QueryPathInfoResponse {
+        QueryPathInfoResponse {
+            path: Some(ValidPathInfo {
+                deriver: OptionalStorePath(None),
+                hash: crate::NarHash::from_bytes(&[0; 32]),
+                references: Default::default(),
+                registration_time: 0,
+                nar_size,
+                ultimate: false,
+                sigs: Default::default(),
+                content_address: Default::default(),
+            }),
+        }
+    }
+
+    #[test]
+    fn query_all_valid_paths_streaming() {
+        let paths: Vec<StorePath> = ["foo", "bar", "baz"]
+            .iter()
+            .map(|name| StorePath(NixString::from(format!("/nix/store/abc-{name}"))))
+            .collect();
+        let mut client = client(&crate::StorePathSet {
+            paths: paths.clone(),
+        });
+
+        let mut seen = Vec::new();
+        client
+            .query_all_valid_paths_streaming(|p| seen.push(p))
+            .unwrap();
+        assert_eq!(seen, paths);
+        assert_eq!(client.read.position(), client.read.get_ref().len() as u64);
+    }
+
+    #[test]
+    fn query_valid_paths_deduped() {
+        let [foo, bar] =
+            ["foo", "bar"].map(|name| StorePath(NixString::from(format!("/nix/store/abc-{name}"))));
+        let reply = StorePathSet {
+            paths: vec![foo.clone(), bar.clone(), foo.clone()],
+        };
+
+        let valid = client(&reply)
+            .query_valid_paths(vec![foo.clone(), bar.clone()], false)
+            .unwrap();
+        assert_eq!(valid, reply);
+
+        let mut deduped = client(&reply);
+        let valid = deduped
+            .query_valid_paths_deduped(vec![foo.clone(), bar.clone()], true)
+            .unwrap();
+        assert_eq!(valid.paths, vec![bar.clone(), foo.clone()]);
+        let sent: WorkerOp = crate::from_bytes(&deduped.write).unwrap();
+        let WorkerOp::QueryValidPaths(Plain(query), _) = sent else {
+            panic!("expected QueryValidPaths, got {sent:?}");
+        };
+        assert_eq!(query.paths.paths, vec![foo, bar]);
+        assert!(query.builders_use_substitutes);
+    }
+
+    #[test]
+    fn daemon_error() {
+        use std::error::Error as _;
+
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let msgs = [stderr::Msg::Error(stderr::StderrError::new(
+            "path '/nix/store/abc-foo' is not valid",
+        ))];
+        let err = client_with_stderr(&msgs, &())
+            .query_path_info(path)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::UpstreamDaemon(_)), "{err:?}");
+        assert_eq!(
+            err.to_string(),
+            "Daemon error: path '/nix/store/abc-foo' is not valid"
+        );
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "path '/nix/store/abc-foo' is not valid"
+        );
+    }
+
+    #[test]
+    fn nar_too_large() {
+        let path = StorePath(NixString::from(
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo".to_vec(),
+        ));
+        let mut client = client(&path_info(1 << 30));
+        match client.nar_from_path_checked(path.clone(), 1024) {
+            Err(Error::NarTooLarge {
+                path: p,
+                size,
+                max_size,
+            }) => {
+                assert_eq!(p, path);
+                assert_eq!(size, 1 << 30);
+                assert_eq!(max_size, 1024);
+            }
+            Err(e) => panic!("unexpected error {e:?}"),
+            Ok(_) => panic!("expected an error"),
+        }
+
+        // We should only have asked for the path info, not the NAR.
+        let sent: WorkerOp = crate::from_bytes(&client.write).unwrap();
+        assert_eq!(sent, WorkerOp::QueryPathInfo(Plain(path), Resp::new()));
+    }
+
+    #[test]
+    fn invalid_gc_request() {
+        let gc = CollectGarbage::new(
+            crate::worker_op::GcAction::DeleteSpecific,
+            crate::StorePathSet::default(),
+        );
+        let mut client = client(&());
+        let err = client.collect_garbage(gc).unwrap_err();
+        assert!(matches!(err, Error::InvalidGcRequest { .. }), "{err:?}");
+        assert!(client.write.is_empty());
+    }
+
+    #[test]
+    fn store_maintenance_progress() {
+        let msgs = vec![
+            start_activity(1, stderr::ActivityType::OptimiseStore),
+            stderr::Msg::Next(NixString::from(b"3 files deduplicated".to_vec())),
+            stderr::Msg::StopActivity(1),
+        ];
+
+        let mut progress = Vec::new();
+        client_with_stderr(&msgs, &1u64)
+            .optimise_store(|msg| progress.push(msg))
+            .unwrap();
+        assert_eq!(progress, msgs);
+
+        let mut progress = Vec::new();
+        let errors = client_with_stderr(&msgs, &true)
+            .verify_store(true, false, |msg| progress.push(msg))
+            .unwrap();
+        assert!(errors);
+        assert_eq!(progress, msgs);
+    }
+}
@@ -0,0 +1,228 @@
+use std::io;
+
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    serialize::NixDeserializer,
+    stderr,
+    worker_op::{
+        Plain, QueryMissing, QueryMissingResponse, QueryPathInfoResponse, Resp, ValidPathInfo,
+        WorkerOp,
+    },
+    Error, NixString, OptionalStorePath, Result, StorePath, PROTOCOL_VERSION, WORKER_MAGIC_1,
+    WORKER_MAGIC_2,
+};
+
+/// A connection to a nix daemon, for use from async code.
+///
+/// This is [`NixClient`](super::NixClient) on top of tokio's [`AsyncRead`] and
+/// [`AsyncWrite`]. Each op is written in one go, and its stderr messages and reply are
+/// decoded as soon as enough of them has arrived, without blocking the thread while
+/// waiting for the daemon.
+///
+/// Replies are buffered in full before they are decoded, so this isn't suited to ops
+/// with huge replies like `QueryAllValidPaths`.
+pub struct AsyncNixClient<R, W> {
+    pub read: R,
+    pub write: W,
+    // Bytes that have been read from the daemon but not decoded yet.
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncNixClient<R, W> {
+    pub fn new(read: R, write: W) -> Self {
+        Self {
+            read,
+            write,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Perform the version negotiation with the daemon.
+    ///
+    /// Returns the daemon's protocol version.
+    pub async fn handshake(&mut self) -> Result<u64> {
+        self.send(&WORKER_MAGIC_1).await?;
+        let magic: u64 = self.recv().await?;
+        if magic != WORKER_MAGIC_2 {
+            Err(anyhow!("unexpected WORKER_MAGIC_2: got {magic:x}"))?;
+        }
+        let daemon_version: u64 = self.recv().await?;
+        if daemon_version < PROTOCOL_VERSION.into() {
+            Err(anyhow!("daemon version {daemon_version} is too old"))?;
+        }
+
+        // The obsolete cpu affinity and reserve space fields.
+        self.send(&(u64::from(PROTOCOL_VERSION), 0u64, 0u64))
+            .await?;
+        let _daemon_identity: NixString = self.recv().await?;
+        self.process_stderr().await?;
+        Ok(daemon_version)
+    }
+
+    /// Check whether `path` is a valid path in the daemon's store.
+    pub async fn is_valid_path(&mut self, path: StorePath) -> Result<bool> {
+        self.op(WorkerOp::IsValidPath(Plain(path), Resp::new()))
+            .await
+    }
+
+    /// Query the daemon's information about a store path.
+    ///
+    /// Returns `None` if the path isn't valid.
+    pub async fn query_path_info(&mut self, path: StorePath) -> Result<Option<ValidPathInfo>> {
+        let resp: QueryPathInfoResponse = self
+            .op(WorkerOp::QueryPathInfo(Plain(path), Resp::new()))
+            .await?;
+        Ok(resp.path)
+    }
+
+    /// Look up a store path from the hash part of its name.
+    ///
+    /// Returns `None` if the daemon doesn't know of any path with that hash.
+    pub async fn query_path_from_hash_part(
+        &mut self,
+        hash: NixString,
+    ) -> Result<Option<StorePath>> {
+        let path: OptionalStorePath = self
+            .op(WorkerOp::QueryPathFromHashPart(Plain(hash), Resp::new()))
+            .await?;
+        Ok(path.into())
+    }
+
+    /// Find out what would need to be built or substituted to get `paths`.
+    pub async fn query_missing(&mut self, paths: Vec<StorePath>) -> Result<QueryMissingResponse> {
+        self.op(WorkerOp::QueryMissing(
+            Plain(QueryMissing { paths }),
+            Resp::new(),
+        ))
+        .await
+    }
+
+    /// Send a worker op (without any framed source), and read back its reply.
+    async fn op<T: DeserializeOwned>(&mut self, op: WorkerOp) -> Result<T> {
+        self.send(&op).await?;
+        self.process_stderr().await?;
+        self.recv().await
+    }
+
+    /// Read stderr messages from the daemon until the final one, returning an error if
+    /// the daemon sends one.
+    async fn process_stderr(&mut self) -> Result<()> {
+        loop {
+            match self.recv().await? {
+                stderr::Msg::Last(()) => return Ok(()),
+                stderr::Msg::Error(e) => return Err(Error::UpstreamDaemon(e)),
+                _ => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, value: &impl Serialize) -> Result<()> {
+        self.write.write_all(&crate::to_vec(value)?).await?;
+        self.write.flush().await?;
+        Ok(())
+    }
+
+    // Decode a value from the start of the buffer, reading more from the daemon for as
+    // long as the buffer ends partway through it.
+    async fn recv<T: DeserializeOwned>(&mut self) -> Result<T> {
+        loop {
+            let mut rest = &self.buf[..];
+            let err = match T::deserialize(&mut NixDeserializer::new(&mut rest)) {
+                Ok(value) => {
+                    let used = self.buf.len() - rest.len();
+                    self.buf.drain(..used);
+                    return Ok(value);
+                }
+                Err(e) => e,
+            };
+            if err.io_error().map(io::Error::kind) != Some(io::ErrorKind::UnexpectedEof) {
+                return Err(err.into());
+            }
+
+            // Grow the buffer geometrically, so that a long reply isn't decoded from
+            // scratch after every small read.
+            self.buf.reserve(self.buf.len().max(4096));
+            if self.read.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::ScriptedDaemon, StorePathSet};
+
+    #[tokio::test]
+    async fn ops() {
+        let path = StorePath(NixString::from(
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo".to_vec(),
+        ));
+        let hash = NixString::from(b"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q".to_vec());
+        let missing = QueryMissingResponse {
+            will_build: StorePathSet {
+                paths: vec![path.clone()],
+            },
+            will_substitute: StorePathSet::default(),
+            unknown: StorePathSet::default(),
+            download_size: 0,
+            nar_size: 0,
+        };
+        let log = stderr::Msg::Next(NixString::from(b"querying".to_vec()));
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&WorkerOp::IsValidPath(Plain(path.clone()), Resp::new()))
+            .reply(std::slice::from_ref(&log), &true)
+            .expect_nix(&WorkerOp::QueryPathInfo(Plain(path.clone()), Resp::new()))
+            .reply(&[], &QueryPathInfoResponse { path: None })
+            .expect_nix(&WorkerOp::QueryPathFromHashPart(
+                Plain(hash.clone()),
+                Resp::new(),
+            ))
+            .reply(&[log], &path)
+            .expect_nix(&WorkerOp::QueryMissing(
+                Plain(QueryMissing {
+                    paths: vec![path.clone()],
+                }),
+                Resp::new(),
+            ))
+            .reply(&[], &missing);
+        let (read, write) = daemon.io();
+
+        let mut client = AsyncNixClient::new(read, write);
+        assert_eq!(
+            client.handshake().await.unwrap(),
+            u64::from(PROTOCOL_VERSION)
+        );
+        assert!(client.is_valid_path(path.clone()).await.unwrap());
+        assert_eq!(client.query_path_info(path.clone()).await.unwrap(), None);
+        assert_eq!(
+            client.query_path_from_hash_part(hash).await.unwrap(),
+            Some(path.clone())
+        );
+        assert_eq!(client.query_missing(vec![path]).await.unwrap(), missing);
+        daemon.finish();
+    }
+
+    #[tokio::test]
+    async fn daemon_error() {
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let daemon = ScriptedDaemon::new()
+            .expect_nix(&WorkerOp::QueryPathInfo(Plain(path.clone()), Resp::new()))
+            .respond_nix(&stderr::Msg::Error(stderr::StderrError::new(
+                "path '/nix/store/abc-foo' is not valid",
+            )));
+        let (read, write) = daemon.io();
+
+        let err = AsyncNixClient::new(read, write)
+            .query_path_info(path)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UpstreamDaemon(_)), "{err:?}");
+        daemon.finish();
+    }
+}
@@ -0,0 +1,163 @@
+//! Content addressing methods.
+//!
+//! Content-addressed store paths record how their contents were hashed: the method
+//! (`text`, or `fixed` with some file ingestion method) and the hash algorithm. Nix
+//! renders these as strings like `text:sha256` or `fixed:r:sha256`.
+
+use std::fmt;
+
+use anyhow::anyhow;
+
+use crate::Result;
+
+/// A hash algorithm supported by nix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgo {
+    pub fn parse(s: &str) -> Result<HashAlgo> {
+        match s {
+            "md5" => Ok(HashAlgo::Md5),
+            "sha1" => Ok(HashAlgo::Sha1),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512" => Ok(HashAlgo::Sha512),
+            _ => Err(anyhow!("unknown hash algorithm `{s}`"))?,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Md5 => "md5",
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How a file system object gets turned into bytes for hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileIngestionMethod {
+    /// Hash the contents of a single regular file.
+    Flat,
+    /// Hash the NAR serialization (rendered as `r:`).
+    Recursive,
+    /// Hash as a git tree (rendered as `git:`).
+    Git,
+}
+
+impl FileIngestionMethod {
+    /// Split a file ingestion method prefix off `s`.
+    fn parse_prefix(s: &str) -> (FileIngestionMethod, &str) {
+        if let Some(rest) = s.strip_prefix("r:") {
+            (FileIngestionMethod::Recursive, rest)
+        } else if let Some(rest) = s.strip_prefix("git:") {
+            (FileIngestionMethod::Git, rest)
+        } else {
+            (FileIngestionMethod::Flat, s)
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            FileIngestionMethod::Flat => "",
+            FileIngestionMethod::Recursive => "r:",
+            FileIngestionMethod::Git => "git:",
+        }
+    }
+}
+
+/// A content addressing method, together with its hash algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CaMethod {
+    /// `text:<algo>`, used for derivations and `builtins.toFile`.
+    Text(HashAlgo),
+    /// `fixed:[r:|git:]<algo>`, used for fixed-output derivations and `nix store add`.
+    Fixed(FileIngestionMethod, HashAlgo),
+}
+
+impl CaMethod {
+    /// Parse a rendered method, like `fixed:r:sha256`.
+    pub fn parse(s: &[u8]) -> Result<CaMethod> {
+        let s = std::str::from_utf8(s).map_err(|_| anyhow!("invalid content address method"))?;
+        if let Some(algo) = s.strip_prefix("text:") {
+            Ok(CaMethod::Text(HashAlgo::parse(algo)?))
+        } else if let Some(rest) = s.strip_prefix("fixed:") {
+            let (method, algo) = FileIngestionMethod::parse_prefix(rest);
+            Ok(CaMethod::Fixed(method, HashAlgo::parse(algo)?))
+        } else {
+            Err(anyhow!("unknown content address method `{s}`"))?
+        }
+    }
+
+    /// Parse the method as it appears in a derivation output, like `r:sha256`.
+    ///
+    /// Derivations leave off the `fixed:` prefix, but keep `text:`.
+    pub fn parse_derivation_output(s: &[u8]) -> Result<CaMethod> {
+        let s = std::str::from_utf8(s).map_err(|_| anyhow!("invalid output hash algorithm"))?;
+        if let Some(algo) = s.strip_prefix("text:") {
+            Ok(CaMethod::Text(HashAlgo::parse(algo)?))
+        } else {
+            let (method, algo) = FileIngestionMethod::parse_prefix(s);
+            Ok(CaMethod::Fixed(method, HashAlgo::parse(algo)?))
+        }
+    }
+
+    pub fn algo(self) -> HashAlgo {
+        match self {
+            CaMethod::Text(algo) | CaMethod::Fixed(_, algo) => algo,
+        }
+    }
+}
+
+impl fmt::Display for CaMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaMethod::Text(algo) => write!(f, "text:{algo}"),
+            CaMethod::Fixed(method, algo) => write!(f, "fixed:{}{algo}", method.prefix()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ca_method() {
+        let cases = [
+            ("text:sha256", CaMethod::Text(HashAlgo::Sha256)),
+            (
+                "fixed:sha1",
+                CaMethod::Fixed(FileIngestionMethod::Flat, HashAlgo::Sha1),
+            ),
+            (
+                "fixed:r:sha256",
+                CaMethod::Fixed(FileIngestionMethod::Recursive, HashAlgo::Sha256),
+            ),
+            (
+                "fixed:git:sha1",
+                CaMethod::Fixed(FileIngestionMethod::Git, HashAlgo::Sha1),
+            ),
+        ];
+        for (s, method) in cases {
+            assert_eq!(CaMethod::parse(s.as_bytes()).unwrap(), method);
+            assert_eq!(method.to_string(), s);
+        }
+
+        for garbage in ["", "text", "text:sha3", "fixed:r:", "/nix/store/abc-foo"] {
+            assert!(CaMethod::parse(garbage.as_bytes()).is_err(), "{garbage}");
+        }
+    }
+}
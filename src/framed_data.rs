@@ -4,7 +4,12 @@ use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use std::io::{Read, Write};
 
-use crate::Result;
+use crate::{
+    hash::Hasher,
+    serialize::{NixReadExt, Tee},
+    worker_op::ValidPathInfo,
+    DaemonVersion, Error, NarHashPolicy, NixString, Result, StorePath,
+};
 
 /// Nix "framed data" stored in memory.
 ///
@@ -31,7 +36,7 @@ impl std::fmt::Debug for FramedData {
 
 impl FramedData {
     pub fn read(mut r: impl Read) -> Result<FramedData> {
-        let mut de = crate::serialize::NixDeserializer { read: &mut r };
+        let mut de = crate::serialize::NixDeserializer::new(&mut r);
 
         let mut ret = FramedData::default();
         loop {
@@ -48,7 +53,7 @@ impl FramedData {
     }
 
     pub fn write(&self, mut w: impl Write) -> Result<()> {
-        let mut ser = crate::serialize::NixSerializer { write: &mut w };
+        let mut ser = crate::serialize::NixSerializer::new(&mut w);
 
         for data in &self.data {
             (data.len() as u64).serialize(&mut ser)?;
@@ -59,25 +64,517 @@ impl FramedData {
     }
 }
 
+/// A reader for the contents of framed data.
+///
+/// This reads the frames from the underlying reader as they are needed, and yields
+/// the data in them. It stops at the terminating empty frame.
+pub struct FramedReader<R> {
+    read: R,
+    // How much of the current frame is left.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(read: R) -> Self {
+        FramedReader {
+            read,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Read for FramedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let mut len = [0; 8];
+            self.read.read_exact(&mut len)?;
+            self.remaining = u64::from_le_bytes(len);
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max_len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.read.read(&mut buf[..max_len])?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
 /// Stream framed data from a `std::io::Read` to a `std::io::Write`.
 pub fn stream(read: &mut impl Read, write: &mut impl Write) -> anyhow::Result<()> {
-    let mut de = crate::serialize::NixDeserializer { read };
-    let mut ser = crate::serialize::NixSerializer { write };
+    Ok(stream_with_check(read, write, &mut ())?)
+}
+
+/// Like [`stream`], but hashes the data as it goes past.
+///
+/// Once all the data has been copied, but before the terminating empty frame is
+/// written, `check` gets to look at the digest and decide whether to go ahead. If it
+/// fails, the framed data is left unterminated, so the receiving end won't mistake it
+/// for complete.
+pub fn stream_checked<H: Hasher>(
+    read: &mut impl Read,
+    write: &mut impl Write,
+    hasher: H,
+    check: impl FnOnce(Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    stream_with_check(read, write, &mut HashCheck::new(hasher, check))
+}
+
+/// Like [`stream`], but lets `check` look at the data as it goes past.
+///
+/// If `check` fails, either on some data or at the end, streaming stops and the
+/// framed data is left unterminated, so the receiving end won't mistake it for
+/// complete.
+pub fn stream_with_check(
+    read: &mut impl Read,
+    write: &mut impl Write,
+    check: &mut (impl FrameCheck + ?Sized),
+) -> Result<()> {
+    let mut de = crate::serialize::NixDeserializer::new(read);
+    let mut ser = crate::serialize::NixSerializer::new(write);
+    copy_frames(&mut de, &mut ser, check)?;
+    check.finish()?;
+    0_u64.serialize(&mut ser)?;
+    Ok(())
+}
+
+/// Whether [`stream_with_control`] should carry on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamControl {
+    Continue,
+    Abort,
+}
+
+/// Like [`stream`], but asks `control` before forwarding each frame.
+///
+/// `control` is called with the number of bytes forwarded so far and the length of the
+/// next frame, so it can also be used to report progress. If it says to abort, the
+/// rest of the framed data is read and thrown away (leaving `read` at the end of it,
+/// as if it had been streamed), the framed data written so far is left unterminated,
+/// and this fails with [`Error::Aborted`](crate::Error::Aborted).
+pub fn stream_with_control(
+    read: &mut impl Read,
+    write: &mut impl Write,
+    mut control: impl FnMut(u64, u64) -> StreamControl,
+) -> Result<()> {
+    let mut de = crate::serialize::NixDeserializer::new(read);
+    let mut ser = crate::serialize::NixSerializer::new(write);
+    let mut offset = 0;
+    loop {
+        let len = u64::deserialize(&mut de)?;
+        if len == 0 {
+            break;
+        }
+        let mut frame = (&mut de.read).take(len);
+        if control(offset, len) == StreamControl::Abort {
+            std::io::copy(&mut frame, &mut std::io::sink())?;
+            std::io::copy(&mut FramedReader::new(&mut de.read), &mut std::io::sink())?;
+            return Err(crate::Error::Aborted);
+        }
+        len.serialize(&mut ser)?;
+        if std::io::copy(&mut frame, &mut ser.write)? < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        offset += len;
+    }
+    0_u64.serialize(&mut ser)?;
+    Ok(())
+}
+
+/// Stream the framed source of an `AddMultipleToStore` op, checking the hash of each NAR
+/// in it.
+///
+/// The source is a count, followed by that many store paths, each with its path info and
+/// NAR. They are decoded as they go past, one at a time, and each NAR is hashed with `H`
+/// and compared with the hash in its path info. A mismatch is handled according to
+/// `policy`: with [`NarHashPolicy::Fail`], this fails with [`Error::NarHashMismatch`] and
+/// the framed data is left unterminated, so the daemon doesn't take the paths before
+/// the bad one as a complete request either. A declared hash that can't be parsed is
+/// treated the same way, except that with [`NarHashPolicy::Warn`] that NAR just isn't
+/// checked.
+///
+/// The data is re-framed on the way, so the frames that `write` sees needn't line up
+/// with the ones that were read.
+pub fn stream_add_multiple_checked<H: Hasher + Default>(
+    read: &mut impl Read,
+    write: &mut impl Write,
+    version: DaemonVersion,
+    policy: NarHashPolicy,
+) -> Result<()> {
+    let mut source = FramedReader::new(read);
+    let mut sink = FramedWriter::new(write);
+
+    let count: u64 = Tee::new(&mut source, &mut sink).read_nix_versioned(version)?;
+    for _ in 0..count {
+        let (path, info): (StorePath, ValidPathInfo) =
+            Tee::new(&mut source, &mut sink).read_nix_versioned(version)?;
+        // A hash that we can't parse can't be checked, which is as bad as a mismatch.
+        let declared = match (info.hash.digest(), policy) {
+            (Ok(declared), _) => Some(declared),
+            (Err(e), NarHashPolicy::Warn) => {
+                eprintln!("warning: not checking the NAR hash of {path:?}: {e}");
+                None
+            }
+            (Err(e), NarHashPolicy::Fail) => return Err(e),
+        };
+        let (actual, _) = crate::nar::stream_hashing(&mut source, &mut sink, H::default())?;
+        if declared
+            .as_ref()
+            .is_some_and(|declared| actual != *declared)
+        {
+            let err = Error::NarHashMismatch {
+                path,
+                declared: NixString::from(info.hash.data.to_vec()),
+                actual: actual.iter().map(|b| format!("{b:02x}")).collect(),
+            };
+            match policy {
+                NarHashPolicy::Warn => eprintln!("warning: {err}"),
+                NarHashPolicy::Fail => return Err(err),
+            }
+        }
+    }
+    // There shouldn't be anything else, but if there is, it's up to the daemon to
+    // complain about it.
+    std::io::copy(&mut source, &mut sink)?;
+    sink.finish()
+}
+
+// Writes framed data, collecting small writes into bigger frames.
+struct FramedWriter<W> {
+    write: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> FramedWriter<W> {
+    const FRAME_SIZE: usize = 32 * 1024;
+
+    fn new(write: W) -> Self {
+        FramedWriter {
+            write,
+            buf: Vec::with_capacity(Self::FRAME_SIZE),
+        }
+    }
+
+    fn write_frame(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            self.write
+                .write_all(&(self.buf.len() as u64).to_le_bytes())?;
+            self.write.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    // Write out what's left, and the terminating empty frame.
+    fn finish(mut self) -> Result<()> {
+        self.write_frame()?;
+        self.write.write_all(&0u64.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for FramedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(Self::FRAME_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == Self::FRAME_SIZE {
+            self.write_frame()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_frame()?;
+        self.write.flush()
+    }
+}
+
+/// A check on framed data, for [`stream_with_check`].
+///
+/// Checks can be combined: a pair of checks runs both, and `None` runs none.
+pub trait FrameCheck {
+    /// Look at the next piece of data.
+    fn update(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Decide, after all the data has gone past, whether it was acceptable.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// No check at all.
+impl FrameCheck for () {
+    fn update(&mut self, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<A: FrameCheck, B: FrameCheck> FrameCheck for (A, B) {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        self.0.update(data)?;
+        self.1.update(data)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.0.finish()?;
+        self.1.finish()
+    }
+}
+
+impl<C: FrameCheck> FrameCheck for Option<C> {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        self.as_mut().map_or(Ok(()), |c| c.update(data))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.as_mut().map_or(Ok(()), |c| c.finish())
+    }
+}
+
+impl<C: FrameCheck + ?Sized> FrameCheck for Box<C> {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        (**self).update(data)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Hashes the data, and passes the digest to a closure at the end.
+pub struct HashCheck<H, F> {
+    // These are only `None` once the check has finished.
+    hasher: Option<H>,
+    check: Option<F>,
+}
+
+impl<H: Hasher, F: FnOnce(Vec<u8>) -> Result<()>> HashCheck<H, F> {
+    pub fn new(hasher: H, check: F) -> Self {
+        HashCheck {
+            hasher: Some(hasher),
+            check: Some(check),
+        }
+    }
+}
+
+impl<H: Hasher, F: FnOnce(Vec<u8>) -> Result<()>> FrameCheck for HashCheck<H, F> {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(data);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match (self.hasher.take(), self.check.take()) {
+            (Some(hasher), Some(check)) => check(hasher.finish()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Copy all the frames, except the terminating empty one.
+fn copy_frames(
+    de: &mut crate::serialize::NixDeserializer,
+    ser: &mut crate::serialize::NixSerializer,
+    check: &mut (impl FrameCheck + ?Sized),
+) -> Result<()> {
     const BUF_SIZE: usize = 4096;
     let mut buf = vec![0; BUF_SIZE];
 
     loop {
-        let mut len = u64::deserialize(&mut de)? as usize;
-        (len as u64).serialize(&mut ser)?;
+        let mut len = u64::deserialize(&mut *de)? as usize;
         if len == 0 {
-            break;
+            return Ok(());
         }
+        (len as u64).serialize(&mut *ser)?;
         while len > 0 {
             let chunk_len = len.min(BUF_SIZE);
             de.read.read_exact(&mut buf[..chunk_len])?;
+            check.update(&buf[..chunk_len])?;
             ser.write.write_all(&buf[..chunk_len])?;
             len -= chunk_len;
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_source() {
+        // An empty source (like the contents of an empty file) is just the terminator.
+        let input = crate::to_vec(&(0u64, 42u64)).unwrap();
+        let mut read = &input[..];
+        let mut forwarded = Vec::new();
+        stream(&mut read, &mut forwarded).unwrap();
+        assert_eq!(forwarded, 0u64.to_le_bytes());
+        assert_eq!(read, crate::to_vec(&42u64).unwrap());
+        assert!(FramedData::read(&forwarded[..]).unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn abort_mid_stream() {
+        let frames = FramedData {
+            data: ["one", "two", "three"]
+                .iter()
+                .map(|s| ByteBuf::from(s.as_bytes().to_vec()))
+                .collect(),
+        };
+        let mut input = Vec::new();
+        frames.write(&mut input).unwrap();
+        input.extend(crate::to_vec(&42u64).unwrap());
+
+        let mut seen = Vec::new();
+        let mut read = &input[..];
+        let mut forwarded = Vec::new();
+        let err = stream_with_control(&mut read, &mut forwarded, |offset, len| {
+            seen.push((offset, len));
+            if offset > 0 {
+                StreamControl::Abort
+            } else {
+                StreamControl::Continue
+            }
+        })
+        .unwrap_err();
+
+        assert!(matches!(err, crate::Error::Aborted), "{err:?}");
+        assert_eq!(seen, [(0, 3), (3, 3)]);
+        // Only the first frame went through, and it wasn't terminated.
+        assert_eq!(forwarded, [&3u64.to_le_bytes()[..], b"one"].concat());
+        // The rest of the framed data was skipped, leaving whatever comes after it.
+        assert_eq!(read, crate::to_vec(&42u64).unwrap());
+
+        let mut read = &input[..];
+        let mut forwarded = Vec::new();
+        stream_with_control(&mut read, &mut forwarded, |_, _| StreamControl::Continue).unwrap();
+        assert_eq!(FramedData::read(&forwarded[..]).unwrap().data, frames.data);
+    }
+
+    #[cfg(feature = "hash-sha2")]
+    #[test]
+    fn add_multiple_checked() {
+        use crate::{nar, NarHash, OptionalStorePath, StorePathSet, StringSet, PROTOCOL_VERSION};
+
+        let entry_with_hash = |name: &str, contents: &str, hash: String| {
+            let path = StorePath(NixString::from(
+                format!("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-{name}").into_bytes(),
+            ));
+            let nar = nar::Nar::Contents(nar::NarFile {
+                contents: NixString::from(contents.as_bytes().to_vec()),
+                executable: false,
+            });
+            let info = ValidPathInfo {
+                deriver: OptionalStorePath(None),
+                hash: NarHash {
+                    data: ByteBuf::from(hash.into_bytes()),
+                },
+                references: StorePathSet::default(),
+                registration_time: 0,
+                nar_size: crate::to_vec(&nar).unwrap().len() as u64,
+                ultimate: false,
+                sigs: StringSet::default(),
+                content_address: NixString::default(),
+            };
+            crate::to_vec(&(path, info, nar)).unwrap()
+        };
+        let entry = |name: &str, contents: &str, hash_of: &str| {
+            let mut hasher = crate::hash::Sha256::default();
+            hasher.update(
+                &crate::to_vec(&nar::Nar::Contents(nar::NarFile {
+                    contents: NixString::from(hash_of.as_bytes().to_vec()),
+                    executable: false,
+                }))
+                .unwrap(),
+            );
+            let hex = hasher.finish().iter().map(|b| format!("{b:02x}")).collect();
+            entry_with_hash(name, contents, hex)
+        };
+        // Split into small frames, so that entries straddle them.
+        let framed = |entries: &[Vec<u8>]| {
+            let payload = [
+                crate::to_vec(&(entries.len() as u64)).unwrap(),
+                entries.concat(),
+            ]
+            .concat();
+            let frames = FramedData {
+                data: payload
+                    .chunks(100)
+                    .map(|c| ByteBuf::from(c.to_vec()))
+                    .collect(),
+            };
+            let mut input = Vec::new();
+            frames.write(&mut input).unwrap();
+            (payload, input)
+        };
+        let run = |input: &[u8], policy| {
+            let mut forwarded = Vec::new();
+            let result = stream_add_multiple_checked::<crate::hash::Sha256>(
+                &mut &input[..],
+                &mut forwarded,
+                PROTOCOL_VERSION,
+                policy,
+            );
+            (result, forwarded)
+        };
+        let unframe = |forwarded: &[u8]| {
+            let frames = FramedData::read(forwarded).unwrap();
+            frames
+                .data
+                .iter()
+                .flat_map(|d| d.iter().copied())
+                .collect::<Vec<_>>()
+        };
+
+        let good = [entry("foo", "foo", "foo"), entry("bar", "bar", "bar")];
+        let (payload, input) = framed(&good);
+        let (result, forwarded) = run(&input, NarHashPolicy::Fail);
+        result.unwrap();
+        assert_eq!(unframe(&forwarded), payload);
+
+        let bad = [entry("foo", "foo", "foo"), entry("bar", "bar", "baz")];
+        let (payload, input) = framed(&bad);
+        let (result, forwarded) = run(&input, NarHashPolicy::Fail);
+        match result {
+            Err(Error::NarHashMismatch { path, .. }) => {
+                assert!(path.0 .0.ends_with(b"-bar"), "{path:?}")
+            }
+            r => panic!("expected NarHashMismatch, got {r:?}"),
+        }
+        // The daemon never sees the end of the source.
+        assert!(FramedData::read(&forwarded[..]).is_err());
+
+        let (result, forwarded) = run(&input, NarHashPolicy::Warn);
+        result.unwrap();
+        assert_eq!(unframe(&forwarded), payload);
+
+        // A hash that can't be parsed fails like a mismatch, unless we only warn.
+        let garbled = [
+            entry_with_hash("foo", "foo", "not a hash".to_owned()),
+            entry("bar", "bar", "bar"),
+        ];
+        let (payload, input) = framed(&garbled);
+        let (result, forwarded) = run(&input, NarHashPolicy::Fail);
+        assert!(result.is_err());
+        assert!(FramedData::read(&forwarded[..]).is_err());
+
+        let (result, forwarded) = run(&input, NarHashPolicy::Warn);
+        result.unwrap();
+        assert_eq!(unframe(&forwarded), payload);
+    }
 }
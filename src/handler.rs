@@ -0,0 +1,151 @@
+//! Answering worker ops locally, instead of forwarding them to a nix daemon.
+//!
+//! See [`NixProxy::with_handler`](crate::NixProxy::with_handler).
+
+use std::io::{Read, Write};
+
+use anyhow::anyhow;
+
+use crate::{stderr, worker_op::WorkerOp, DaemonVersion, NixWriteExt, Result, StorePathSet};
+
+/// Something that can answer worker ops.
+pub trait WorkerOpHandler {
+    /// Answer `op`, returning its reply encoded in the wire format of protocol `version`,
+    /// the one negotiated with the client (some replies, like `BuildResult`, depend on it).
+    ///
+    /// If the op has a framed source (like `AddToStoreNar`), its contents can be read
+    /// from `source`; anything left unread is skipped. For other ops, `source` is empty.
+    ///
+    /// Log messages and activities for the client (like a build's progress) can be
+    /// sent with `progress` while the op is being handled; they arrive ahead of the reply.
+    ///
+    /// An error is passed on to the client as a stderr error message, and the
+    /// connection carries on.
+    fn handle(
+        &mut self,
+        op: &WorkerOp,
+        version: DaemonVersion,
+        source: &mut dyn Read,
+        progress: &mut ProgressSink<'_>,
+    ) -> Result<Vec<u8>>;
+}
+
+/// Sends stderr messages to the client while a [`WorkerOpHandler`] answers an op.
+pub struct ProgressSink<'a> {
+    write: &'a mut dyn Write,
+}
+
+impl<'a> ProgressSink<'a> {
+    pub fn new(write: &'a mut dyn Write) -> Self {
+        ProgressSink { write }
+    }
+
+    /// Send `msg` to the client straight away.
+    ///
+    /// `Last` and `Error` messages are refused, since they end the op: the final one is
+    /// sent after the handler returns, and an error should be returned from the handler
+    /// instead.
+    pub fn send(&mut self, msg: &stderr::Msg) -> Result<()> {
+        if matches!(msg, stderr::Msg::Last(_) | stderr::Msg::Error(_)) {
+            Err(anyhow!(
+                "{:?} messages can't be sent as progress",
+                msg.opcode()
+            ))?;
+        }
+        self.write.write_nix(msg)?;
+        self.write.flush()?;
+        Ok(())
+    }
+
+    /// Send a log line to the client.
+    pub fn log(&mut self, line: impl Into<String>) -> Result<()> {
+        self.send(&stderr::Msg::Next(line.into().into()))
+    }
+}
+
+type QueryValidPathsHook = Box<dyn Fn(&StorePathSet, bool) -> StorePathSet + Send>;
+
+/// A [`WorkerOpHandler`] that is put together from hooks for individual ops.
+///
+/// The hooks only see decoded requests and return decoded replies; the wire format is
+/// taken care of here. Ops without a hook are refused with an error.
+#[derive(Default)]
+pub struct OpHooks {
+    /// Answer `QueryValidPaths`, given the paths and the `builders_use_substitutes` flag.
+    ///
+    /// This is the place to consult substituters for paths that aren't valid locally.
+    pub on_query_valid_paths: Option<QueryValidPathsHook>,
+}
+
+impl WorkerOpHandler for OpHooks {
+    fn handle(
+        &mut self,
+        op: &WorkerOp,
+        version: DaemonVersion,
+        _source: &mut dyn Read,
+        _progress: &mut ProgressSink<'_>,
+    ) -> Result<Vec<u8>> {
+        match (op, &self.on_query_valid_paths) {
+            (WorkerOp::QueryValidPaths(query, resp), Some(hook)) => {
+                let valid = hook(&query.paths, query.builders_use_substitutes);
+                let mut reply = Vec::new();
+                reply.write_nix_versioned(&resp.ty(valid), version)?;
+                Ok(reply)
+            }
+            _ => Err(anyhow!("operation '{}' is not supported", op.name()))?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        worker_op::{Plain, QueryValidPaths, Resp},
+        NixString, StorePath, PROTOCOL_VERSION,
+    };
+
+    #[test]
+    fn query_valid_paths_hook() {
+        let path = |name: &str| StorePath(NixString::from(format!("/nix/store/abc-{name}")));
+        let mut hooks = OpHooks {
+            on_query_valid_paths: Some(Box::new(move |paths, use_substitutes| {
+                assert!(use_substitutes);
+                let mut paths = paths.clone();
+                paths.paths.push(path("substituted"));
+                paths
+            })),
+        };
+
+        let op = WorkerOp::QueryValidPaths(
+            Plain(QueryValidPaths {
+                paths: StorePathSet {
+                    paths: vec![path("local")],
+                },
+                builders_use_substitutes: true,
+            }),
+            Resp::new(),
+        );
+        let mut progress = Vec::new();
+        let reply = hooks
+            .handle(
+                &op,
+                PROTOCOL_VERSION,
+                &mut std::io::empty(),
+                &mut ProgressSink::new(&mut progress),
+            )
+            .unwrap();
+        let expected = StorePathSet {
+            paths: vec![path("local"), path("substituted")],
+        };
+        assert_eq!(reply, crate::to_vec(&expected).unwrap());
+        assert!(progress.is_empty());
+
+        let op = WorkerOp::QueryAllValidPaths(Plain(()), Resp::new());
+        let mut sink = std::io::sink();
+        let mut progress = ProgressSink::new(&mut sink);
+        assert!(hooks
+            .handle(&op, PROTOCOL_VERSION, &mut std::io::empty(), &mut progress)
+            .is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Hashing, with a pluggable backend.
+//!
+//! Nix hashes NARs with SHA-256. By default we use the `sha2` crate for that (behind the
+//! `hash-sha2` feature), but anything implementing [`Hasher`] will do; for example, a
+//! wrapper around `ring` or `openssl` if those are already in your dependency tree.
+
+use std::io::Write;
+
+use crate::content_address::HashAlgo;
+
+/// An incremental hash function.
+pub trait Hasher {
+    /// The algorithm this computes.
+    fn algo(&self) -> HashAlgo;
+
+    /// Feed some more data into the hash.
+    fn update(&mut self, data: &[u8]);
+
+    /// The digest of everything passed to [`Hasher::update`].
+    fn finish(self) -> Vec<u8>;
+}
+
+/// SHA-256, as implemented by the `sha2` crate.
+#[cfg(feature = "hash-sha2")]
+#[derive(Clone, Default)]
+pub struct Sha256(sha2::Sha256);
+
+#[cfg(feature = "hash-sha2")]
+impl Hasher for Sha256 {
+    fn algo(&self) -> HashAlgo {
+        HashAlgo::Sha256
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data)
+    }
+
+    fn finish(self) -> Vec<u8> {
+        sha2::Digest::finalize(self.0).to_vec()
+    }
+}
+
+/// A writer that hashes (and counts) everything written to it, before passing it on.
+pub struct HashingWriter<W, H> {
+    write: W,
+    hasher: H,
+    len: u64,
+}
+
+impl<W: Write, H: Hasher> HashingWriter<W, H> {
+    pub fn new(write: W, hasher: H) -> Self {
+        HashingWriter {
+            write,
+            hasher,
+            len: 0,
+        }
+    }
+
+    /// The digest and length of everything written.
+    pub fn finish(self) -> (Vec<u8>, u64) {
+        (self.hasher.finish(), self.len)
+    }
+}
+
+impl<W: Write, H: Hasher> Write for HashingWriter<W, H> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.write.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write.flush()
+    }
+}
+
+#[cfg(all(test, feature = "hash-sha2"))]
+mod tests {
+    use super::*;
+    use crate::nixbase32;
+
+    #[test]
+    fn sha256() {
+        let mut hasher = Sha256::default();
+        hasher.update(b"");
+        assert_eq!(
+            nixbase32::encode(&hasher.finish()),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+
+        let mut w = HashingWriter::new(Vec::new(), Sha256::default());
+        w.write_all(b"hello ").unwrap();
+        w.write_all(b"world").unwrap();
+        let mut hasher = Sha256::default();
+        hasher.update(b"hello world");
+        assert_eq!(w.finish(), (hasher.finish(), 11));
+    }
+
+    #[test]
+    fn stream_nar() {
+        let nar = crate::nar::Nar::Contents(crate::nar::NarFile {
+            contents: crate::NixString::from(b"hello".to_vec()),
+            executable: true,
+        });
+        let bytes = crate::to_vec(&nar).unwrap();
+
+        let mut out = Vec::new();
+        let (digest, size) =
+            crate::nar::stream_hashing(&bytes[..], &mut out, Sha256::default()).unwrap();
+        let mut hasher = Sha256::default();
+        hasher.update(&bytes);
+        assert_eq!(out, bytes);
+        assert_eq!(size, bytes.len() as u64);
+        assert_eq!(digest, hasher.finish());
+    }
+}
@@ -0,0 +1,47 @@
+//! Metrics about the proxy, reported through the [`metrics`](https://docs.rs/metrics)
+//! facade when the `metrics` feature is enabled. Without it, these functions do nothing.
+//!
+//! The metrics are:
+//! - `nix_remote_connections_total`: connections that [`NixProxy::process_connection`]
+//!   has started on.
+//! - `nix_remote_ops_total`, labelled with `op`: ops read from clients.
+//! - `nix_remote_bytes_up_total` and `nix_remote_bytes_down_total`: bytes sent to and
+//!   received from upstream daemons.
+//! - `nix_remote_op_duration_seconds`, labelled with `op`: a histogram of the time from
+//!   reading an op to forwarding the daemon's reply to the client.
+//! - `nix_remote_self_check_mismatches_total`, labelled with `op`: daemon responses that
+//!   changed after a roundtrip (see [`SelfCheckPolicy`]).
+//!
+//! [`NixProxy::process_connection`]: crate::NixProxy::process_connection
+//! [`SelfCheckPolicy`]: crate::worker_op::SelfCheckPolicy
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+
+pub(crate) fn connection() {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("nix_remote_connections_total").increment(1);
+}
+
+pub(crate) fn op(name: &'static str) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("nix_remote_ops_total", "op" => name).increment(1);
+}
+
+pub(crate) fn upstream_bytes(up: u64, down: u64) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("nix_remote_bytes_up_total").increment(up);
+        metrics::counter!("nix_remote_bytes_down_total").increment(down);
+    }
+}
+
+pub(crate) fn op_duration(name: &'static str, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("nix_remote_op_duration_seconds", "op" => name).record(duration);
+}
+
+pub(crate) fn self_check_mismatch(name: &'static str) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("nix_remote_self_check_mismatches_total", "op" => name).increment(1);
+}
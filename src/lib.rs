@@ -1,20 +1,33 @@
 use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
-use serialize::NixSerializer;
+use serialize::{NixSerializer, PathRewrite};
 use std::{
+    collections::HashMap,
     ffi::OsStr,
     io::{Read, Write},
     os::unix::prelude::OsStrExt,
     string::FromUtf8Error,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use worker_op::ValidPathInfo;
+use framed_data::FrameCheck;
+use handler::WorkerOpHandler;
+use worker_op::{AddToStoreNar, SelfCheckPolicy, ValidPathInfo, Verbosity};
 
+pub mod client;
+pub mod content_address;
 pub mod framed_data;
+pub mod handler;
+pub mod hash;
+mod instrument;
 pub mod nar;
+pub mod nixbase32;
 pub mod serialize;
 pub mod stderr;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 pub mod worker_op;
 
 pub use serialize::{NixReadExt, NixWriteExt};
@@ -56,13 +69,147 @@ pub enum Error {
 
     #[error("Other error: {0}")]
     Other(#[from] anyhow::Error),
+
+    /// A daemon response to `op` didn't survive being decoded and re-encoded.
+    #[error("Response to {op} changed after a roundtrip: expected {expected:?}, got {got:?}")]
+    RoundtripMismatch {
+        op: String,
+        expected: Vec<u8>,
+        got: Vec<u8>,
+    },
+
+    /// The client closed its connection while we were still talking to it.
+    #[error("Client disconnected")]
+    ClientDisconnected,
+
+    /// The client didn't finish the handshake within the time set by
+    /// [`NixProxy::set_handshake_timeout`].
+    #[error("Client didn't complete the handshake in time")]
+    HandshakeTimeout,
+
+    /// The daemon that we were talking to reported an error.
+    #[error("Daemon error: {0}")]
+    UpstreamDaemon(#[source] stderr::StderrError),
+
+    /// The client speaks an older protocol version than we are willing to.
+    #[error("Client version {got} is too old; the minimum is {minimum}")]
+    ClientTooOld {
+        got: DaemonVersion,
+        minimum: DaemonVersion,
+    },
+
+    /// The NAR that a client sent for `path` doesn't have the hash it said it would.
+    #[error("NAR for {path:?} has hash {actual}, but the client declared {declared:?}")]
+    NarHashMismatch {
+        path: StorePath,
+        declared: NixString,
+        actual: String,
+    },
+
+    /// The NAR that a client sent for `path` isn't the size it said it would be.
+    #[error("NAR for {path:?} is {actual} bytes, but the client declared {declared}")]
+    NarSizeMismatch {
+        path: StorePath,
+        declared: u64,
+        actual: u64,
+    },
+
+    /// The NAR for `path` is bigger than the caller was willing to accept.
+    #[error("NAR for {path:?} is {size} bytes, more than the maximum of {max_size}")]
+    NarTooLarge {
+        path: StorePath,
+        size: u64,
+        max_size: u64,
+    },
+
+    /// A `CollectGarbage` request whose paths don't match its action: `DeleteSpecific`
+    /// needs some paths to delete, and the other actions mustn't have any.
+    #[error("Invalid garbage collection request: {action:?} with {paths} paths to delete")]
+    InvalidGcRequest {
+        action: worker_op::GcAction,
+        paths: usize,
+    },
+
+    /// A request that failed [`WorkerOp::validate`].
+    #[error("Invalid {op} request: {reason}")]
+    InvalidOp { op: String, reason: String },
+
+    /// A daemon reply that listed the same derivation output more than once (see
+    /// [`worker_op::DerivationOutputMap::to_map`]).
+    #[error("Duplicate derivation output {0:?}")]
+    DuplicateOutput(NixString),
+
+    /// Streaming was stopped by the caller (see [`framed_data::stream_with_control`]).
+    #[error("Aborted")]
+    Aborted,
+
+    /// The client sent something that isn't allowed in the connection's current state,
+    /// like an unknown opcode where an op should be.
+    #[error("Protocol violation: unexpected {got} in state {state:?}")]
+    ProtocolStateViolation { state: ConnState, got: String },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq, Hash)]
+impl Error {
+    /// Turn a timed-out read from the client into [`Error::HandshakeTimeout`].
+    fn or_handshake_timeout(self) -> Error {
+        let timed_out = match &self {
+            Error::Io(e) => Some(e),
+            Error::Deser(e) => e.io_error(),
+            _ => None,
+        }
+        .is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            )
+        });
+        if timed_out {
+            Error::HandshakeTimeout
+        } else {
+            self
+        }
+    }
+
+    /// Turn a failed write to the client into [`Error::ClientDisconnected`], if that's
+    /// what it looks like.
+    ///
+    /// Clients commonly go away mid-response (e.g. when the user hits Ctrl-C), and
+    /// that shouldn't be reported the same way as a protocol failure.
+    fn or_client_disconnected(self) -> Error {
+        fn is_disconnect(e: &std::io::Error) -> bool {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset
+            )
+        }
+
+        let disconnected = match &self {
+            Error::Io(e) => is_disconnect(e),
+            Error::Deser(e) => e.io_error().is_some_and(is_disconnect),
+            Error::Other(e) => e.chain().any(|e| {
+                e.downcast_ref::<std::io::Error>()
+                    .is_some_and(is_disconnect)
+                    || e.downcast_ref::<serialize::Error>()
+                        .and_then(serialize::Error::io_error)
+                        .is_some_and(is_disconnect)
+            }),
+            _ => false,
+        };
+        if disconnected {
+            Error::ClientDisconnected
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq, Hash, Default)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-#[serde(transparent)]
+// Not transparent, so that serializers can tell paths apart from other strings
+// (see `serialize::PathRewrite` and `NixDeserializer::validate_store_paths`).
+#[serde(rename = "__nix_remote_store_path")]
 pub struct StorePath(pub NixString);
 
 impl AsRef<[u8]> for StorePath {
@@ -71,9 +218,158 @@ impl AsRef<[u8]> for StorePath {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq)]
+/// The store directory. Nix allows this to be configured, but we don't (yet).
+pub const STORE_DIR: &[u8] = b"/nix/store";
+
+impl StorePath {
+    /// Check that `path` looks like `/nix/store/<hash>-<name>`.
+    pub fn parse(path: &[u8]) -> Result<StorePath> {
+        let base = path
+            .strip_prefix(STORE_DIR)
+            .and_then(|p| p.strip_prefix(b"/"))
+            .ok_or_else(|| invalid_store_path(path, "not in the store"))?;
+        split_base_name(path, base)?;
+        Ok(StorePath(NixString::from(path.to_vec())))
+    }
+
+    /// Like [`StorePath::parse`], but only says what is wrong with `path`, if anything.
+    pub(crate) fn check(path: &[u8]) -> Result<(), &'static str> {
+        let base = path
+            .strip_prefix(STORE_DIR)
+            .and_then(|p| p.strip_prefix(b"/"))
+            .ok_or("not in the store")?;
+        check_base_name(base).map(|_| ())
+    }
+
+    /// The hash part of this path's name, like `g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q`.
+    ///
+    /// The path may or may not include a store directory.
+    pub fn hash_part(&self) -> Result<&[u8]> {
+        Ok(self.split()?.0)
+    }
+
+    /// Compare two paths by their hash part and name, ignoring which store directory (if
+    /// any) they are in.
+    ///
+    /// Paths that can't be parsed are compared byte by byte.
+    pub fn eq_ignoring_store_dir(&self, other: &StorePath) -> bool {
+        match (self.split(), other.split()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => self == other,
+        }
+    }
+
+    // Split the path into its hash part and name.
+    fn split(&self) -> Result<(&[u8], &[u8])> {
+        let path: &[u8] = self.as_ref();
+        let base = match path.iter().rposition(|&c| c == b'/') {
+            Some(i) => &path[i + 1..],
+            None => path,
+        };
+        split_base_name(path, base)
+    }
+}
+
+fn invalid_store_path(path: &[u8], reason: &str) -> Error {
+    anyhow!(
+        "invalid store path {:?}: {reason}",
+        String::from_utf8_lossy(path)
+    )
+    .into()
+}
+
+// Check that `base` (the last component of `path`) looks like `<hash>-<name>`, and split it.
+fn split_base_name<'a>(path: &[u8], base: &'a [u8]) -> Result<(&'a [u8], &'a [u8])> {
+    check_base_name(base).map_err(|reason| invalid_store_path(path, reason))
+}
+
+fn check_base_name(base: &[u8]) -> Result<(&[u8], &[u8]), &'static str> {
+    if base.len() < HASH_PART_LEN + 2 || base[HASH_PART_LEN] != b'-' {
+        return Err("missing hash part");
+    }
+    let (hash, name) = (&base[..HASH_PART_LEN], &base[HASH_PART_LEN + 1..]);
+    if !hash.iter().all(|c| nixbase32::ALPHABET.contains(c)) {
+        return Err("hash part isn't base-32");
+    }
+    if name.starts_with(b".")
+        || !name
+            .iter()
+            .all(|&c| c.is_ascii_alphanumeric() || b"+-._?=".contains(&c))
+    {
+        return Err("bad name");
+    }
+    Ok((hash, name))
+}
+
+/// The length of the hash part of a store path, in base-32 characters.
+const HASH_PART_LEN: usize = 32;
+
+impl TryFrom<Path> for StorePath {
+    type Error = Error;
+
+    fn try_from(path: Path) -> Result<StorePath> {
+        StorePath::parse(path.as_ref())
+    }
+}
+
+impl From<StorePath> for Path {
+    fn from(path: StorePath) -> Path {
+        Path(path.0)
+    }
+}
+
+impl From<StorePath> for NixString {
+    fn from(path: StorePath) -> NixString {
+        path.0
+    }
+}
+
+/// A store path that might be missing.
+///
+/// On the wire, a missing path is represented as an empty string.
+#[derive(Clone, PartialEq, Debug, Eq, Hash, Default)]
+pub struct OptionalStorePath(pub Option<StorePath>);
+
+impl Serialize for OptionalStorePath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0 {
+            Some(path) => path.serialize(serializer),
+            None => NixString::default().serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OptionalStorePath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Named differently from `StorePath`, so that validating deserializers let the
+        // empty string through.
+        #[derive(Deserialize)]
+        #[serde(rename = "__nix_remote_optional_store_path")]
+        struct Optional(NixString);
+
+        let Optional(path) = Optional::deserialize(deserializer)?;
+        Ok(OptionalStorePath(
+            (!path.0.is_empty()).then_some(StorePath(path)),
+        ))
+    }
+}
+
+impl From<Option<StorePath>> for OptionalStorePath {
+    fn from(path: Option<StorePath>) -> Self {
+        OptionalStorePath(path)
+    }
+}
+
+impl From<OptionalStorePath> for Option<StorePath> {
+    fn from(path: OptionalStorePath) -> Self {
+        path.0
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq, Hash)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-#[serde(transparent)]
+// Not transparent, like `StorePath`.
+#[serde(rename = "__nix_remote_path")]
 pub struct Path(pub NixString);
 
 impl AsRef<[u8]> for Path {
@@ -90,7 +386,8 @@ impl AsRef<OsStr> for Path {
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
-#[serde(transparent)]
+// Not transparent, like `StorePath`.
+#[serde(rename = "__nix_remote_path")]
 pub struct DerivedPath(pub NixString);
 
 impl AsRef<[u8]> for DerivedPath {
@@ -103,35 +400,86 @@ impl AsRef<[u8]> for DerivedPath {
 ///
 /// Strings in the nix protocol are not necessarily UTF-8, so this is
 /// different from the rust standard `String`.
-#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Default, Hash, PartialOrd, Ord)]
-#[serde(transparent)]
-pub struct NixString(pub ByteBuf);
+///
+/// The contents are immutable and reference-counted, so cloning is cheap. That
+/// matters because store paths (which are `NixString`s underneath) get cloned a lot.
+#[derive(Clone, PartialEq, Eq, Default, Hash, PartialOrd, Ord)]
+pub struct NixString(pub Arc<[u8]>);
 
 impl NixString {
     pub fn to_string(&self) -> Result<String, FromUtf8Error> {
-        String::from_utf8(self.0.as_slice().to_owned())
+        String::from_utf8(self.0.to_vec())
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        NixString(ByteBuf::from(bytes.to_vec()))
+        NixString(Arc::from(bytes))
+    }
+
+    /// The string, with any invalid UTF-8 replaced by `U+FFFD`.
+    pub fn to_string_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// The string, if it is valid UTF-8.
+    pub fn try_to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl Serialize for NixString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for NixString {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = NixString;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a string")
+            }
+
+            // The bytes are copied straight into the `Arc`, which is the only allocation.
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<NixString, E> {
+                Ok(NixString::from_bytes(v))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<NixString, E> {
+                Ok(NixString::from(v))
+            }
+        }
+
+        deserializer.deserialize_bytes(Visitor)
     }
 }
 
 impl From<String> for NixString {
     fn from(s: String) -> NixString {
-        NixString(ByteBuf::from(s.into_bytes()))
+        NixString::from(s.into_bytes())
     }
 }
 
 impl From<Vec<u8>> for NixString {
     fn from(s: Vec<u8>) -> NixString {
-        NixString(ByteBuf::from(s))
+        NixString(Arc::from(s))
     }
 }
 
+/// Text is shown as it is. Anything that isn't text (invalid UTF-8, or containing NUL
+/// bytes) is shown in hex, like `0x00ff`, so that logs don't lose any of it.
 impl std::fmt::Debug for NixString {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&String::from_utf8_lossy(&self.0))
+        match self.try_to_str() {
+            Some(s) if !s.contains('\0') => f.write_str(s),
+            _ => {
+                f.write_str("0x")?;
+                self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+        }
     }
 }
 
@@ -149,30 +497,239 @@ impl AsRef<OsStr> for NixString {
 
 const WORKER_MAGIC_1: u64 = 0x6e697863;
 const WORKER_MAGIC_2: u64 = 0x6478696f;
+
+/// The first protocol minor version in which the daemon says what it is in the handshake.
+const IDENTITY_MINOR: u8 = 33;
+/// The newest protocol version that we speak.
+///
+/// Clients and daemons that speak something newer are talked down to this version, so
+/// the handshake never gets as far as the parts that were added later: the trust flag
+/// (from 1.35) and the feature exchange (from 1.38).
 const PROTOCOL_VERSION: DaemonVersion = DaemonVersion {
     major: 1,
     minor: 34,
 };
 
+/// The oldest daemon that we'll proxy to. Before 1.25, ops like `AddToStore` sent their
+/// data in a different way, which we don't support.
+const MIN_UPSTREAM_VERSION: DaemonVersion = DaemonVersion {
+    major: 1,
+    minor: 25,
+};
+
+/// Overrides the protocol version that [`NixProxy`] advertises, for testing.
+const PROTOCOL_VERSION_VAR: &str = "NIX_REMOTE_RUST_PROTOCOL_VERSION";
+
+/// The protocol version to advertise: [`PROTOCOL_VERSION_VAR`] if it is set to
+/// something like `1.30`, and [`PROTOCOL_VERSION`] otherwise.
+///
+/// The override can only lower the version, and not below [`MIN_UPSTREAM_VERSION`]:
+/// we don't speak the newer versions, so advertising them would break the connection
+/// as soon as the client relied on them. Anything else is ignored with a warning.
+fn protocol_version_from_env() -> DaemonVersion {
+    let Ok(value) = std::env::var(PROTOCOL_VERSION_VAR) else {
+        return PROTOCOL_VERSION;
+    };
+    match value.parse::<DaemonVersion>() {
+        Ok(version) if (MIN_UPSTREAM_VERSION..=PROTOCOL_VERSION).contains(&version) => version,
+        Ok(version) => {
+            eprintln!(
+                "warning: ignoring {PROTOCOL_VERSION_VAR}={version}: \
+                 only versions {MIN_UPSTREAM_VERSION} to {PROTOCOL_VERSION} are supported"
+            );
+            PROTOCOL_VERSION
+        }
+        Err(e) => {
+            eprintln!("warning: ignoring {PROTOCOL_VERSION_VAR}={value:?}: {e}");
+            PROTOCOL_VERSION
+        }
+    }
+}
+
+/// How long a daemon that we spawned has to exit once we close its input.
+const DAEMON_EXIT_GRACE: Duration = Duration::from_secs(5);
+
 struct DaemonHandle {
-    child_in: std::process::ChildStdin,
-    child_out: std::process::ChildStdout,
+    child_in: Counted<Box<dyn Write + Send>>,
+    child_out: Counted<Box<dyn Read + Send>>,
+    // The daemon process, if we spawned one.
+    child: Option<std::process::Child>,
 }
 
 impl DaemonHandle {
     pub fn new() -> Self {
-        let mut child = std::process::Command::new("nix-daemon")
-            .arg("--stdio")
+        Self::local().unwrap()
+    }
+
+    /// Spawn the local `nix-daemon`.
+    fn local() -> std::io::Result<Self> {
+        let mut cmd = std::process::Command::new("nix-daemon");
+        cmd.arg("--stdio");
+        Self::spawn(cmd)
+    }
+
+    /// The command that runs `program` as a daemon for the store at `uri`.
+    fn store_command(program: &str, uri: &str) -> Result<std::process::Command> {
+        check_store_uri(uri)?;
+        let mut cmd = std::process::Command::new(program);
+        cmd.arg("--stdio").arg("--store").arg(uri);
+        Ok(cmd)
+    }
+
+    /// Spawn a daemon, talking to it over its stdin and stdout.
+    fn spawn(mut cmd: std::process::Command) -> std::io::Result<Self> {
+        let mut child = cmd
             .stdin(std::process::Stdio::piped())
             .stdout(std::process::Stdio::piped())
-            .spawn()
-            .unwrap();
+            .spawn()?;
+
+        Ok(Self {
+            child_in: Counted::new(Box::new(child.stdin.take().unwrap())),
+            child_out: Counted::new(Box::new(child.stdout.take().unwrap())),
+            child: Some(child),
+        })
+    }
+}
+
+impl DaemonHandle {
+    /// Close our end of the connection, so that the daemon sees EOF, and wait for the
+    /// daemon to exit if we spawned it.
+    ///
+    /// A daemon that is still running [`DAEMON_EXIT_GRACE`] later is killed.
+    fn close(&mut self) -> std::io::Result<()> {
+        self.close_within(DAEMON_EXIT_GRACE)
+    }
+
+    fn close_within(&mut self, grace: Duration) -> std::io::Result<()> {
+        self.child_in.flush()?;
+        self.child_in.inner = Box::new(std::io::sink());
+        if let Some(child) = &mut self.child {
+            // Nobody wants anything else the daemon says, but it mustn't block on a full
+            // pipe while exiting. The draining happens on its own thread so that a daemon
+            // that never closes its stdout can't hold us up; it ends with the pipe.
+            let mut out = std::mem::replace(&mut self.child_out.inner, Box::new(std::io::empty()));
+            std::thread::spawn(move || std::io::copy(&mut out, &mut std::io::sink()));
+
+            let deadline = Instant::now() + grace;
+            while child.try_wait()?.is_none() {
+                if Instant::now() >= deadline {
+                    eprintln!(
+                        "warning: daemon (pid {}) is still running {grace:?} after its input \
+                         closed, killing it",
+                        child.id()
+                    );
+                    child.kill()?;
+                    child.wait()?;
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        Ok(())
+    }
 
+    /// A handle that isn't connected to any daemon, for answering ops locally.
+    fn disconnected() -> Self {
         Self {
-            child_in: child.stdin.take().unwrap(),
-            child_out: child.stdout.take().unwrap(),
+            child_in: Counted::new(Box::new(std::io::sink())),
+            child_out: Counted::new(Box::new(std::io::empty())),
+            child: None,
+        }
+    }
+}
+
+// A rough check that `uri` looks like a nix store URI: `auto`, `daemon`, `local`, an
+// absolute path or `scheme://...`, optionally followed by `?settings`. Nix does the real
+// parsing; this just catches typos before we spawn anything.
+fn check_store_uri(uri: &str) -> Result<()> {
+    let store = uri.split_once('?').map_or(uri, |(store, _)| store);
+    let scheme = store.split_once("://").map(|(scheme, _)| scheme);
+    let valid = !uri.chars().any(|c| c.is_whitespace() || c.is_control())
+        && (matches!(store, "auto" | "daemon" | "local")
+            || store.starts_with('/')
+            || scheme.is_some_and(|scheme| {
+                !scheme.is_empty()
+                    && scheme
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+            }));
+    if !valid {
+        Err(anyhow!("invalid store URI {uri:?}"))?;
+    }
+    Ok(())
+}
+
+/// A reader or writer that counts the bytes going through it.
+pub(crate) struct Counted<T> {
+    pub(crate) inner: T,
+    pub(crate) count: u64,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Counted { inner, count: 0 }
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer to the client that remembers whether writing to it failed.
+///
+/// When a response is streamed from the daemon to the client, an error could come from
+/// either end; this tells us whether it's the client that went away.
+struct ClientWrite<W> {
+    inner: W,
+    failed: bool,
+}
+
+impl<W: Write> ClientWrite<W> {
+    fn new(inner: W) -> Self {
+        ClientWrite {
+            inner,
+            failed: false,
         }
     }
+
+    /// Blame `result`'s error on the client if it was writing to the client that failed.
+    fn blame<T>(&self, result: Result<T>) -> Result<T> {
+        if self.failed {
+            result.map_err(Error::or_client_disconnected)
+        } else {
+            result
+        }
+    }
+}
+
+impl<W: Write> Write for ClientWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let r = self.inner.write(buf);
+        self.failed |= r.is_err();
+        r
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let r = self.inner.flush();
+        self.failed |= r.is_err();
+        r
+    }
 }
 
 impl Default for DaemonHandle {
@@ -185,307 +742,4162 @@ impl Default for DaemonHandle {
 ///
 /// This doesn't currently *do* very much, it just inspects the protocol as it goes past.
 /// But it can be used to test our protocol implementation.
+///
+/// Instead of proxying, it can also answer ops itself; see [`NixProxy::with_handler`].
+///
+/// A `NixProxy<R, W>` is `Send` whenever `R` and `W` are, so a server can hand each
+/// connection to its own thread. Everything it holds on to (the daemon handle, the
+/// callbacks and the handler) is required to be `Send` for this reason.
 pub struct NixProxy<R, W> {
     pub read: NixRead<R>,
     pub write: NixWrite<W>,
     proxy: DaemonHandle,
+    cancel: CancellationToken,
+    handshake_timeout: Option<(Duration, SetReadTimeoutFn<R>)>,
+    handshake_deadline: Option<(Instant, SetReadTimeoutFn<R>)>,
+    op_policy: Option<OpPolicy>,
+    client_identity: Option<String>,
+    dry_run: bool,
+    verbosity_clamp: (Option<Verbosity>, Option<Verbosity>),
+    build_option_caps: (Option<u64>, Option<u64>),
+    nar_hash_check: Option<NarHashCheck>,
+    add_multiple_check: Option<AddMultipleCheck>,
+    check_nar_sizes: bool,
+    max_nar_size: Option<u64>,
+    rate_limit: Option<TokenBucket>,
+    protocol_version: DaemonVersion,
+    on_op: Option<OpObserver>,
+    handler: Option<Box<dyn WorkerOpHandler + Send>>,
+    store_dir_rewrite: Option<PathRewrite>,
+    // The upstream daemon's version, once we've shaken hands with it.
+    upstream_version: Option<DaemonVersion>,
+    upstream_identity: Option<DaemonIdentity>,
+    max_lifetime: Option<Duration>,
+    path_info_cache: Option<PathInfoCache>,
+    self_check: SelfCheckPolicy,
+    routes: Option<Routes>,
+    strict_validation: bool,
+    state: ConnState,
 }
 
-impl<R: Read, W: Write> NixProxy<R, W> {
-    pub fn new(r: R, w: W) -> Self {
-        Self {
-            read: NixRead { inner: r },
-            write: NixWrite { inner: w },
-            proxy: DaemonHandle::new(),
-        }
-    }
+/// Where a [`NixProxy`] is in its conversation with the client, which decides what the
+/// client may send next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    /// Waiting for the client's side of the handshake.
+    Handshaking,
+    /// Waiting for the client's next op.
+    Ready,
+    /// Reading the framed source that follows an op like `AddToStoreNar`.
+    StreamingSource,
+    /// Answering an op that has been read in full.
+    AwaitingReply,
 }
 
-/// A wrapper around a `std::io::Read`, adding support for the nix wire format.
-pub struct NixRead<R> {
-    pub inner: R,
+/// What happened during a connection, as returned by [`NixProxy::process_connection`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The protocol version negotiated with the client.
+    pub protocol_version: DaemonVersion,
+    /// The number of ops read from the client, including any that were rejected.
+    pub ops_processed: u64,
+    /// Bytes sent to the upstream daemon.
+    pub bytes_up: u64,
+    /// Bytes received from the upstream daemon.
+    pub bytes_down: u64,
+    pub duration: Duration,
+    /// What the upstream daemon said it was, if it said.
+    pub daemon_identity: Option<DaemonIdentity>,
 }
 
-/// A wrapper around a `std::io::Write`, adding support for the nix wire format.
-pub struct NixWrite<W> {
-    pub inner: W,
-}
+/// What a daemon says it is in the handshake, like `nix-daemon (Nix) 2.18.1`.
+///
+/// This is free-form, so anything not in UTF-8 is replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaemonIdentity(pub String);
 
-/// A set of paths.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct PathSet {
-    pub paths: Vec<Path>,
+impl DaemonIdentity {
+    /// The version at the end of the identity, if there seems to be one.
+    pub fn version(&self) -> Option<&str> {
+        let last = self.0.rsplit(' ').next()?;
+        last.starts_with(|c: char| c.is_ascii_digit())
+            .then_some(last)
+    }
 }
 
-/// A set of store paths.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct StorePathSet {
-    // TODO: in nix, they call `parseStorePath` to separate store directory from path
-    pub paths: Vec<StorePath>,
+impl std::fmt::Display for DaemonIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
-/// A set of strings.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct StringSet {
-    pub paths: Vec<NixString>,
+/// What an op policy knows about the client (see [`NixProxy::op_policy`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The protocol version negotiated with the client.
+    pub protocol_version: DaemonVersion,
+    /// Who the client is, as set with [`NixProxy::set_client_identity`]. The protocol
+    /// doesn't carry this, so it has to come from the transport (like an ssh user).
+    pub identity: Option<String>,
 }
 
-/// A realisation.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct Realisation(pub NixString);
-
-/// A set of realisations.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct RealisationSet {
-    pub realisations: Vec<Realisation>,
+/// Whether an op policy lets an op through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpDecision {
+    Allow,
+    /// Refuse the op with a generic error message.
+    Deny,
+    /// Refuse the op with this error message.
+    DenyWithError(String),
 }
 
-/// A nar hash.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub struct NarHash {
-    /// This data has not been validated; this is just copied from the wire.
-    pub data: ByteBuf,
+/// A token bucket, for limiting the rate of worker ops.
+///
+/// The bucket holds up to a second's worth of tokens, so clients can burst that
+/// many ops before being slowed down.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    /// This goes negative when ops need to wait for their tokens.
+    tokens: f64,
+    last: Instant,
 }
 
-impl NarHash {
-    pub fn from_bytes(bytes: &[u8]) -> NarHash {
-        const BASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
-
-        let len = (bytes.len() * 8 - 1) / 5 + 1;
-
-        let data = (0..len)
-            .rev()
-            .map(|n| {
-                let b: usize = n * 5;
-                let i: usize = b / 8;
-                let j: usize = b % 8;
-                // bits from the lower byte
-                let v1 = bytes[i].checked_shr(j as u32).unwrap_or(0);
-                // bits from the upper byte
-                let v2 = if i >= bytes.len() - 1 {
-                    0
-                } else {
-                    bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0)
-                };
-                let v: usize = (v1 | v2) as usize;
-                BASE32_CHARS[v % BASE32_CHARS.len()]
-            })
-            .collect::<Vec<_>>();
+impl TokenBucket {
+    fn new(ops_per_sec: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate: ops_per_sec as f64,
+            tokens: ops_per_sec as f64,
+            last: now,
+        }
+    }
 
-        NarHash {
-            data: ByteBuf::from(data),
+    /// Take a token, returning how long to wait before using it.
+    fn take(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - 1.0;
+        self.last = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
         }
     }
 }
 
-// TODO: This naming is a footgun. CppNix calls the inner one UnkeyedValidPathInfo
-// and the outer one ValidPathInfo.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-pub struct ValidPathInfoWithPath {
-    pub path: StorePath,
-    pub info: ValidPathInfo,
+/// A cache of `QueryPathInfo` and `IsValidPath` replies, so that the proxy can answer
+/// repeated queries without asking the daemon.
+///
+/// Entries expire after `ttl`, and the least recently used one is evicted when the
+/// cache is full. Since the daemon's answers can change whenever the store does, any
+/// op that isn't known to be read-only clears the whole cache.
+#[derive(Debug)]
+struct PathInfoCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<CacheKey, CachedReply>,
+    // Incremented on every lookup, for finding the least recently used entry.
+    clock: u64,
 }
 
-impl<R: Read> NixRead<R> {
-    /// Read an integer from the wire.
-    pub fn read_u64(&mut self) -> serialize::Result<u64> {
-        self.inner.read_nix()
+// The op's name, and the path that it asks about.
+type CacheKey = (&'static str, StorePath);
+
+#[derive(Debug)]
+struct CachedReply {
+    /// The reply, as sent to the client.
+    reply: Vec<u8>,
+    inserted: Instant,
+    last_used: u64,
+}
+
+impl PathInfoCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        PathInfoCache {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            clock: 0,
+        }
     }
 
-    /// Read a "string" (really, a byte buffer) from the wire.
-    pub fn read_string(&mut self) -> serialize::Result<NixString> {
-        self.inner.read_nix()
+    /// The cache key for `op`, if its reply can be cached.
+    fn key(op: &WorkerOp) -> Option<CacheKey> {
+        match op {
+            WorkerOp::IsValidPath(path, _) | WorkerOp::QueryPathInfo(path, _) => {
+                Some((op.name(), path.0.clone()))
+            }
+            _ => None,
+        }
     }
 
-    /// Read any serializable type from the wire.
-    pub fn read_nix(&mut self) -> serialize::Result<()> {
-        self.inner.read_nix()
+    /// Whether `op` leaves the store alone, so that it doesn't invalidate the cache.
+    ///
+    /// `QueryValidPaths` with `builders_use_substitutes` isn't: the daemon substitutes
+    /// the paths that are missing.
+    fn is_read_only(op: &WorkerOp) -> bool {
+        if let WorkerOp::QueryValidPaths(query, _) = op {
+            return !query.builders_use_substitutes;
+        }
+        matches!(
+            op,
+            WorkerOp::IsValidPath(..)
+                | WorkerOp::QueryReferences(..)
+                | WorkerOp::QueryReferrers(..)
+                | WorkerOp::FindRoots(..)
+                | WorkerOp::SetOptions(..)
+                | WorkerOp::QueryAllValidPaths(..)
+                | WorkerOp::QueryPathInfo(..)
+                | WorkerOp::QueryPathFromHashPart(..)
+                | WorkerOp::QuerySubstitutablePaths(..)
+                | WorkerOp::QueryValidDerivers(..)
+                | WorkerOp::NarFromPath(..)
+                | WorkerOp::QueryMissing(..)
+                | WorkerOp::QueryDerivationOutputMap(..)
+                | WorkerOp::QueryRealisation(..)
+        )
     }
-}
 
-impl<W: Write> NixWrite<W> {
-    /// Write an integer to the wire.
-    pub fn write_u64(&mut self, n: u64) -> serialize::Result<()> {
-        self.inner.write_nix(&n)
+    fn get(&mut self, key: &CacheKey, now: Instant) -> Option<&[u8]> {
+        self.clock += 1;
+        let ttl = self.ttl;
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|e| now.saturating_duration_since(e.inserted) >= ttl)
+        {
+            self.entries.remove(key);
+        }
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(&entry.reply)
     }
 
-    /// Write a "string" (really, a byte buffer) to the wire.
-    pub fn write_string(&mut self, s: &[u8]) -> serialize::Result<()> {
-        NixSerializer {
-            write: &mut self.inner,
+    fn insert(&mut self, key: CacheKey, reply: Vec<u8>, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(lru) = lru {
+                self.entries.remove(&lru);
+            }
         }
-        .write_byte_buf(s)
+        let entry = CachedReply {
+            reply,
+            inserted: now,
+            last_used: self.clock,
+        };
+        self.entries.insert(key, entry);
     }
 
-    /// Write any serializable type to the wire.
-    ///
-    /// *Warning*: don't call this with `[u8]` data: that will (attempt to)
-    /// serialize a sequence of `u8`s, and then panic because the nix wire
-    /// protocol only supports 64-bit integers. If you want to write a byte
-    /// buffer, use [`NixWrite::write_string`] instead.
-    pub fn write_nix(&mut self, data: &impl Serialize) -> serialize::Result<()> {
-        self.inner.write_nix(data)
+    fn clear(&mut self) {
+        self.entries.clear();
     }
+}
 
-    /// Flush the underlying writer.
-    pub fn flush(&mut self) -> Result<()> {
-        Ok(self.inner.flush()?)
+type RouteFn = Box<dyn Fn(&WorkerOp) -> usize + Send>;
+
+// The extra upstream daemons of a `NixRouter`, and how to choose between them.
+struct Routes {
+    // Upstream `i` is `upstreams[i - 1]`; upstream 0 is the proxy's own daemon.
+    upstreams: Vec<DaemonHandle>,
+    route: RouteFn,
+}
+
+type OpPolicy = Box<dyn Fn(&ClientInfo, &WorkerOp) -> OpDecision + Send>;
+type OpObserver = Box<dyn FnMut(&WorkerOp) + Send>;
+type NarHashCheck = Box<dyn Fn(&AddToStoreNar) -> Result<Box<dyn FrameCheck>> + Send>;
+type AddMultipleCheck =
+    Box<dyn Fn(&mut dyn Read, &mut dyn Write, DaemonVersion) -> Result<()> + Send>;
+
+/// What to do when a client's NAR doesn't match the hash it declared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NarHashPolicy {
+    /// Log a warning, and forward the NAR anyway (the daemon will probably reject it).
+    Warn,
+    /// Fail with [`Error::NarHashMismatch`], without letting the daemon see the end
+    /// of the NAR.
+    Fail,
+}
+
+/// A check that the NAR of an `AddToStoreNar` op has the declared hash.
+fn nar_hash_check(
+    op: &AddToStoreNar,
+    hasher: impl hash::Hasher + 'static,
+    policy: NarHashPolicy,
+) -> Result<impl FrameCheck + 'static> {
+    let declared = NarHash {
+        data: ByteBuf::from(op.nar_hash.0.to_vec()),
     }
+    .digest();
+    // A hash that we can't parse can't be checked, which is as bad as a mismatch.
+    let declared = match (declared, policy) {
+        (Ok(declared), _) => Some(declared),
+        (Err(e), NarHashPolicy::Warn) => {
+            eprintln!("warning: not checking the NAR hash of {:?}: {e}", op.path);
+            None
+        }
+        (Err(e), NarHashPolicy::Fail) => return Err(e),
+    };
+    let path = op.path.clone();
+    let nar_hash = op.nar_hash.clone();
+    Ok(framed_data::HashCheck::new(hasher, move |actual| {
+        if declared.as_ref().is_none_or(|declared| actual == *declared) {
+            return Ok(());
+        }
+        let err = Error::NarHashMismatch {
+            path,
+            declared: nar_hash,
+            actual: actual.iter().map(|b| format!("{b:02x}")).collect(),
+        };
+        match policy {
+            NarHashPolicy::Warn => {
+                eprintln!("warning: {err}");
+                Ok(())
+            }
+            NarHashPolicy::Fail => Err(err),
+        }
+    }))
 }
 
-impl<R: Read, W: Write> NixProxy<R, W> {
-    // Wait for an initialization message from the client, and perform
-    // the version negotiation.
-    //
-    // Returns the client version.
-    pub fn handshake(&mut self) -> Result<u64> {
-        let magic = self.read.read_u64()?;
-        if magic != WORKER_MAGIC_1 {
-            eprintln!("{magic:x}");
-            eprintln!("{WORKER_MAGIC_1:x}");
-            todo!("handle error: protocol mismatch 1");
+/// A check that the NAR of an `AddToStoreNar` op has the declared size, and
+/// optionally that it isn't too big.
+struct NarSizeCheck {
+    path: StorePath,
+    declared: u64,
+    max_size: Option<u64>,
+    seen: u64,
+}
+
+impl FrameCheck for NarSizeCheck {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        self.seen += data.len() as u64;
+        let size = self.seen.max(self.declared);
+        if let Some(max_size) = self.max_size.filter(|&max| size > max) {
+            return Err(Error::NarTooLarge {
+                path: self.path.clone(),
+                size,
+                max_size,
+            });
         }
+        // Don't wait for the end to reject a NAR that is already too long.
+        if self.seen > self.declared {
+            return self.finish();
+        }
+        Ok(())
+    }
 
-        self.write.write_u64(WORKER_MAGIC_2)?;
-        self.write.write_u64(PROTOCOL_VERSION.into())?;
-        self.write.flush()?;
+    fn finish(&mut self) -> Result<()> {
+        if self.seen != self.declared {
+            return Err(Error::NarSizeMismatch {
+                path: self.path.clone(),
+                declared: self.declared,
+                actual: self.seen,
+            });
+        }
+        Ok(())
+    }
+}
+
+type SetReadTimeoutFn<R> = fn(&R, Option<Duration>) -> std::io::Result<()>;
+
+/// Readers that can time out, like sockets.
+pub trait ReadTimeout {
+    /// Make reads fail (with `WouldBlock` or `TimedOut`) if they take longer than `timeout`.
+    ///
+    /// `None` means to wait forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
 
-        let client_version = self.read.read_u64()?;
+/// A reader that fails with `TimedOut` once `deadline` has passed, however many reads
+/// it takes to get there.
+struct DeadlineRead<'a, R> {
+    inner: &'a mut R,
+    deadline: Option<(Instant, SetReadTimeoutFn<R>)>,
+}
 
-        if client_version < PROTOCOL_VERSION.into() {
-            Err(anyhow!("Client version {client_version} is too old"))?;
+impl<R: Read> Read for DeadlineRead<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some((deadline, set_timeout)) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // A zero timeout means "wait forever" to sockets, so don't pass it on.
+            if remaining.is_zero() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            set_timeout(self.inner, Some(remaining))?;
         }
+        self.inner.read(buf)
+    }
+}
 
-        // TODO keep track of number of WorkerOps performed
-        let mut _op_count: u64 = 0;
+impl ReadTimeout for std::net::TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::net::TcpStream::set_read_timeout(self, timeout)
+    }
+}
 
-        let _obsolete_cpu_affinity = self.read.read_u64()?;
-        let _obsolete_reserve_space = self.read.read_u64()?;
-        self.write.write_string("rust-nix-bazel-0.1.0".as_bytes())?;
-        self.write.flush()?;
-        Ok(PROTOCOL_VERSION.into())
+impl ReadTimeout for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
     }
+}
 
-    fn forward_stderr(&mut self) -> Result<()> {
-        loop {
-            let msg: stderr::Msg = self.proxy.child_out.read_nix()?;
-            self.write.inner.write_nix(&msg)?;
-            eprintln!("read stderr msg {msg:?}");
-            self.write.inner.flush()?;
+/// A handle for stopping [`NixProxy::process_connection`] from another thread.
+///
+/// The flag is only checked between worker ops, so cancelling won't interrupt an op
+/// that is in progress. In particular, if the proxy is blocked waiting for the client
+/// to send the next op, it will only notice the cancellation once that read returns;
+/// to stop it promptly, also shut down the client connection.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(std::sync::Arc<std::sync::atomic::AtomicBool>);
 
-            if msg == stderr::Msg::Last(()) {
-                break;
-            }
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+/// Options for reaching a remote daemon over ssh.
+#[derive(Clone, Debug)]
+pub struct SshOptions {
+    /// The ssh binary to run.
+    pub ssh_program: String,
+    pub port: Option<u16>,
+    pub identity_file: Option<std::path::PathBuf>,
+    /// Extra arguments to pass to ssh, before the host name.
+    pub extra_args: Vec<String>,
+    /// The daemon to run on the remote host (like nix's `remote-program` store setting).
+    pub remote_program: String,
+}
+
+impl Default for SshOptions {
+    fn default() -> Self {
+        Self {
+            ssh_program: "ssh".to_owned(),
+            port: None,
+            identity_file: None,
+            extra_args: Vec::new(),
+            remote_program: "nix-daemon".to_owned(),
         }
-        Ok(())
     }
+}
 
-    pub fn next_op(&mut self) -> Result<Option<WorkerOp>> {
-        match self.read.inner.read_nix::<WorkerOp>() {
-            Err(serialize::Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                Ok(None)
-            }
-            Err(e) => Err(e.into()),
-            Ok(x) => Ok(Some(x)),
+impl SshOptions {
+    fn command(&self, host: &str) -> std::process::Command {
+        let mut cmd = std::process::Command::new(&self.ssh_program);
+        if let Some(port) = self.port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity_file {
+            cmd.arg("-i").arg(identity);
         }
+        // The `--` keeps ssh from taking the host as an option.
+        cmd.args(&self.extra_args)
+            .arg("--")
+            .arg(host)
+            .arg(&self.remote_program)
+            .arg("--stdio");
+        cmd
     }
+}
 
-    /// Process a remote nix connection.
-    pub fn process_connection(&mut self) -> Result<()>
-    where
-        W: Send,
-    {
-        let client_version = self.handshake()?;
+impl NixProxy<std::net::TcpStream, std::net::TcpStream> {
+    /// Serve a client connected over TCP, proxying to the local daemon like [`NixProxy::new`].
+    ///
+    /// The stream is cloned, so that it can be read from and written to separately.
+    pub fn over_tcp(stream: std::net::TcpStream) -> std::io::Result<Self> {
+        Ok(Self::with_daemon(
+            stream.try_clone()?,
+            stream,
+            DaemonHandle::local()?,
+        ))
+    }
+}
 
-        // Shake hands with the daemon that we're proxying.
-        self.proxy.child_in.write_nix(&WORKER_MAGIC_1)?;
-        self.proxy.child_in.flush()?;
-        let magic: u64 = self.proxy.child_out.read_nix()?;
-        if magic != WORKER_MAGIC_2 {
-            Err(anyhow!("unexpected WORKER_MAGIC_2: got {magic:x}"))?;
+impl NixProxy<std::os::unix::net::UnixStream, std::os::unix::net::UnixStream> {
+    /// Like [`NixProxy::over_tcp`], but for a client connected over a unix socket.
+    pub fn over_unix(stream: std::os::unix::net::UnixStream) -> std::io::Result<Self> {
+        Ok(Self::with_daemon(
+            stream.try_clone()?,
+            stream,
+            DaemonHandle::local()?,
+        ))
+    }
+}
+
+impl<R: Read, W: Write> NixProxy<R, W> {
+    pub fn new(r: R, w: W) -> Self {
+        Self::with_daemon(r, w, DaemonHandle::new())
+    }
+
+    /// Proxy to a daemon that we talk to over `upstream_read` and `upstream_write`.
+    ///
+    /// This is mostly useful for testing, with an in-memory daemon.
+    pub fn from_io(
+        r: R,
+        w: W,
+        upstream_read: impl Read + Send + 'static,
+        upstream_write: impl Write + Send + 'static,
+    ) -> Self {
+        let proxy = DaemonHandle {
+            child_in: Counted::new(Box::new(upstream_write)),
+            child_out: Counted::new(Box::new(upstream_read)),
+            child: None,
+        };
+        Self::with_daemon(r, w, proxy)
+    }
+
+    /// Proxy to a local `nix-daemon` serving the store at `uri`, like `local?root=/tmp/store`
+    /// or `s3://bucket`, which it is given with `--store`.
+    ///
+    /// The URI is checked for obvious mistakes first; nix itself reports anything else
+    /// when the daemon starts.
+    pub fn with_store_uri(r: R, w: W, uri: &str) -> Result<Self> {
+        Ok(Self::with_daemon(
+            r,
+            w,
+            DaemonHandle::spawn(DaemonHandle::store_command("nix-daemon", uri)?)?,
+        ))
+    }
+
+    /// Proxy to a daemon on a remote host, by running it over ssh (like nix's `ssh-ng://` stores).
+    ///
+    /// Hosts that start with `-` are refused, since ssh could take them as options.
+    pub fn connect_ssh(r: R, w: W, host: &str, opts: SshOptions) -> Result<Self> {
+        if host.is_empty() || host.starts_with('-') {
+            Err(anyhow!("invalid ssh host {host:?}"))?;
         }
-        let protocol_version: u64 = self.proxy.child_out.read_nix()?;
-        if protocol_version < PROTOCOL_VERSION.into() {
-            Err(anyhow!(
-                "unexpected protocol version: got {protocol_version}"
-            ))?;
+        Ok(Self::with_daemon(
+            r,
+            w,
+            DaemonHandle::spawn(opts.command(host))?,
+        ))
+    }
+
+    /// Proxy to a daemon started by running `cmd` with `args`, in a controlled environment.
+    ///
+    /// The daemon inherits our environment (unless `clear_env` is set), with `envs` added on
+    /// top. This is useful for pointing the daemon at a particular store, using `NIX_REMOTE`
+    /// or `NIX_STORE_DIR`.
+    pub fn with_command_env(
+        r: R,
+        w: W,
+        cmd: impl AsRef<OsStr>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+        envs: HashMap<String, String>,
+        clear_env: bool,
+    ) -> Result<Self> {
+        let mut cmd = std::process::Command::new(cmd);
+        cmd.args(args);
+        if clear_env {
+            cmd.env_clear();
         }
-        self.proxy.child_in.write_nix(&client_version)?;
-        self.proxy.child_in.write_nix(&0u64)?; // cpu affinity, obsolete
-        self.proxy.child_in.write_nix(&0u64)?; // reserve space, obsolete
-        self.proxy.child_in.flush()?;
-        let proxy_daemon_version: NixString = self.proxy.child_out.read_nix()?;
-        eprintln!(
-            "Proxy daemon is: {}",
-            String::from_utf8_lossy(proxy_daemon_version.0.as_ref())
-        );
-        self.forward_stderr()?;
+        cmd.envs(envs);
+        Ok(Self::with_daemon(r, w, DaemonHandle::spawn(cmd)?))
+    }
 
-        loop {
-            let op = match self.read.inner.read_nix::<WorkerOp>() {
-                Err(serialize::Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    eprintln!("EOF, closing");
-                    break;
-                }
-                x => x,
-            }?;
+    /// Answer the client's ops with `handler`, instead of forwarding them to a daemon.
+    pub fn with_handler(r: R, w: W, handler: impl WorkerOpHandler + Send + 'static) -> Self {
+        let mut ret = Self::with_daemon(r, w, DaemonHandle::disconnected());
+        ret.handler = Some(Box::new(handler));
+        ret
+    }
 
-            eprintln!("read op {op:?}");
-            self.proxy.child_in.write_nix(&op).unwrap();
-            op.stream(&mut self.read.inner, &mut self.proxy.child_in)
-                .unwrap();
-            self.proxy.child_in.flush().unwrap();
+    fn with_daemon(r: R, w: W, proxy: DaemonHandle) -> Self {
+        Self {
+            read: NixRead { inner: r },
+            write: NixWrite { inner: w },
+            proxy,
+            cancel: CancellationToken::default(),
+            handshake_timeout: None,
+            handshake_deadline: None,
+            op_policy: None,
+            client_identity: None,
+            dry_run: false,
+            verbosity_clamp: (None, None),
+            build_option_caps: (None, None),
+            nar_hash_check: None,
+            add_multiple_check: None,
+            check_nar_sizes: false,
+            max_nar_size: None,
+            rate_limit: None,
+            protocol_version: protocol_version_from_env(),
+            on_op: None,
+            handler: None,
+            store_dir_rewrite: None,
+            upstream_version: None,
+            upstream_identity: None,
+            max_lifetime: None,
+            path_info_cache: None,
+            self_check: SelfCheckPolicy::default(),
+            routes: None,
+            strict_validation: false,
+            state: ConnState::Handshaking,
+        }
+    }
+}
 
-            self.forward_stderr()?;
+/// A wrapper around a `std::io::Read`, adding support for the nix wire format.
+pub struct NixRead<R> {
+    pub inner: R,
+}
 
-            // Read back the actual response.
-            op.proxy_response(&mut self.proxy.child_out, &mut self.write.inner)?;
-            self.write.inner.flush()?;
-        }
-        Ok(())
+/// A wrapper around a `std::io::Write`, adding support for the nix wire format.
+pub struct NixWrite<W> {
+    pub inner: W,
+}
+
+/// A set of paths.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct PathSet {
+    pub paths: Vec<Path>,
+}
+
+/// A set of store paths.
+///
+/// Nothing stops a set on the wire from containing the same path twice; see
+/// [`StorePathSet::dedup_sorted`] and
+/// [`NixDeserializer::reject_duplicates`](serialize::NixDeserializer::reject_duplicates).
+#[derive(Clone, Debug, Serialize, PartialEq, Eq, Default)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct StorePathSet {
+    // TODO: in nix, they call `parseStorePath` to separate store directory from path
+    pub paths: Vec<StorePath>,
+}
+
+impl StorePathSet {
+    /// Sort the paths by their bytes.
+    ///
+    /// The daemon sends sets in whatever order it likes, which isn't always the same.
+    pub fn sort(&mut self) {
+        self.paths.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    /// Sort the paths (like [`StorePathSet::sort`]), and then drop any repeats.
+    pub fn dedup_sorted(&mut self) {
+        self.sort();
+        self.paths.dedup();
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct DaemonVersion {
-    major: u8,
-    minor: u8,
+impl<'de> Deserialize<'de> for StorePathSet {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Named, so that deserializers can tell store path sets apart from other
+        // sequences (see `NixDeserializer::reject_duplicates`).
+        #[derive(Deserialize)]
+        #[serde(rename = "__nix_remote_store_path_set")]
+        struct Set(Vec<StorePath>);
+
+        let Set(paths) = Set::deserialize(deserializer)?;
+        Ok(StorePathSet { paths })
+    }
 }
 
-impl From<u64> for DaemonVersion {
-    fn from(x: u64) -> Self {
-        let major = ((x >> 8) & 0xff) as u8;
-        let minor = (x & 0xff) as u8;
-        Self { major, minor }
+/// A set of strings.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct StringSet {
+    pub paths: Vec<NixString>,
+}
+
+/// A realisation.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct Realisation(pub NixString);
+
+/// A set of realisations.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct RealisationSet {
+    pub realisations: Vec<Realisation>,
+}
+
+/// A nar hash.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NarHash {
+    /// This data has not been validated; this is just copied from the wire.
+    pub data: ByteBuf,
+}
+
+impl NarHash {
+    pub fn from_bytes(bytes: &[u8]) -> NarHash {
+        let data = nixbase32::encode(bytes).into_bytes();
+
+        NarHash {
+            data: ByteBuf::from(data),
+        }
+    }
+
+    /// The SHA-256 digest.
+    ///
+    /// The daemon sends NAR hashes in base-16, but we also accept nix's base-32, with
+    /// or without a `sha256:` prefix.
+    pub fn digest(&self) -> Result<Vec<u8>> {
+        let data = self.data.strip_prefix(b"sha256:").unwrap_or(&self.data);
+        let invalid = || anyhow!("invalid NAR hash {:?}", String::from_utf8_lossy(data));
+        match data.len() {
+            64 => data
+                .chunks(2)
+                .map(|pair| {
+                    std::str::from_utf8(pair)
+                        .ok()
+                        .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                        .ok_or_else(invalid)
+                })
+                .collect::<Result<_, _>>()
+                .map_err(Error::from),
+            52 => {
+                let s = std::str::from_utf8(data).map_err(|_| invalid())?;
+                Ok(nixbase32::decode(s).map_err(|_| invalid())?)
+            }
+            _ => Err(invalid())?,
+        }
     }
 }
 
-impl From<DaemonVersion> for u64 {
-    fn from(DaemonVersion { major, minor }: DaemonVersion) -> Self {
-        ((major as u64) << 8) | minor as u64
+// TODO: This naming is a footgun. CppNix calls the inner one UnkeyedValidPathInfo
+// and the outer one ValidPathInfo.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct ValidPathInfoWithPath {
+    pub path: StorePath,
+    pub info: ValidPathInfo,
+}
+
+impl ValidPathInfoWithPath {
+    /// Pair up a path with its info, checking that the path, its references and its
+    /// deriver are all store paths, and that the NAR hash is readable.
+    pub fn new(path: StorePath, info: ValidPathInfo) -> Result<Self> {
+        StorePath::parse(path.as_ref())?;
+        for reference in info.references_iter() {
+            StorePath::parse(reference.as_ref())?;
+        }
+        if let Some(deriver) = &info.deriver.0 {
+            StorePath::parse(deriver.as_ref())?;
+        }
+        info.hash.digest()?;
+        Ok(ValidPathInfoWithPath { path, info })
+    }
+
+    /// Render the `.narinfo` fields that we know about.
+    ///
+    /// Binary caches also need `URL`, `Compression` and the like, which depend on how
+    /// the NAR is stored; callers can append those. References and the deriver are
+    /// written relative to `store_dir`, which they must be in.
+    pub fn to_narinfo_string(&self, store_dir: &str) -> Result<String> {
+        let base_name = |path: &StorePath| -> Result<String> {
+            let base = path
+                .as_ref()
+                .strip_prefix(store_dir.as_bytes())
+                .and_then(|p| p.strip_prefix(b"/"))
+                .ok_or_else(|| anyhow!("{path:?} is not in {store_dir}"))?;
+            Ok(String::from_utf8_lossy(base).into_owned())
+        };
+        let info = &self.info;
+
+        let mut out = format!(
+            "StorePath: {}\nNarHash: sha256:{}\nNarSize: {}\n",
+            String::from_utf8_lossy(self.path.as_ref()),
+            nixbase32::encode(&info.hash.digest()?),
+            info.nar_size
+        );
+        let references = info
+            .references_iter()
+            .map(base_name)
+            .collect::<Result<Vec<_>>>()?;
+        out += &format!("References: {}\n", references.join(" "));
+        if let Some(deriver) = &info.deriver.0 {
+            out += &format!("Deriver: {}\n", base_name(deriver)?);
+        }
+        for sig in &info.sigs.paths {
+            out += &format!("Sig: {}\n", String::from_utf8_lossy(sig.as_ref()));
+        }
+        if !info.content_address.0.is_empty() {
+            out += &format!(
+                "CA: {}\n",
+                String::from_utf8_lossy(info.content_address.as_ref())
+            );
+        }
+        Ok(out)
     }
 }
 
-#[cfg(test)]
-impl<'a> arbitrary::Arbitrary<'a> for NixString {
-    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
-        let data: Vec<u8> = Vec::arbitrary(u)?;
-        Ok(NixString(ByteBuf::from(data)))
+impl<R: Read> NixRead<R> {
+    /// Read an integer from the wire.
+    pub fn read_u64(&mut self) -> serialize::Result<u64> {
+        self.inner.read_nix()
+    }
+
+    /// Read an integer that starts a new message.
+    ///
+    /// Returns `None` if the stream ends cleanly before the integer, and
+    /// [`serialize::Error::Truncated`] if it ends partway through.
+    pub fn read_u64_at_boundary(&mut self) -> serialize::Result<Option<u64>> {
+        let mut buf = [0u8; 8];
+        let mut got = 0;
+        while got < buf.len() {
+            match self.inner.read(&mut buf[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(serialize::Error::Truncated { got }),
+                Ok(n) => got += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(Some(u64::from_le_bytes(buf)))
+    }
+
+    /// Read a "string" (really, a byte buffer) from the wire.
+    pub fn read_string(&mut self) -> serialize::Result<NixString> {
+        self.inner.read_nix()
+    }
+
+    /// Read any serializable type from the wire.
+    pub fn read_nix(&mut self) -> serialize::Result<()> {
+        self.inner.read_nix()
     }
 }
 
-#[cfg(test)]
-impl<'a> arbitrary::Arbitrary<'a> for NarHash {
-    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
-        let data: Vec<u8> = Vec::arbitrary(u)?;
-        Ok(NarHash {
-            data: ByteBuf::from(data),
-        })
+impl<W: Write> NixWrite<W> {
+    /// Write an integer to the wire.
+    pub fn write_u64(&mut self, n: u64) -> serialize::Result<()> {
+        self.inner.write_nix(&n)
+    }
+
+    /// Write a "string" (really, a byte buffer) to the wire.
+    pub fn write_string(&mut self, s: &[u8]) -> serialize::Result<()> {
+        NixSerializer::new(&mut self.inner).write_byte_buf(s)
+    }
+
+    /// Write a "string" of `len` bytes, copied from `src`.
+    ///
+    /// This is for strings too big to comfortably hold in memory, like build logs.
+    pub fn write_string_from(&mut self, len: u64, src: &mut impl Read) -> serialize::Result<()> {
+        NixSerializer::new(&mut self.inner).write_byte_buf_from(len, src)
+    }
+
+    /// Write any serializable type to the wire.
+    ///
+    /// *Warning*: don't call this with `[u8]` data: that will (attempt to)
+    /// serialize a sequence of `u8`s, and then panic because the nix wire
+    /// protocol only supports 64-bit integers. If you want to write a byte
+    /// buffer, use [`NixWrite::write_string`] instead.
+    pub fn write_nix(&mut self, data: &impl Serialize) -> serialize::Result<()> {
+        self.inner.write_nix(data)
+    }
+
+    /// Flush the underlying writer.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.inner.flush()?)
+    }
+}
+
+impl<R: Read + ReadTimeout, W: Write> NixProxy<R, W> {
+    /// Give up on clients that don't complete the handshake within `timeout`.
+    ///
+    /// The timeout is a deadline for the whole handshake, however the client splits it
+    /// up, and is cleared once the handshake is over (whether or not it succeeded). If
+    /// it expires, [`NixProxy::process_connection`] fails with
+    /// [`Error::HandshakeTimeout`].
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = Some((timeout, R::set_read_timeout));
+    }
+}
+
+impl<R: Read, W: Write> NixProxy<R, W> {
+    /// Only forward the worker ops for which `allowed` returns true.
+    ///
+    /// Other ops are answered with an error, as if the daemon had refused them, and the
+    /// connection carries on. This can be used to give clients restricted (e.g. read-only)
+    /// access to the store.
+    pub fn allow_ops(&mut self, allowed: impl Fn(&WorkerOp) -> bool + Send + 'static) {
+        self.op_policy(move |_, op| {
+            if allowed(op) {
+                OpDecision::Allow
+            } else {
+                OpDecision::Deny
+            }
+        });
+    }
+
+    /// Like [`NixProxy::allow_ops`], but `policy` also gets to see who the client is,
+    /// so that different clients can be allowed different ops.
+    ///
+    /// This replaces any filter set with `allow_ops`.
+    pub fn op_policy(
+        &mut self,
+        policy: impl Fn(&ClientInfo, &WorkerOp) -> OpDecision + Send + 'static,
+    ) {
+        self.op_policy = Some(Box::new(policy));
+    }
+
+    /// Tell [`NixProxy::op_policy`] who the client is.
+    pub fn set_client_identity(&mut self, identity: impl Into<String>) {
+        self.client_identity = Some(identity.into());
+    }
+
+    /// In dry-run mode, ops that would modify the store aren't forwarded to the daemon.
+    ///
+    /// Instead, they're logged and answered with a plausible, empty reply (see
+    /// [`WorkerOp::dry_run_reply`]). `AddToStore`, which has no such reply, fails with
+    /// an error. Queries are forwarded as usual.
+    pub fn dry_run(&mut self, enabled: bool) {
+        self.dry_run = enabled;
+    }
+
+    /// Keep the verbosities that clients ask for with `SetOptions` between `min` and
+    /// `max`, for example to get debug logs out of the daemon, or to keep its logs short.
+    ///
+    /// Both `verbosity` and `build_verbosity` are clamped before the op is forwarded. If
+    /// `min` is above `max`, `max` wins.
+    pub fn clamp_verbosity(&mut self, min: Option<Verbosity>, max: Option<Verbosity>) {
+        self.verbosity_clamp = (min, max);
+    }
+
+    /// Don't let clients ask for more than `max_jobs` parallel builds, or for builds to
+    /// stay silent for longer than `max_silent` seconds, with `SetOptions`.
+    ///
+    /// A client that asks for no silence limit at all (a `max_silent_time` of zero) gets
+    /// `max_silent` too.
+    pub fn cap_build_options(&mut self, max_jobs: Option<u64>, max_silent: Option<u64>) {
+        self.build_option_caps = (max_jobs, max_silent);
+    }
+
+    /// Check that the NARs sent with `AddToStoreNar` and `AddMultipleToStore` match
+    /// their declared hashes.
+    ///
+    /// Each NAR is hashed with `H` as it is forwarded, so this doesn't buffer anything
+    /// (see [`framed_data::stream_add_multiple_checked`] for how `AddMultipleToStore` is
+    /// handled). On a mismatch, `policy` decides whether to just warn or to fail.
+    pub fn check_nar_hashes<H: hash::Hasher + Default + 'static>(&mut self, policy: NarHashPolicy) {
+        self.nar_hash_check = Some(Box::new(move |op| {
+            Ok(Box::new(nar_hash_check(op, H::default(), policy)?))
+        }));
+        self.add_multiple_check = Some(Box::new(move |mut read, mut write, version| {
+            framed_data::stream_add_multiple_checked::<H>(&mut read, &mut write, version, policy)
+        }));
+    }
+
+    /// Check that the NARs sent with `AddToStoreNar` are exactly as big as declared,
+    /// and (if `max_size` is set) no bigger than `max_size`.
+    ///
+    /// Like [`NixProxy::check_nar_hashes`], this happens as the NAR is forwarded, and
+    /// the two checks share a single pass over it. A NAR that fails is cut off before
+    /// the daemon sees its end, with [`Error::NarSizeMismatch`] or [`Error::NarTooLarge`].
+    pub fn check_nar_sizes(&mut self, max_size: Option<u64>) {
+        self.check_nar_sizes = true;
+        self.max_nar_size = max_size;
+    }
+
+    /// Don't forward more than `ops_per_sec` worker ops per second, on average.
+    ///
+    /// Ops over the limit are delayed, not rejected. Short bursts (up to a second's
+    /// worth of ops) go through immediately. A limit of zero means no limit.
+    pub fn rate_limit(&mut self, ops_per_sec: u32) {
+        self.rate_limit = (ops_per_sec > 0).then(|| TokenBucket::new(ops_per_sec, Instant::now()));
+    }
+
+    /// Call `f` with each worker op that [`NixProxy::process_connection`] reads.
+    ///
+    /// `f` sees just the op, not any framed data that follows it. It's called from the
+    /// proxy loop, so it should be quick.
+    pub fn on_op(&mut self, f: impl FnMut(&WorkerOp) + Send + 'static) {
+        self.on_op = Some(Box::new(f));
+    }
+
+    /// Move paths from the `from` directory to `to` on their way to the daemon, and back
+    /// again on the way to the client.
+    ///
+    /// This is for proxying to a daemon whose store is somewhere other than the client's.
+    /// Only the path fields of ops and responses are rewritten; paths that appear inside
+    /// NARs, log messages or other strings are left alone.
+    ///
+    /// Some ops carry paths that this can't reach, and fail with an error instead of being
+    /// forwarded:
+    /// - `AddMultipleToStore`, whose path infos are inside its framed source.
+    /// - `BuildDerivation`, whose derivation's environment and arguments are plain
+    ///   strings that contain paths.
+    ///
+    /// Every other op is covered. Realisations (in `RegisterDrvOutput`,
+    /// `QueryRealisation` and build results) are JSON, but nix writes the paths in them
+    /// without the store directory, so they don't need rewriting.
+    pub fn rewrite_store_dir(&mut self, from: Path, to: Path) {
+        self.store_dir_rewrite = Some(PathRewrite {
+            from: from.0 .0.to_vec(),
+            to: to.0 .0.to_vec(),
+        });
+    }
+
+    /// Close the connection once it has been open for longer than `lifetime`.
+    ///
+    /// This is only checked between ops, so an op (like a long build) is never cut off,
+    /// and every connection gets to run at least one op.
+    pub fn set_max_lifetime(&mut self, lifetime: Duration) {
+        self.max_lifetime = Some(lifetime);
+    }
+
+    /// Decide what happens when a daemon response fails the self-check in
+    /// [`WorkerOp::proxy_response`]. The default is [`SelfCheckPolicy::Warn`].
+    pub fn set_self_check_policy(&mut self, policy: SelfCheckPolicy) {
+        self.self_check = policy;
+    }
+
+    /// Check every op with [`WorkerOp::validate`] before forwarding it, and reject the
+    /// ones that fail with an error to the client.
+    ///
+    /// Without this, only `CollectGarbage` requests are checked.
+    pub fn set_strict_validation(&mut self, strict: bool) {
+        self.strict_validation = strict;
+    }
+
+    /// Remember the daemon's replies to `QueryPathInfo` and `IsValidPath`, and answer
+    /// repeated queries for the same path without forwarding them.
+    ///
+    /// Up to `capacity` replies are kept, each for at most `ttl`. Any op that might
+    /// change the store (like `BuildPaths`, `AddToStore` or `CollectGarbage`) empties
+    /// the cache before it is forwarded. Changes that other clients make to the store
+    /// aren't noticed, though, so `ttl` bounds how stale an answer can be.
+    pub fn cache_path_info(&mut self, capacity: usize, ttl: Duration) {
+        self.path_info_cache = Some(PathInfoCache::new(capacity, ttl));
+    }
+
+    /// A token that can be used to stop [`NixProxy::process_connection`].
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    // Wait for an initialization message from the client, and perform
+    // the version negotiation.
+    //
+    // Returns the client version.
+    pub fn handshake(&mut self) -> Result<u64> {
+        self.expect_state(ConnState::Handshaking, "handshake")?;
+        let magic: u64 = self.read_handshake()?;
+        if magic != WORKER_MAGIC_1 {
+            return Err(self.state_violation(format!("magic number {magic:x}")));
+        }
+
+        self.write.write_u64(WORKER_MAGIC_2)?;
+        self.write.write_u64(self.protocol_version.into())?;
+        self.write.flush()?;
+
+        let client_version: u64 = self.read_handshake()?;
+
+        // We don't yet speak older versions than the one we advertise.
+        if client_version < self.protocol_version.into() {
+            return Err(Error::ClientTooOld {
+                got: client_version.into(),
+                minimum: self.protocol_version,
+            });
+        }
+
+        // TODO keep track of number of WorkerOps performed
+        let mut _op_count: u64 = 0;
+
+        let _obsolete_cpu_affinity: u64 = self.read_handshake()?;
+        let _obsolete_reserve_space: u64 = self.read_handshake()?;
+        if self.protocol_version.minor >= IDENTITY_MINOR {
+            self.write.write_string("rust-nix-bazel-0.1.0".as_bytes())?;
+        }
+        self.write.flush()?;
+        self.state = ConnState::Ready;
+        Ok(self.protocol_version.into())
+    }
+
+    // Read part of the client's handshake, giving up if the handshake deadline passes.
+    fn read_handshake<T: serde::de::DeserializeOwned>(&mut self) -> serialize::Result<T> {
+        DeadlineRead {
+            inner: &mut self.read.inner,
+            deadline: self.handshake_deadline,
+        }
+        .read_nix()
+    }
+
+    // Apply `clamp_verbosity` and `cap_build_options` to a client's `SetOptions`.
+    fn override_options(&self, options: &mut worker_op::SetOptions) {
+        let (min, max) = self.verbosity_clamp;
+        for verbosity in [&mut options.verbosity, &mut options.build_verbosity] {
+            if let Some(min) = min {
+                *verbosity = (*verbosity).max(min);
+            }
+            if let Some(max) = max {
+                *verbosity = (*verbosity).min(max);
+            }
+        }
+
+        let (max_jobs, max_silent) = self.build_option_caps;
+        if let Some(max_jobs) = max_jobs {
+            options.max_build_jobs = options.max_build_jobs.min(max_jobs);
+        }
+        if let Some(max_silent) = max_silent {
+            if options.max_silent_time == 0 || options.max_silent_time > max_silent {
+                options.max_silent_time = max_silent;
+            }
+        }
+    }
+
+    /// Where the connection with the client is at.
+    pub fn conn_state(&self) -> ConnState {
+        self.state
+    }
+
+    fn expect_state(&self, state: ConnState, got: &str) -> Result<()> {
+        if self.state == state {
+            Ok(())
+        } else {
+            Err(self.state_violation(got.to_owned()))
+        }
+    }
+
+    fn state_violation(&self, got: String) -> Error {
+        Error::ProtocolStateViolation {
+            state: self.state,
+            got,
+        }
+    }
+
+    /// Shake hands with the upstream daemon and return the protocol version it speaks.
+    ///
+    /// This performs the whole client side of the handshake (advertising our own
+    /// version), so the daemon is ready to accept ops afterwards. Don't call
+    /// [`NixProxy::process_connection`] after this, because it would try to shake
+    /// hands again.
+    pub fn probe_version(&mut self) -> Result<DaemonVersion> {
+        let daemon_version = self.upstream_handshake(self.protocol_version.into())?;
+        self.skip_upstream_stderr()?;
+        Ok(daemon_version)
+    }
+
+    /// Check that the upstream daemon is alive, and return its protocol version.
+    ///
+    /// The first ping shakes hands with the daemon, like [`NixProxy::probe_version`]. Later
+    /// ones send it a `QueryValidPaths` for no paths at all, which is about as cheap as an
+    /// op gets. Like `probe_version`, this is for proxies that aren't serving a client.
+    pub fn ping(&mut self) -> Result<DaemonVersion> {
+        if let Some(version) = self.upstream_version {
+            let op = WorkerOp::QueryValidPaths(
+                worker_op::Plain(worker_op::QueryValidPaths {
+                    paths: StorePathSet::default(),
+                    builders_use_substitutes: false,
+                }),
+                worker_op::Resp::new(),
+            );
+            // Before 1.27, the request doesn't have `builders_use_substitutes`.
+            let wire = version.min(self.protocol_version);
+            self.proxy.child_in.write_nix_versioned(&op, wire)?;
+            self.proxy.child_in.flush()?;
+            self.skip_upstream_stderr()?;
+            let _: StorePathSet = self.proxy.child_out.read_nix_versioned(wire)?;
+            return Ok(version);
+        }
+
+        self.probe_version()
+    }
+
+    // Read stderr messages from the daemon up to the final one, without forwarding them.
+    fn skip_upstream_stderr(&mut self) -> Result<()> {
+        loop {
+            match self.proxy.child_out.read_nix()? {
+                stderr::Msg::Last(()) => return Ok(()),
+                stderr::Msg::Error(e) => return Err(Error::UpstreamDaemon(e)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Shake hands with the upstream daemon, telling it that we speak `client_version`
+    /// (or [`PROTOCOL_VERSION`], if that is older).
+    ///
+    /// Returns the daemon's version, which may be older or newer than ours: the
+    /// connection then uses the older of `client_version` and the daemon's version. Only
+    /// daemons that are too old for us to talk to at all are refused. The daemon follows
+    /// the handshake with stderr messages (ending with `Last`), which are left for the
+    /// caller to read.
+    ///
+    /// This is separate from [`NixProxy::handshake`], which is the daemon side of the
+    /// handshake with our own client.
+    pub fn upstream_handshake(&mut self, client_version: u64) -> Result<DaemonVersion> {
+        let daemon_version = self.upstream_hello()?;
+        self.upstream_finish_handshake(client_version, daemon_version)?;
+        Ok(daemon_version)
+    }
+
+    // The first half of the upstream handshake: find out the daemon's version.
+    fn upstream_hello(&mut self) -> Result<DaemonVersion> {
+        self.proxy.child_in.write_nix(&WORKER_MAGIC_1)?;
+        self.proxy.child_in.flush()?;
+        let magic: u64 = self.proxy.child_out.read_nix()?;
+        if magic != WORKER_MAGIC_2 {
+            Err(anyhow!("unexpected WORKER_MAGIC_2: got {magic:x}"))?;
+        }
+        let daemon_version = DaemonVersion::from(self.proxy.child_out.read_nix::<u64>()?);
+        if daemon_version.major != MIN_UPSTREAM_VERSION.major
+            || daemon_version < MIN_UPSTREAM_VERSION
+        {
+            Err(anyhow!(
+                "unsupported daemon protocol version {daemon_version}"
+            ))?;
+        }
+        Ok(daemon_version)
+    }
+
+    // The second half of the upstream handshake: tell the daemon our version.
+    fn upstream_finish_handshake(
+        &mut self,
+        client_version: u64,
+        daemon_version: DaemonVersion,
+    ) -> Result<()> {
+        // We can't follow a handshake that is newer than ours.
+        let client_version = client_version.min(PROTOCOL_VERSION.into());
+        self.proxy.child_in.write_nix(&client_version)?;
+        let version = DaemonVersion::from(client_version).min(daemon_version);
+        self.proxy.child_in.write_nix(&0u64)?; // cpu affinity, obsolete
+        self.proxy.child_in.write_nix(&0u64)?; // reserve space, obsolete
+        self.proxy.child_in.flush()?;
+        if version.minor >= IDENTITY_MINOR {
+            let identity: NixString = self.proxy.child_out.read_nix()?;
+            let identity = DaemonIdentity(String::from_utf8_lossy(&identity.0).into_owned());
+            eprintln!("Proxy daemon is: {identity}");
+            self.upstream_identity = Some(identity);
+        }
+        self.upstream_version = Some(daemon_version);
+        Ok(())
+    }
+
+    // Forward stderr messages from the daemon to the client, up to the final one.
+    //
+    // `Last` is the boundary between the daemon's stderr messages and whatever comes
+    // next, both at the end of the handshake and before every op's reply; this is the
+    // only place where we look for it.
+    //
+    // If the daemon sends an error, that is the final message: it's forwarded, and then
+    // returned as `Error::UpstreamDaemon`. If it asks for source data with a `Read`
+    // message, the client's reply is passed back to it.
+    //
+    // Only failures on the client's side are reported as `Error::ClientDisconnected`; a
+    // daemon that goes away is an ordinary error.
+    fn forward_stderr(&mut self) -> Result<()> {
+        loop {
+            let msg: stderr::Msg = self.proxy.child_out.read_nix()?;
+            eprintln!("read stderr msg {msg:?}");
+            self.write
+                .inner
+                .write_nix(&msg)
+                .and_then(|()| Ok(self.write.inner.flush()?))
+                .map_err(|e| Error::from(e).or_client_disconnected())?;
+
+            match msg {
+                stderr::Msg::Last(()) => return Ok(()),
+                stderr::Msg::Error(e) => return Err(Error::UpstreamDaemon(e)),
+                stderr::Msg::Read(_) => {
+                    let data: ByteBuf = self
+                        .read
+                        .inner
+                        .read_nix()
+                        .map_err(|e| Error::from(e).or_client_disconnected())?;
+                    self.proxy.child_in.write_nix(&data)?;
+                    self.proxy.child_in.flush()?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Read the next op from the client, or `None` if the client closed the
+    /// connection between ops.
+    pub fn next_op(&mut self) -> Result<Option<WorkerOp>> {
+        self.expect_state(ConnState::Ready, "op")?;
+        let Some(opcode) = self.read.read_u64_at_boundary()? else {
+            return Ok(None);
+        };
+        // A client that starts over (or replays its handshake) mustn't be mistaken for
+        // one that sends an op; `handshake` has already refused to run again.
+        if opcode == WORKER_MAGIC_1 {
+            return Err(self.state_violation("a second handshake".to_owned()));
+        }
+        // Anything else here (like the frames of a source, sent without the op they
+        // belong to) would be misread as the start of an op.
+        if !worker_op::supported_opcodes().contains(&opcode) {
+            return Err(self.state_violation(format!("opcode {opcode}")));
+        }
+        // Put the opcode back in front of the body so that the op can be deserialized whole.
+        let opcode = opcode.to_le_bytes();
+        let mut read = Read::chain(&opcode[..], &mut self.read.inner);
+        let op: WorkerOp = read.read_nix_versioned(self.protocol_version)?;
+        self.state = if op.has_framed_source() {
+            ConnState::StreamingSource
+        } else {
+            ConnState::AwaitingReply
+        };
+        Ok(Some(op))
+    }
+
+    /// Process a remote nix connection.
+    ///
+    /// The client-facing writer is flushed after every complete message to the client
+    /// (the handshake, each stderr message, and each reply), and the daemon-facing one
+    /// after every op, so it's fine for either of them to be buffered.
+    ///
+    /// If the upstream daemon is older than the protocol version we advertise, we offer
+    /// the client the daemon's version instead, and speak that for the whole connection.
+    ///
+    /// Everything is forwarded synchronously: we only read the next message from the daemon
+    /// once the previous one has been written to the client, so a slow client slows down
+    /// how fast we read from the daemon, and the daemon is held up by its own full pipe
+    /// or socket rather than by memory piling up in the proxy. The exceptions are replies,
+    /// which are decoded in full before they are passed on.
+    ///
+    /// Each reply is forwarded in full before the next op is read, so when the client
+    /// hangs up, nothing is left in flight: we close our connections to the daemons, and
+    /// wait for any that we spawned to exit.
+    ///
+    /// The connection moves through the states of [`ConnState`], and a client that sends
+    /// something out of turn (a bad magic number in the handshake, or anything but a known
+    /// opcode where an op should start) gets [`Error::ProtocolStateViolation`].
+    pub fn process_connection(&mut self) -> Result<ConnectionStats> {
+        let start = Instant::now();
+        instrument::connection();
+        let (sent, received) = self.upstream_bytes();
+        if self.handler.is_none() {
+            self.handshake_upstreams()?;
+        }
+        self.handshake_deadline = self
+            .handshake_timeout
+            .map(|(timeout, set_timeout)| (Instant::now() + timeout, set_timeout));
+        let protocol_version = self.handshake();
+        let cleared = match self.handshake_deadline.take() {
+            Some((_, set_timeout)) => set_timeout(&self.read.inner, None),
+            None => Ok(()),
+        };
+        let protocol_version = protocol_version
+            .map_err(Error::or_handshake_timeout)
+            .map_err(Error::or_client_disconnected)?;
+        cleared?;
+
+        if self.handler.is_some() {
+            self.write
+                .inner
+                .write_nix(&stderr::Msg::Last(()))
+                .and_then(|()| Ok(self.write.inner.flush()?))
+                .map_err(|e| Error::from(e).or_client_disconnected())?;
+        } else {
+            self.forward_stderr()?;
+        }
+
+        let client = ClientInfo {
+            protocol_version: protocol_version.into(),
+            identity: self.client_identity.clone(),
+        };
+        let mut ops_processed = 0;
+        let mut recorded = (sent, received);
+        loop {
+            // Every op has been answered in full (or failed the connection) by the time
+            // we get back here.
+            self.state = ConnState::Ready;
+            if self.cancel.is_cancelled() {
+                eprintln!("cancelled, closing");
+                break;
+            }
+            if ops_processed > 0 && self.max_lifetime.is_some_and(|max| start.elapsed() >= max) {
+                eprintln!("connection is too old, closing");
+                break;
+            }
+
+            let Some(mut op) = self.next_op()? else {
+                eprintln!("EOF, closing");
+                break;
+            };
+            ops_processed += 1;
+            let op_start = Instant::now();
+            instrument::op(op.name());
+
+            eprintln!("read op {op:?}");
+            if let Some(f) = &mut self.on_op {
+                f(&op);
+            }
+            if let Some(bucket) = &mut self.rate_limit {
+                std::thread::sleep(bucket.take(Instant::now()));
+            }
+            let decision = match &self.op_policy {
+                Some(policy) => policy(&client, &op),
+                None => OpDecision::Allow,
+            };
+            if decision != OpDecision::Allow {
+                eprintln!("rejecting disallowed op {}", op.name());
+                op.stream(&mut self.read.inner, &mut std::io::sink())?;
+                let msg = match decision {
+                    OpDecision::DenyWithError(msg) => msg,
+                    _ => format!("operation '{}' is not allowed by this proxy", op.name()),
+                };
+                self.write
+                    .inner
+                    .write_nix(&stderr::Msg::Error(stderr::StderrError::new(msg)))
+                    .and_then(|()| Ok(self.write.inner.flush()?))
+                    .map_err(|e| Error::from(e).or_client_disconnected())?;
+                continue;
+            }
+
+            let validation = match &op {
+                WorkerOp::AddMultipleToStore(..) | WorkerOp::BuildDerivation(..)
+                    if self.store_dir_rewrite.is_some() =>
+                {
+                    Err(anyhow!(
+                        "{} can't be proxied to a daemon with a different store directory",
+                        op.name()
+                    )
+                    .into())
+                }
+                // Obsolete, and refused whatever the version (see the op's docs).
+                WorkerOp::QueryReferences(..) => Err(anyhow!(
+                    "QueryReferences is obsolete and isn't proxied; use QueryPathInfo"
+                )
+                .into()),
+                _ if self.strict_validation => op.validate(self.protocol_version),
+                WorkerOp::CollectGarbage(gc, _) => gc.validate(),
+                _ => Ok(()),
+            };
+            if let Err(e) = validation {
+                eprintln!("rejecting invalid op: {e}");
+                op.stream(&mut self.read.inner, &mut std::io::sink())?;
+                self.write
+                    .inner
+                    .write_nix(&stderr::Msg::Error(stderr::StderrError::new(e.to_string())))
+                    .and_then(|()| Ok(self.write.inner.flush()?))
+                    .map_err(|e| Error::from(e).or_client_disconnected())?;
+                continue;
+            }
+
+            if let Some(reply) = op
+                .dry_run_reply(self.protocol_version)
+                .filter(|_| self.dry_run)
+            {
+                eprintln!("dry run: not forwarding {}", op.name());
+                op.stream(&mut self.read.inner, &mut std::io::sink())?;
+                let written = match reply {
+                    Ok(reply) => self
+                        .write
+                        .inner
+                        .write_nix(&stderr::Msg::Last(()))
+                        .and_then(|()| Ok(self.write.inner.write_all(&reply)?)),
+                    Err(e) => self
+                        .write
+                        .inner
+                        .write_nix(&stderr::Msg::Error(stderr::StderrError::new(e.to_string()))),
+                };
+                written
+                    .and_then(|()| Ok(self.write.inner.flush()?))
+                    .map_err(|e| Error::from(e).or_client_disconnected())?;
+                continue;
+            }
+
+            if let WorkerOp::SetOptions(worker_op::Plain(options), _) = &mut op {
+                self.override_options(options);
+            }
+
+            if let Some(handler) = &mut self.handler {
+                let version = self.protocol_version;
+                let mut progress = handler::ProgressSink::new(&mut self.write.inner);
+                let reply = if op.has_framed_source() {
+                    let mut source = framed_data::FramedReader::new(&mut self.read.inner);
+                    let reply = handler.handle(&op, version, &mut source, &mut progress);
+                    std::io::copy(&mut source, &mut std::io::sink())?;
+                    self.state = ConnState::AwaitingReply;
+                    reply
+                } else {
+                    handler.handle(&op, version, &mut std::io::empty(), &mut progress)
+                };
+                let write = &mut self.write.inner;
+                match reply {
+                    Ok(reply) => write
+                        .write_nix(&stderr::Msg::Last(()))
+                        .and_then(|()| Ok(write.write_all(&reply)?)),
+                    Err(e) => {
+                        eprintln!("failed to handle {}: {e}", op.name());
+                        let msg = stderr::StderrError::new(e.to_string());
+                        write.write_nix(&stderr::Msg::Error(msg))
+                    }
+                }
+                .and_then(|()| Ok(write.flush()?))
+                .map_err(|e| Error::from(e).or_client_disconnected())?;
+                continue;
+            }
+
+            let cache_key = self
+                .path_info_cache
+                .as_ref()
+                .and_then(|_| PathInfoCache::key(&op));
+            if let Some(cache) = &mut self.path_info_cache {
+                let cached = cache_key
+                    .as_ref()
+                    .and_then(|k| cache.get(k, Instant::now()));
+                if let Some(reply) = cached {
+                    eprintln!("answering {} from the cache", op.name());
+                    self.write
+                        .inner
+                        .write_nix(&stderr::Msg::Last(()))
+                        .and_then(|()| Ok(self.write.inner.write_all(reply)?))
+                        .and_then(|()| Ok(self.write.inner.flush()?))
+                        .map_err(|e| Error::from(e).or_client_disconnected())?;
+                    continue;
+                }
+                if !PathInfoCache::is_read_only(&op) {
+                    cache.clear();
+                }
+            }
+
+            let target = match &self.routes {
+                Some(routes) => (routes.route)(&op),
+                None => 0,
+            };
+            if target >= self.upstream_count() {
+                Err(anyhow!(
+                    "{} was routed to upstream {target}, which doesn't exist",
+                    op.name()
+                ))?;
+            }
+            self.with_upstream(target, |this| this.forward_op(&op, cache_key))?;
+            instrument::op_duration(op.name(), op_start.elapsed());
+            self.record_upstream_bytes(&mut recorded);
+        }
+        self.record_upstream_bytes(&mut recorded);
+        // Every reply has been forwarded in full by now, so the daemons aren't in the
+        // middle of anything.
+        self.close_upstreams();
+        Ok(ConnectionStats {
+            protocol_version: protocol_version.into(),
+            ops_processed,
+            bytes_up: self.upstream_bytes().0 - sent,
+            bytes_down: self.upstream_bytes().1 - received,
+            duration: start.elapsed(),
+            daemon_identity: self.upstream_identity.clone(),
+        })
+    }
+
+    // Shake hands with the daemons that we're proxying, before the client, so we know
+    // what version to offer it. With several daemons, they all have to speak the same
+    // version as us, so we find out all their versions before telling them ours.
+    fn handshake_upstreams(&mut self) -> Result<()> {
+        let upstreams = self.upstream_count();
+        let mut version = self.protocol_version;
+        let mut daemon_versions = vec![version; upstreams];
+        for (i, daemon_version) in daemon_versions.iter_mut().enumerate() {
+            *daemon_version = self.with_upstream(i, |this| this.upstream_hello())?;
+            version = version.min(*daemon_version);
+        }
+        // The primary daemon goes last, so that its version and identity are the ones we
+        // remember; its stderr messages are forwarded to the client after the handshake.
+        for (i, daemon_version) in daemon_versions.into_iter().enumerate().rev() {
+            let offer = if upstreams == 1 {
+                self.protocol_version
+            } else {
+                version
+            };
+            self.with_upstream(i, |this| {
+                this.upstream_finish_handshake(offer.into(), daemon_version)?;
+                if i > 0 {
+                    this.skip_upstream_stderr()?;
+                }
+                Ok::<_, Error>(())
+            })?;
+        }
+        self.protocol_version = version;
+        Ok(())
+    }
+
+    // The number of upstream daemons, including the primary one.
+    fn upstream_count(&self) -> usize {
+        1 + self.routes.as_ref().map_or(0, |r| r.upstreams.len())
+    }
+
+    // The number of bytes sent to and received from all upstream daemons.
+    fn upstream_bytes(&self) -> (u64, u64) {
+        let extra = self.routes.iter().flat_map(|r| &r.upstreams);
+        std::iter::once(&self.proxy)
+            .chain(extra)
+            .fold((0, 0), |(sent, received), d| {
+                (sent + d.child_in.count, received + d.child_out.count)
+            })
+    }
+
+    // Report the bytes exchanged with the daemons since `recorded`, and update it.
+    fn record_upstream_bytes(&self, recorded: &mut (u64, u64)) {
+        let now = self.upstream_bytes();
+        instrument::upstream_bytes(now.0 - recorded.0, now.1 - recorded.1);
+        *recorded = now;
+    }
+
+    // Let the daemons know that the client is done, and wait for them to finish.
+    fn close_upstreams(&mut self) {
+        for i in 0..self.upstream_count() {
+            if let Err(e) = self.with_upstream(i, |this| this.proxy.close()) {
+                eprintln!("failed to close upstream {i}: {e}");
+            }
+        }
+    }
+
+    // Run `f` with upstream `i` standing in for the primary daemon.
+    fn with_upstream<T>(&mut self, i: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+        let swap = |this: &mut Self| {
+            if let (Some(routes), Some(j)) = (&mut this.routes, i.checked_sub(1)) {
+                std::mem::swap(&mut this.proxy, &mut routes.upstreams[j]);
+            }
+        };
+        swap(self);
+        let result = f(self);
+        swap(self);
+        result
+    }
+
+    // Send `op` to the upstream daemon, and pass its reply back to the client.
+    fn forward_op(&mut self, op: &WorkerOp, cache_key: Option<CacheKey>) -> Result<()> {
+        match &self.store_dir_rewrite {
+            Some(rewrite) => op.serialize(
+                &mut NixSerializer::with_version(&mut self.proxy.child_in, self.protocol_version)
+                    .with_path_rewrite(rewrite),
+            )?,
+            None => self
+                .proxy
+                .child_in
+                .write_nix_versioned(op, self.protocol_version)?,
+        }
+        match op {
+            WorkerOp::AddToStoreNar(add, _)
+                if self.check_nar_sizes || self.nar_hash_check.is_some() =>
+            {
+                let mut check = (
+                    self.check_nar_sizes.then(|| NarSizeCheck {
+                        path: add.path.clone(),
+                        declared: add.nar_size,
+                        max_size: self.max_nar_size,
+                        seen: 0,
+                    }),
+                    self.nar_hash_check.as_ref().map(|f| f(add)).transpose()?,
+                );
+                framed_data::stream_with_check(
+                    &mut self.read.inner,
+                    &mut self.proxy.child_in,
+                    &mut check,
+                )?
+            }
+            WorkerOp::AddMultipleToStore(..) if self.add_multiple_check.is_some() => {
+                let check = self.add_multiple_check.as_ref().unwrap();
+                check(
+                    &mut self.read.inner,
+                    &mut self.proxy.child_in,
+                    self.protocol_version,
+                )?
+            }
+            _ => op.stream(&mut self.read.inner, &mut self.proxy.child_in)?,
+        }
+        self.proxy.child_in.flush()?;
+        self.state = ConnState::AwaitingReply;
+
+        match self.forward_stderr() {
+            // The op failed, and the client has been told; there's no response.
+            Err(Error::UpstreamDaemon(e)) => {
+                eprintln!("daemon error for {}: {e}", op.name());
+                return Ok(());
+            }
+            r => r?,
+        }
+
+        // Read back the actual response.
+        let rewrite = self.store_dir_rewrite.as_ref().map(PathRewrite::reversed);
+        if let (Some(cache), Some(key)) = (&mut self.path_info_cache, cache_key) {
+            let mut reply = Vec::new();
+            op.proxy_response_with(
+                &mut self.proxy.child_out,
+                &mut reply,
+                self.protocol_version,
+                rewrite.as_ref(),
+                self.self_check,
+            )?;
+            self.write
+                .inner
+                .write_all(&reply)
+                .and_then(|()| self.write.inner.flush())
+                .map_err(|e| Error::from(e).or_client_disconnected())?;
+            cache.insert(key, reply, Instant::now());
+            return Ok(());
+        }
+        let mut client = ClientWrite::new(&mut self.write.inner);
+        let result = op
+            .proxy_response_with(
+                &mut self.proxy.child_out,
+                &mut client,
+                self.protocol_version,
+                rewrite.as_ref(),
+                self.self_check,
+            )
+            .and_then(|()| Ok(client.flush()?));
+        client.blame(result)
+    }
+}
+
+/// A proxy that sends each op to one of several upstream daemons.
+///
+/// Upstream 0 is the daemon of the [`NixProxy`] that the router is made from, and the
+/// ones added with [`NixRouter::add_upstream`] are numbered from 1. For every op that
+/// the client sends, `route` picks the daemon to forward it to. Ops that change the
+/// store should usually all go to the same daemon, so that later queries see the change.
+///
+/// All of the daemons are spoken to with the same protocol version, which is the oldest
+/// one that any of them (or the client) speaks. The connection stats count the bytes
+/// exchanged with all of the daemons; the daemon identity is upstream 0's.
+pub struct NixRouter<R, W> {
+    proxy: NixProxy<R, W>,
+}
+
+impl<R: Read, W: Write> NixRouter<R, W> {
+    pub fn new(
+        mut proxy: NixProxy<R, W>,
+        route: impl Fn(&WorkerOp) -> usize + Send + 'static,
+    ) -> Self {
+        proxy.routes = Some(Routes {
+            upstreams: Vec::new(),
+            route: Box::new(route),
+        });
+        NixRouter { proxy }
+    }
+
+    /// Add an upstream daemon that we talk to through `read` and `write`, and return
+    /// its number.
+    pub fn add_upstream(
+        &mut self,
+        read: impl Read + Send + 'static,
+        write: impl Write + Send + 'static,
+    ) -> usize {
+        let routes = self
+            .proxy
+            .routes
+            .as_mut()
+            .expect("a router always has routes");
+        routes.upstreams.push(DaemonHandle {
+            child_in: Counted::new(Box::new(write)),
+            child_out: Counted::new(Box::new(read)),
+            child: None,
+        });
+        routes.upstreams.len()
+    }
+
+    /// The underlying proxy, for configuring it. Its settings apply to all upstreams.
+    pub fn proxy_mut(&mut self) -> &mut NixProxy<R, W> {
+        &mut self.proxy
+    }
+
+    /// Like [`NixProxy::process_connection`], but with an upstream daemon for each op.
+    ///
+    /// Fails if `route` picks an upstream that doesn't exist.
+    pub fn process_connection(&mut self) -> Result<ConnectionStats> {
+        self.proxy.process_connection()
+    }
+}
+
+/// A version of the worker protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DaemonVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl From<u64> for DaemonVersion {
+    fn from(x: u64) -> Self {
+        let major = ((x >> 8) & 0xff) as u8;
+        let minor = (x & 0xff) as u8;
+        Self { major, minor }
+    }
+}
+
+impl std::str::FromStr for DaemonVersion {
+    type Err = anyhow::Error;
+
+    /// Parse a version like `1.34`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s
+            .split_once('.')
+            .ok_or_else(|| anyhow!("expected a version like 1.34, got {s:?}"))?;
+        Ok(DaemonVersion {
+            major: major.parse()?,
+            minor: minor.parse()?,
+        })
+    }
+}
+
+impl std::fmt::Display for DaemonVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl From<DaemonVersion> for u64 {
+    fn from(DaemonVersion { major, minor }: DaemonVersion) -> Self {
+        ((major as u64) << 8) | minor as u64
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for NixString {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let data: Vec<u8> = Vec::arbitrary(u)?;
+        Ok(NixString::from(data))
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for NarHash {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let data: Vec<u8> = Vec::arbitrary(u)?;
+        Ok(NarHash {
+            data: ByteBuf::from(data),
+        })
+    }
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for OptionalStorePath {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // An empty path is indistinguishable from a missing one.
+        let path: Option<StorePath> = u.arbitrary()?;
+        Ok(OptionalStorePath(path.filter(|p| !p.0 .0.is_empty())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::test_util::handshake_reply;
+
+    /// A daemon's output: its handshake at [`PROTOCOL_VERSION`], and then `replies`.
+    fn daemon_sends(replies: &impl Serialize) -> Vec<u8> {
+        let mut bytes = handshake_reply(PROTOCOL_VERSION);
+        to_writer(&mut bytes, replies).unwrap();
+        bytes
+    }
+
+    /// What the proxy sends a client in the handshake at protocol `version`. Before
+    /// 1.33, it doesn't say what it is.
+    fn proxy_handshake(version: DaemonVersion) -> Vec<u8> {
+        assert!(version <= PROTOCOL_VERSION);
+        let mut bytes = to_vec(&(WORKER_MAGIC_2, u64::from(version))).unwrap();
+        if version.minor >= IDENTITY_MINOR {
+            to_writer(
+                &mut bytes,
+                &NixString::from(b"rust-nix-bazel-0.1.0".to_vec()),
+            )
+            .unwrap();
+        }
+        to_writer(&mut bytes, &stderr::Msg::Last(())).unwrap();
+        bytes
+    }
+
+    /// A writer whose output can be inspected after it has been moved into the proxy.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+
+        assert_send::<NixProxy<std::net::TcpStream, std::net::TcpStream>>();
+        assert_send::<client::NixClient<std::net::TcpStream, std::net::TcpStream>>();
+        assert_send::<CancellationToken>();
+        assert_sync::<CancellationToken>();
+        assert_send::<WorkerOp>();
+        assert_sync::<WorkerOp>();
+    }
+
+    #[test]
+    fn proxy_handshake_in_memory() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap();
+        let daemon = to_vec(&(
+            WORKER_MAGIC_2,
+            version,
+            NixString::from(b"nix-daemon (Nix) 2.18".to_vec()),
+            stderr::Msg::Last(()),
+        ))
+        .unwrap();
+        let upstream = SharedBuf::default();
+
+        let mut to_client = Vec::new();
+        NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            Cursor::new(daemon),
+            upstream.clone(),
+        )
+        .process_connection()
+        .unwrap();
+
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()
+        );
+        assert_eq!(to_client, proxy_handshake(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn probe_version() {
+        let ours = u64::from(PROTOCOL_VERSION);
+        let theirs = DaemonVersion {
+            major: PROTOCOL_VERSION.major,
+            minor: PROTOCOL_VERSION.minor + 3,
+        };
+        let daemon = to_vec(&(
+            WORKER_MAGIC_2,
+            u64::from(theirs),
+            NixString::from(b"nix-daemon (Nix) 2.24".to_vec()),
+            stderr::Msg::Next(NixString::from(b"hello".to_vec())),
+            stderr::Msg::Last(()),
+        ))
+        .unwrap();
+        let upstream = SharedBuf::default();
+
+        let mut to_client = Vec::new();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(Vec::new()),
+            &mut to_client,
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        assert_eq!(proxy.probe_version().unwrap(), theirs);
+        drop(proxy);
+
+        // We advertise our own version, since it's the older one.
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, ours, 0u64, 0u64)).unwrap()
+        );
+        assert!(to_client.is_empty());
+    }
+
+    #[test]
+    fn ping() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let daemon = daemon_sends(&((stderr::Msg::Last(()), StorePathSet::default()),));
+        let upstream = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            std::io::empty(),
+            std::io::sink(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        assert_eq!(proxy.ping().unwrap(), PROTOCOL_VERSION);
+        assert_eq!(proxy.ping().unwrap(), PROTOCOL_VERSION);
+        // There's nothing left to answer a third ping.
+        assert!(proxy.ping().is_err());
+
+        let query = worker_op::QueryValidPaths {
+            paths: StorePathSet::default(),
+            builders_use_substitutes: false,
+        };
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(
+                (WORKER_MAGIC_1, version, 0u64, 0u64),
+                (31u64, &query),
+                (31u64, &query),
+            ))
+            .unwrap()
+        );
+
+        // An old daemon is pinged in its own version, where the query is just the paths.
+        let old = DaemonVersion {
+            major: 1,
+            minor: 26,
+        };
+        let daemon = [
+            handshake_reply(old),
+            to_vec(&(stderr::Msg::Last(()), StorePathSet::default())).unwrap(),
+        ]
+        .concat();
+        let upstream = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            std::io::empty(),
+            std::io::sink(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        assert_eq!(proxy.ping().unwrap(), old);
+        assert_eq!(proxy.ping().unwrap(), old);
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(
+                (WORKER_MAGIC_1, version, 0u64, 0u64),
+                (31u64, StorePathSet::default()),
+            ))
+            .unwrap()
+        );
+
+        // A daemon that exits straight away.
+        let mut proxy = NixProxy::with_command_env(
+            std::io::empty(),
+            std::io::sink(),
+            "true",
+            [] as [&str; 0],
+            HashMap::new(),
+            false,
+        )
+        .unwrap();
+        assert!(proxy.ping().is_err());
+    }
+
+    #[test]
+    fn daemon_identity() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let daemon = to_vec(&(
+            (WORKER_MAGIC_2, version),
+            NixString::from(b"nix-daemon (Nix) 2.18.1".to_vec()),
+            stderr::Msg::Last(()),
+        ))
+        .unwrap();
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap();
+        let stats = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::sink(),
+            Cursor::new(daemon),
+            std::io::sink(),
+        )
+        .process_connection()
+        .unwrap();
+
+        let identity = stats.daemon_identity.unwrap();
+        assert_eq!(identity.to_string(), "nix-daemon (Nix) 2.18.1");
+        assert_eq!(identity.version(), Some("2.18.1"));
+        assert_eq!(DaemonIdentity("lix".to_owned()).version(), None);
+    }
+
+    #[test]
+    fn upstream_handshake() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let daemon = to_vec(&(
+            WORKER_MAGIC_2,
+            version,
+            NixString::from(b"nix-daemon (Nix) 2.18".to_vec()),
+        ))
+        .unwrap();
+        let upstream = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(Vec::new()),
+            std::io::sink(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        // Offering a newer version than ours gets ours, since we couldn't follow the
+        // rest of a newer handshake (like its feature exchange).
+        let newer = u64::from(DaemonVersion {
+            major: 1,
+            minor: 38,
+        });
+        assert_eq!(proxy.upstream_handshake(newer).unwrap(), PROTOCOL_VERSION);
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()
+        );
+
+        let daemon = to_vec(&(WORKER_MAGIC_1, version)).unwrap();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(Vec::new()),
+            std::io::sink(),
+            Cursor::new(daemon),
+            std::io::sink(),
+        );
+        let err = proxy.upstream_handshake(version).unwrap_err();
+        assert!(err.to_string().contains("WORKER_MAGIC_2"), "{err}");
+    }
+
+    #[test]
+    fn upstream_versions() {
+        let ours = u64::from(PROTOCOL_VERSION);
+        let is_valid = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let build = worker_op::BuildPaths {
+            paths: vec![StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))],
+            derived_paths: Vec::new(),
+            build_mode: worker_op::BuildMode::Normal,
+        };
+        let build = WorkerOp::BuildPaths(worker_op::Plain(build), worker_op::Resp::new());
+        // A newer daemon speaks our version.
+        let newer = u64::from(DaemonVersion {
+            major: 1,
+            minor: 35,
+        });
+        let client = to_vec(&(WORKER_MAGIC_1, newer, 0u64, 0u64, &is_valid)).unwrap();
+        let daemon = to_vec(&(
+            (WORKER_MAGIC_2, newer, NixString::default()),
+            stderr::Msg::Last(()),
+            (stderr::Msg::Last(()), true),
+        ))
+        .unwrap();
+        let mut to_client = Vec::new();
+        let stats = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            Cursor::new(daemon),
+            std::io::sink(),
+        )
+        .process_connection()
+        .unwrap();
+        assert_eq!(stats.protocol_version, PROTOCOL_VERSION);
+        let reply = to_vec(&(stderr::Msg::Last(()), true)).unwrap();
+        assert_eq!(
+            to_client,
+            [proxy_handshake(PROTOCOL_VERSION), reply].concat()
+        );
+
+        // With an older daemon, we all speak its version. Before 1.30, `BuildPaths`
+        // takes plain store paths.
+        let older = u64::from(DaemonVersion {
+            major: 1,
+            minor: 29,
+        });
+        let mut client = to_vec(&(WORKER_MAGIC_1, ours, 0u64, 0u64)).unwrap();
+        client.write_nix_versioned(&build, older.into()).unwrap();
+        let daemon = [
+            handshake_reply(older.into()),
+            to_vec(&(stderr::Msg::Last(()), 1u64)).unwrap(),
+        ]
+        .concat();
+        let upstream = SharedBuf::default();
+        let mut to_client = Vec::new();
+        let stats = NixProxy::from_io(
+            Cursor::new(client.clone()),
+            &mut to_client,
+            Cursor::new(daemon),
+            upstream.clone(),
+        )
+        .process_connection()
+        .unwrap();
+        assert_eq!(stats.protocol_version, DaemonVersion::from(older));
+        assert_eq!(*upstream.0.lock().unwrap(), client);
+        let reply = to_vec(&(stderr::Msg::Last(()), 1u64)).unwrap();
+        assert_eq!(to_client, [proxy_handshake(older.into()), reply].concat());
+
+        // Daemons that are too old, or from a different major version, are refused.
+        for version in [0x114u64, 0x222] {
+            let daemon = to_vec(&(WORKER_MAGIC_2, version)).unwrap();
+            let err = NixProxy::from_io(
+                Cursor::new(Vec::new()),
+                std::io::sink(),
+                Cursor::new(daemon),
+                std::io::sink(),
+            )
+            .process_connection()
+            .unwrap_err();
+            assert!(err.to_string().contains("unsupported"), "{err}");
+        }
+    }
+
+    #[test]
+    fn write_string_from() {
+        for len in [0, 5, 8, 4099] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut write = NixWrite { inner: Vec::new() };
+            write.write_string_from(len as u64, &mut &data[..]).unwrap();
+            write.write_u64(7).unwrap();
+
+            let mut expected = NixWrite { inner: Vec::new() };
+            expected.write_string(&data).unwrap();
+            expected.write_u64(7).unwrap();
+            assert_eq!(write.inner, expected.inner);
+
+            let mut read = NixRead {
+                inner: Cursor::new(write.inner),
+            };
+            assert_eq!(read.read_string().unwrap(), NixString::from(data));
+            assert_eq!(read.read_u64().unwrap(), 7);
+        }
+
+        // A source that ends early is an error, not a short string.
+        let mut write = NixWrite { inner: Vec::new() };
+        let err = write.write_string_from(10, &mut &b"short"[..]).unwrap_err();
+        assert!(err.io_error().is_some(), "{err:?}");
+    }
+
+    #[test]
+    fn read_u64_at_boundary() {
+        let mut read = NixRead {
+            inner: Cursor::new(to_vec(&7u64).unwrap()),
+        };
+        assert_eq!(read.read_u64_at_boundary().unwrap(), Some(7));
+        assert_eq!(read.read_u64_at_boundary().unwrap(), None);
+
+        let mut read = NixRead {
+            inner: Cursor::new(vec![1, 2, 3]),
+        };
+        assert!(matches!(
+            read.read_u64_at_boundary(),
+            Err(serialize::Error::Truncated { got: 3 })
+        ));
+    }
+
+    #[test]
+    fn truncated_op_is_an_error() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let op = to_vec(&op).unwrap();
+        let client = [
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap(),
+            op[..12].to_vec(),
+        ]
+        .concat();
+        let daemon = handshake_reply(PROTOCOL_VERSION);
+
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::sink(),
+            Cursor::new(daemon),
+            std::io::sink(),
+        );
+        assert!(proxy.process_connection().is_err());
+    }
+
+    #[test]
+    fn out_of_order_messages() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let daemon = || Cursor::new(handshake_reply(PROTOCOL_VERSION));
+
+        // The frames of a source, without the op that they belong to.
+        let client = to_vec(&(
+            (WORKER_MAGIC_1, version, 0u64, 0u64),
+            (4u64, 0x6f6f66u64, 0u64),
+        ))
+        .unwrap();
+        let mut proxy =
+            NixProxy::from_io(Cursor::new(client), Vec::new(), daemon(), std::io::sink());
+        let err = proxy.process_connection().unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::ProtocolStateViolation {
+                    state: ConnState::Ready,
+                    got,
+                } if got == "opcode 4"
+            ),
+            "{err:?}"
+        );
+
+        // An op before the handshake.
+        let client = to_vec(&WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        ))
+        .unwrap();
+        let mut proxy =
+            NixProxy::from_io(Cursor::new(client), Vec::new(), daemon(), std::io::sink());
+        assert_eq!(proxy.conn_state(), ConnState::Handshaking);
+        let err = proxy.process_connection().unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::ProtocolStateViolation {
+                    state: ConnState::Handshaking,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        assert!(matches!(
+            proxy.next_op(),
+            Err(Error::ProtocolStateViolation { .. })
+        ));
+    }
+
+    #[test]
+    fn second_handshake() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let handshake = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap();
+        let daemon = handshake_reply(PROTOCOL_VERSION);
+
+        let client = [handshake.clone(), handshake].concat();
+        let upstream = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::sink(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        let err = proxy.process_connection().unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::ProtocolStateViolation {
+                    state: ConnState::Ready,
+                    got,
+                } if got == "a second handshake"
+            ),
+            "{err:?}"
+        );
+        // Nothing after the first handshake reached the daemon.
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()
+        );
+        assert!(matches!(
+            proxy.handshake(),
+            Err(Error::ProtocolStateViolation { .. })
+        ));
+    }
+
+    #[test]
+    fn client_too_old() {
+        let client = to_vec(&(WORKER_MAGIC_1, 0x109u64, 0u64, 0u64)).unwrap();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::sink(),
+            std::io::empty(),
+            std::io::sink(),
+        );
+        match proxy.handshake() {
+            Err(Error::ClientTooOld { got, minimum }) => {
+                assert_eq!(got, DaemonVersion { major: 1, minor: 9 });
+                assert_eq!(minimum, PROTOCOL_VERSION);
+            }
+            r => panic!("expected ClientTooOld, got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn daemon_error_ends_op() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &op)).unwrap();
+        let error = stderr::Msg::Error(stderr::StderrError::new("no such path"));
+        let daemon = daemon_sends(&(&error, (stderr::Msg::Last(()), true)));
+
+        let mut to_client = Vec::new();
+        NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            Cursor::new(daemon),
+            std::io::sink(),
+        )
+        .process_connection()
+        .unwrap();
+
+        // The error stands in for the first op's response, and the second op goes ahead.
+        let handshake = proxy_handshake(PROTOCOL_VERSION);
+        let ops = to_vec(&(&error, stderr::Msg::Last(()), true)).unwrap();
+        assert_eq!(to_client, [handshake, ops].concat());
+    }
+
+    #[test]
+    fn stderr_before_reply() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &op)).unwrap();
+        let replies = to_vec(&(
+            stderr::Msg::Next(NixString::from(b"checking".to_vec())),
+            stderr::Msg::Next(NixString::from(b"still checking".to_vec())),
+            (stderr::Msg::Last(()), true),
+            (stderr::Msg::Last(()), false),
+        ))
+        .unwrap();
+        let daemon = [handshake_reply(PROTOCOL_VERSION), replies.clone()].concat();
+
+        let mut to_client = Vec::new();
+        let stats = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            Cursor::new(daemon),
+            std::io::sink(),
+        )
+        .process_connection()
+        .unwrap();
+
+        // Both ops went through, and the client got the log messages before the reply.
+        assert_eq!(stats.ops_processed, 2);
+        assert!(to_client.ends_with(&replies));
+    }
+
+    #[test]
+    fn last_delimits_reply() {
+        use crate::test_util::ScriptedDaemon;
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::AddTempRoot(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        // The replies look like stderr opcodes, so they would be misread if the proxy
+        // didn't stop at `Last`.
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&op)
+            .reply(&[], &stderr::Opcode::Next.as_u64())
+            .expect_nix(&op)
+            .reply(
+                &[stderr::Msg::Next(NixString::from(b"adding".to_vec()))],
+                &stderr::Opcode::Last.as_u64(),
+            );
+        let (daemon_read, daemon_write) = daemon.io();
+
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &op)).unwrap();
+        let mut to_client = Vec::new();
+        let stats = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            daemon_read,
+            daemon_write,
+        )
+        .process_connection()
+        .unwrap();
+        daemon.finish();
+
+        assert_eq!(stats.ops_processed, 2);
+        let replies = to_vec(&(
+            (stderr::Msg::Last(()), stderr::Opcode::Next.as_u64()),
+            stderr::Msg::Next(NixString::from(b"adding".to_vec())),
+            (stderr::Msg::Last(()), stderr::Opcode::Last.as_u64()),
+        ))
+        .unwrap();
+        assert!(to_client.ends_with(&replies));
+    }
+
+    #[test]
+    fn forwarding_keeps_pace_with_client() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        // Counts the bytes that the proxy has read from the daemon.
+        struct CountingRead<R>(R, Arc<AtomicU64>);
+
+        impl<R: Read> Read for CountingRead<R> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = self.0.read(buf)?;
+                self.1.fetch_add(n as u64, Ordering::SeqCst);
+                Ok(n)
+            }
+        }
+
+        // A client that checks, whenever the proxy writes to it, how far ahead of it the
+        // proxy has read from the daemon.
+        struct SlowClient {
+            daemon_read: Arc<AtomicU64>,
+            written: u64,
+            max_lead: u64,
+        }
+
+        impl Write for SlowClient {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let lead = self
+                    .daemon_read
+                    .load(Ordering::SeqCst)
+                    .saturating_sub(self.written);
+                self.max_lead = self.max_lead.max(lead);
+                self.written += buf.len() as u64;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::AddTempRoot(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let mut daemon = to_vec(&(
+            (WORKER_MAGIC_2, version),
+            (
+                NixString::from(b"nix-daemon".to_vec()),
+                stderr::Msg::Last(()),
+            ),
+        ))
+        .unwrap();
+        let logs = (0..1000).map(|i| stderr::Msg::Next(NixString::from(format!("line {i}"))));
+        for msg in logs.chain([stderr::Msg::Last(())]) {
+            daemon.write_nix(&msg).unwrap();
+        }
+        daemon.write_nix(&1u64).unwrap();
+
+        let daemon_read = Arc::new(AtomicU64::new(0));
+        let mut client = SlowClient {
+            daemon_read: daemon_read.clone(),
+            written: 0,
+            max_lead: 0,
+        };
+        let daemon_len = daemon.len() as u64;
+        NixProxy::from_io(
+            Cursor::new(to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap()),
+            &mut client,
+            CountingRead(Cursor::new(daemon), daemon_read),
+            std::io::sink(),
+        )
+        .process_connection()
+        .unwrap();
+
+        // The proxy never reads more than a message ahead of what the client has taken,
+        // even though the daemon had everything ready up front.
+        assert!(client.written >= daemon_len);
+        assert!(
+            client.max_lead <= 64,
+            "read {} bytes ahead",
+            client.max_lead
+        );
+    }
+
+    #[test]
+    fn stderr_read() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let data = ByteBuf::from(b"hello".to_vec());
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &data)).unwrap();
+        let daemon = daemon_sends(&(stderr::Msg::Read(4096), (stderr::Msg::Last(()), true)));
+
+        let upstream = SharedBuf::default();
+        let mut to_client = Vec::new();
+        NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            Cursor::new(daemon),
+            upstream.clone(),
+        )
+        .process_connection()
+        .unwrap();
+
+        // The client's data follows the op upstream...
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &data)).unwrap()
+        );
+        // ...and the client saw the request for it.
+        let handshake = proxy_handshake(PROTOCOL_VERSION);
+        let replies = to_vec(&(stderr::Msg::Read(4096), stderr::Msg::Last(()), true)).unwrap();
+        assert_eq!(to_client, [handshake, replies].concat());
+    }
+
+    #[test]
+    fn buffered_client_gets_reply() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap();
+        let daemon = daemon_sends(&((stderr::Msg::Last(()), true),));
+
+        let to_client = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::BufWriter::new(to_client.clone()),
+            Cursor::new(daemon),
+            std::io::sink(),
+        );
+        proxy.process_connection().unwrap();
+
+        // The reply made it through the buffer while the proxy is still alive.
+        let reply = to_vec(&(stderr::Msg::Last(()), true)).unwrap();
+        assert!(to_client.0.lock().unwrap().ends_with(&reply));
+    }
+
+    #[test]
+    fn connection_stats() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &op, &op)).unwrap();
+        let daemon = daemon_sends(&(
+            (stderr::Msg::Last(()), true),
+            (stderr::Msg::Last(()), false),
+            (stderr::Msg::Last(()), true),
+        ));
+        let daemon_len = daemon.len() as u64;
+
+        let stats = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::sink(),
+            Cursor::new(daemon),
+            std::io::sink(),
+        )
+        .process_connection()
+        .unwrap();
+
+        assert_eq!(stats.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(stats.ops_processed, 3);
+        assert_eq!(stats.bytes_up, 32 + 3 * to_vec(&op).unwrap().len() as u64);
+        assert_eq!(stats.bytes_down, daemon_len);
+        assert_eq!(
+            stats.daemon_identity,
+            Some(DaemonIdentity("scripted-daemon".into()))
+        );
+    }
+
+    #[test]
+    fn cancel_between_ops() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &op)).unwrap();
+        let daemon = daemon_sends(&((stderr::Msg::Last(()), true), (stderr::Msg::Last(()), true)));
+
+        // Cancel as soon as the first op has been forwarded.
+        struct CancelOnOp(SharedBuf, CancellationToken);
+        impl Write for CancelOnOp {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                // The upstream handshake is 32 bytes; anything after that is an op.
+                if self.0 .0.lock().unwrap().len() > 32 {
+                    self.1.cancel();
+                }
+                Ok(())
+            }
+        }
+
+        let upstream = SharedBuf::default();
+        let mut to_client = Vec::new();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            Cursor::new(daemon),
+            std::io::sink(),
+        );
+        proxy.proxy.child_in.inner =
+            Box::new(CancelOnOp(upstream.clone(), proxy.cancellation_token()));
+        proxy.process_connection().unwrap();
+
+        let forwarded = upstream.0.lock().unwrap().len();
+        assert_eq!(forwarded, 32 + to_vec(&op).unwrap().len());
+    }
+
+    #[test]
+    fn max_lifetime() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, &op)).unwrap();
+        let daemon = daemon_sends(&((stderr::Msg::Last(()), true), (stderr::Msg::Last(()), true)));
+
+        let upstream = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::sink(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        proxy.set_max_lifetime(Duration::ZERO);
+        let stats = proxy.process_connection().unwrap();
+
+        assert_eq!(stats.ops_processed, 1);
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap()
+        );
+    }
+
+    #[test]
+    fn client_disconnects_mid_nar() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let op = WorkerOp::NarFromPath(worker_op::Plain(path), worker_op::Resp::new());
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap();
+        let nar = nar::Nar::Contents(nar::NarFile {
+            contents: NixString::from(vec![0; 1 << 20]),
+            executable: false,
+        });
+        let daemon = daemon_sends(&(stderr::Msg::Last(()), nar));
+
+        // Behaves like a socket whose other end is closed after the first few kilobytes.
+        struct HangUp(usize);
+        impl Write for HangUp {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0 > 4096 {
+                    return Err(std::io::ErrorKind::BrokenPipe.into());
+                }
+                self.0 += buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let err = NixProxy::from_io(
+            Cursor::new(client),
+            HangUp(0),
+            Cursor::new(daemon),
+            std::io::sink(),
+        )
+        .process_connection()
+        .unwrap_err();
+        assert!(matches!(err, Error::ClientDisconnected), "{err:?}");
+    }
+
+    #[test]
+    fn daemon_dies_mid_reply() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let op = WorkerOp::NarFromPath(worker_op::Plain(path), worker_op::Resp::new());
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap();
+        let daemon = daemon_sends(&(stderr::Msg::Last(()),));
+
+        // Behaves like a socket whose other end is reset.
+        struct Reset;
+        impl Read for Reset {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::ConnectionReset.into())
+            }
+        }
+
+        // The daemon goes away part way through its reply.
+        let mut reply = to_vec(&nar::Nar::Contents(nar::NarFile {
+            contents: NixString::from(vec![0; 4096]),
+            executable: false,
+        }))
+        .unwrap();
+        reply.truncate(100);
+        let dying = Cursor::new([daemon, reply].concat()).chain(Reset);
+
+        let err = NixProxy::from_io(Cursor::new(client), std::io::sink(), dying, std::io::sink())
+            .process_connection()
+            .unwrap_err();
+        assert!(!matches!(err, Error::ClientDisconnected), "{err:?}");
+    }
+
+    /// A client that sends its magic number straight away, but then waits before
+    /// sending the rest of its handshake.
+    struct SlowClient {
+        data: Cursor<Vec<u8>>,
+        delay: Duration,
+        timeout: std::cell::Cell<Option<Duration>>,
+    }
+
+    impl Read for SlowClient {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.data.position() == 8 {
+                match self.timeout.get() {
+                    Some(timeout) if timeout < self.delay => {
+                        std::thread::sleep(timeout);
+                        return Err(std::io::ErrorKind::WouldBlock.into());
+                    }
+                    _ => std::thread::sleep(self.delay),
+                }
+            }
+            self.data.read(buf)
+        }
+    }
+
+    impl ReadTimeout for SlowClient {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+            self.timeout.set(timeout);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let slow_client = || SlowClient {
+            data: Cursor::new(to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()),
+            delay: Duration::from_millis(50),
+            timeout: Default::default(),
+        };
+        let daemon = || Cursor::new(handshake_reply(PROTOCOL_VERSION));
+
+        let mut proxy = NixProxy::from_io(slow_client(), Vec::new(), daemon(), std::io::sink());
+        proxy.set_handshake_timeout(Duration::from_millis(10));
+        let err = proxy.process_connection().unwrap_err();
+        assert!(matches!(err, Error::HandshakeTimeout), "{err:?}");
+        assert_eq!(proxy.read.inner.timeout.get(), None);
+
+        let mut proxy = NixProxy::from_io(slow_client(), Vec::new(), daemon(), std::io::sink());
+        proxy.set_handshake_timeout(Duration::from_secs(10));
+        proxy.process_connection().unwrap();
+        assert_eq!(proxy.read.inner.timeout.get(), None);
+    }
+
+    /// A client that sends its handshake one byte at a time, never waiting long enough
+    /// for a single read to time out.
+    struct TricklingClient {
+        data: Cursor<Vec<u8>>,
+        timeout: std::cell::Cell<Option<Duration>>,
+    }
+
+    impl Read for TricklingClient {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            assert!(self.timeout.get().is_some());
+            std::thread::sleep(Duration::from_millis(5));
+            let len = buf.len().min(1);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    impl ReadTimeout for TricklingClient {
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+            self.timeout.set(timeout);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_timeout_is_a_deadline() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let client = TricklingClient {
+            data: Cursor::new(to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()),
+            timeout: Default::default(),
+        };
+        let daemon = handshake_reply(PROTOCOL_VERSION);
+
+        let mut proxy = NixProxy::from_io(client, Vec::new(), Cursor::new(daemon), std::io::sink());
+        proxy.set_handshake_timeout(Duration::from_millis(50));
+        let err = proxy.process_connection().unwrap_err();
+        assert!(matches!(err, Error::HandshakeTimeout), "{err:?}");
+        assert_eq!(proxy.read.inner.timeout.get(), None);
+    }
+
+    #[test]
+    fn disallowed_op() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let build = WorkerOp::BuildPaths(
+            worker_op::Plain(worker_op::BuildPaths {
+                paths: Vec::new(),
+                derived_paths: vec![DerivedPath(path.0.clone())],
+                build_mode: worker_op::BuildMode::Normal,
+            }),
+            worker_op::Resp::new(),
+        );
+        let query = WorkerOp::IsValidPath(worker_op::Plain(path), worker_op::Resp::new());
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &build, &query)).unwrap();
+        let daemon = daemon_sends(&((stderr::Msg::Last(()), true),));
+
+        let upstream = SharedBuf::default();
+        let to_client = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            to_client.clone(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        proxy.allow_ops(|op| !matches!(op, WorkerOp::BuildPaths(..)));
+        proxy.process_connection().unwrap();
+
+        // Only the query made it upstream.
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &query)).unwrap()
+        );
+
+        let to_client = to_client.0.lock().unwrap();
+        let handshake = proxy_handshake(PROTOCOL_VERSION);
+        let mut replies = &to_client[handshake.len()..];
+        let msg: stderr::Msg = replies.read_nix().unwrap();
+        let stderr::Msg::Error(e) = msg else {
+            panic!("expected an error, got {msg:?}");
+        };
+        assert_eq!(
+            e.message(),
+            b"operation 'BuildPaths' is not allowed by this proxy"
+        );
+        let rest: (stderr::Msg, bool) = replies.read_nix().unwrap();
+        assert_eq!(rest, (stderr::Msg::Last(()), true));
+    }
+
+    #[test]
+    fn rewrite_store_dir_rejects_unreachable_paths() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::AddMultipleToStore(
+            worker_op::WithFramedSource(worker_op::AddMultipleToStore {
+                repair: false,
+                dont_check_sigs: false,
+            }),
+            worker_op::Resp::new(),
+        );
+        // The op is followed by an empty framed source.
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, 0u64)).unwrap();
+        let daemon = handshake_reply(PROTOCOL_VERSION);
+
+        let upstream = SharedBuf::default();
+        let to_client = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            to_client.clone(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        proxy.rewrite_store_dir(
+            Path(NixString::from(b"/nix/store".to_vec())),
+            Path(NixString::from(b"/gnu/store".to_vec())),
+        );
+        let stats = proxy.process_connection().unwrap();
+
+        assert_eq!(stats.ops_processed, 1);
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()
+        );
+        let to_client = to_client.0.lock().unwrap();
+        let msg = b"different store directory";
+        assert!(to_client.windows(msg.len()).any(|w| w == msg));
+    }
+
+    #[test]
+    fn query_references_is_refused() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let op = WorkerOp::QueryReferences(worker_op::Plain(path), worker_op::Resp::new());
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap();
+
+        let upstream = SharedBuf::default();
+        let to_client = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            to_client.clone(),
+            Cursor::new(handshake_reply(PROTOCOL_VERSION)),
+            upstream.clone(),
+        );
+        let stats = proxy.process_connection().unwrap();
+
+        // The op never reaches the daemon.
+        assert_eq!(stats.ops_processed, 1);
+        assert!(!upstream
+            .0
+            .lock()
+            .unwrap()
+            .ends_with(&op.to_bytes().unwrap()));
+        let to_client = to_client.0.lock().unwrap();
+        let msg = b"QueryReferences is obsolete";
+        assert!(to_client.windows(msg.len()).any(|w| w == msg));
+    }
+
+    #[test]
+    fn rewrite_store_dir() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let nix_path = |name: &str| StorePath(NixString::from(format!("/nix/store/{name}")));
+        let gnu_path = |name: &str| StorePath(NixString::from(format!("/gnu/store/{name}")));
+        let info = |path: &dyn Fn(&str) -> StorePath| worker_op::QueryPathInfoResponse {
+            path: Some(worker_op::ValidPathInfo {
+                deriver: OptionalStorePath(Some(path("abc-foo.drv"))),
+                hash: NarHash::from_bytes(&[0; 32]),
+                references: StorePathSet {
+                    paths: vec![path("def-bar")],
+                },
+                registration_time: 0,
+                nar_size: 0,
+                ultimate: false,
+                sigs: Default::default(),
+                content_address: Default::default(),
+            }),
+        };
+        let is_valid = |path| WorkerOp::IsValidPath(worker_op::Plain(path), worker_op::Resp::new());
+        let query = |path| WorkerOp::QueryPathInfo(worker_op::Plain(path), worker_op::Resp::new());
+
+        let client = to_vec(&(
+            (WORKER_MAGIC_1, version, 0u64, 0u64),
+            is_valid(nix_path("abc-foo")),
+            query(nix_path("abc-foo")),
+        ))
+        .unwrap();
+        let daemon = daemon_sends(&(
+            (stderr::Msg::Last(()), true),
+            (stderr::Msg::Last(()), info(&gnu_path)),
+        ));
+
+        let upstream = SharedBuf::default();
+        let mut to_client = Vec::new();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        proxy.rewrite_store_dir(
+            Path(NixString::from(b"/nix/store".to_vec())),
+            Path(NixString::from(b"/gnu/store".to_vec())),
+        );
+        proxy.process_connection().unwrap();
+        drop(proxy);
+
+        // The daemon sees its own store dir...
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(
+                (WORKER_MAGIC_1, version, 0u64, 0u64),
+                is_valid(gnu_path("abc-foo")),
+                query(gnu_path("abc-foo")),
+            ))
+            .unwrap()
+        );
+
+        // ...and the client sees its own.
+        let handshake = proxy_handshake(PROTOCOL_VERSION);
+        let replies = to_vec(&(
+            (stderr::Msg::Last(()), true),
+            (stderr::Msg::Last(()), info(&nix_path)),
+        ))
+        .unwrap();
+        assert_eq!(to_client, [handshake, replies].concat());
+    }
+
+    #[test]
+    fn op_policy() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let build = WorkerOp::BuildPaths(
+            worker_op::Plain(worker_op::BuildPaths {
+                paths: Vec::new(),
+                derived_paths: vec![DerivedPath(path.0.clone())],
+                build_mode: worker_op::BuildMode::Normal,
+            }),
+            worker_op::Resp::new(),
+        );
+        let query = WorkerOp::IsValidPath(worker_op::Plain(path), worker_op::Resp::new());
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &build, &query)).unwrap();
+        let daemon = |replies: Vec<u8>| [handshake_reply(PROTOCOL_VERSION), replies].concat();
+        let run = |identity: &str, daemon: Vec<u8>| {
+            let upstream = SharedBuf::default();
+            let to_client = SharedBuf::default();
+            let mut proxy = NixProxy::from_io(
+                Cursor::new(client.clone()),
+                to_client.clone(),
+                Cursor::new(daemon),
+                upstream.clone(),
+            );
+            proxy.set_client_identity(identity);
+            proxy.op_policy(|client, op| match (client.identity.as_deref(), op) {
+                (Some("ci"), _) => OpDecision::Allow,
+                (_, WorkerOp::BuildPaths(..)) => OpDecision::DenyWithError(format!(
+                    "builds are for CI only ({})",
+                    client.protocol_version
+                )),
+                _ => OpDecision::Allow,
+            });
+            proxy.process_connection().unwrap();
+            let upstream = upstream.0.lock().unwrap().clone();
+            let to_client = to_client.0.lock().unwrap().clone();
+            (upstream, to_client)
+        };
+
+        let handshake = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap();
+        let last = stderr::Msg::Last(());
+        let replies = to_vec(&((&last, 1u64), (&last, true))).unwrap();
+        let (upstream, _) = run("ci", daemon(replies));
+        assert_eq!(
+            upstream,
+            [handshake.clone(), to_vec(&(&build, &query)).unwrap()].concat()
+        );
+        let (upstream, to_client) = run("dev", daemon(to_vec(&(&last, true)).unwrap()));
+        assert_eq!(upstream, [handshake, to_vec(&query).unwrap()].concat());
+        let msg = format!("builds are for CI only ({PROTOCOL_VERSION})");
+        let error = stderr::Msg::Error(stderr::StderrError::new(msg));
+        let replies = to_vec(&(error, (&last, true))).unwrap();
+        assert!(to_client.ends_with(&replies));
+    }
+
+    #[test]
+    fn local_handler() {
+        struct Handler(Arc<Mutex<Vec<u8>>>);
+        impl WorkerOpHandler for Handler {
+            fn handle(
+                &mut self,
+                op: &WorkerOp,
+                version: DaemonVersion,
+                source: &mut dyn Read,
+                _progress: &mut handler::ProgressSink<'_>,
+            ) -> Result<Vec<u8>> {
+                // Replies are encoded for the version that the client negotiated.
+                assert_eq!(version, PROTOCOL_VERSION);
+                match op {
+                    WorkerOp::AddBuildLog(_, resp) => {
+                        source.read_to_end(&mut self.0.lock().unwrap())?;
+                        Ok(to_vec(&resp.ty(1))?)
+                    }
+                    _ => Err(anyhow!("no store here"))?,
+                }
+            }
+        }
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let add_log = WorkerOp::AddBuildLog(
+            worker_op::WithFramedSource(worker_op::AddBuildLog { path: path.clone() }),
+            worker_op::Resp::new(),
+        );
+        let is_valid = WorkerOp::IsValidPath(worker_op::Plain(path), worker_op::Resp::new());
+        let mut client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &add_log)).unwrap();
+        framed_data::FramedData {
+            data: vec![
+                ByteBuf::from(b"build ".to_vec()),
+                ByteBuf::from(b"log".to_vec()),
+            ],
+        }
+        .write(&mut client)
+        .unwrap();
+        client.extend(to_vec(&is_valid).unwrap());
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut to_client = Vec::new();
+        NixProxy::with_handler(Cursor::new(client), &mut to_client, Handler(log.clone()))
+            .process_connection()
+            .unwrap();
+
+        assert_eq!(*log.lock().unwrap(), b"build log");
+        let replies = to_vec(&(
+            (stderr::Msg::Last(()), 1u64),
+            stderr::Msg::Error(stderr::StderrError::new("Other error: no store here")),
+        ))
+        .unwrap();
+        let expected = [proxy_handshake(PROTOCOL_VERSION), replies].concat();
+        assert_eq!(to_client, expected);
+    }
+
+    #[test]
+    fn local_handler_progress() {
+        struct Handler;
+        impl WorkerOpHandler for Handler {
+            fn handle(
+                &mut self,
+                op: &WorkerOp,
+                _version: DaemonVersion,
+                _source: &mut dyn Read,
+                progress: &mut handler::ProgressSink<'_>,
+            ) -> Result<Vec<u8>> {
+                let WorkerOp::IsValidPath(_, resp) = op else {
+                    Err(anyhow!("no store here"))?
+                };
+                progress.log("checking")?;
+                progress.send(&stderr::Msg::Next(NixString::from(
+                    b"still checking".to_vec(),
+                )))?;
+                assert!(progress.send(&stderr::Msg::Last(())).is_err());
+                Ok(to_vec(&resp.ty(true))?)
+            }
+        }
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let is_valid = WorkerOp::IsValidPath(worker_op::Plain(path), worker_op::Resp::new());
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &is_valid)).unwrap();
+
+        let mut to_client = Vec::new();
+        NixProxy::with_handler(Cursor::new(client), &mut to_client, Handler)
+            .process_connection()
+            .unwrap();
+
+        let handshake = proxy_handshake(PROTOCOL_VERSION);
+        let mut expected = handshake;
+        for (opcode, line) in [(0x6f6c6d67u64, "checking"), (0x6f6c6d67, "still checking")] {
+            expected.extend(to_vec(&(opcode, NixString::from(line.to_owned()))).unwrap());
+        }
+        expected.extend(to_vec(&(stderr::Msg::Last(()), true)).unwrap());
+        assert_eq!(to_client, expected);
+    }
+
+    #[test]
+    fn dry_run_refuses_add_to_store() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::AddToStore(
+            worker_op::WithFramedSource(worker_op::AddToStore {
+                name: StorePath(NixString::from(b"empty".to_vec())),
+                cam_str: NixString::from(b"text:sha256".to_vec()),
+                refs: StorePathSet::default(),
+                repair: false,
+            }),
+            worker_op::Resp::new(),
+        );
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, 0u64)).unwrap();
+        let daemon = handshake_reply(PROTOCOL_VERSION);
+
+        let upstream = SharedBuf::default();
+        let to_client = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            to_client.clone(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        proxy.dry_run(true);
+        proxy.process_connection().unwrap();
+
+        // Only the handshake made it upstream, and the client was told why.
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()
+        );
+        let to_client = to_client.0.lock().unwrap();
+        let msg = b"can't be faked in dry-run mode";
+        assert!(to_client.windows(msg.len()).any(|w| w == msg));
+    }
+
+    #[test]
+    fn dry_run_collect_garbage() {
+        let version = u64::from(PROTOCOL_VERSION);
+        // CollectGarbage (20) deleting dead paths (2) with no limit.
+        let gc = to_vec(&(
+            20u64,
+            2u64,
+            StorePathSet::default(),
+            false,
+            u64::MAX,
+            0u64,
+            0u64,
+            0u64,
+        ))
+        .unwrap();
+        let client = [to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap(), gc].concat();
+        let daemon = handshake_reply(PROTOCOL_VERSION);
+
+        let upstream = SharedBuf::default();
+        let to_client = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            to_client.clone(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        proxy.dry_run(true);
+        proxy.process_connection().unwrap();
+
+        // Only the handshake made it upstream.
+        assert_eq!(
+            *upstream.0.lock().unwrap(),
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap()
+        );
+
+        let to_client = to_client.0.lock().unwrap();
+        let handshake = proxy_handshake(PROTOCOL_VERSION);
+        let mut replies = &to_client[handshake.len()..];
+        let (last, reply): (stderr::Msg, worker_op::CollectGarbageResponse) =
+            replies.read_nix().unwrap();
+        assert_eq!(last, stderr::Msg::Last(()));
+        assert_eq!(reply.paths.paths, vec![]);
+        assert_eq!(reply.bytes_freed, 0);
+        assert!(replies.is_empty());
+    }
+
+    type TestProxy = NixProxy<Cursor<Vec<u8>>, std::io::Sink>;
+
+    // Send a `SetOptions` through a proxy set up by `configure`, and return what the
+    // daemon got.
+    fn forward_set_options(
+        (verbosity, max_build_jobs, max_silent_time, build_verbosity): (u64, u64, u64, u64),
+        configure: impl FnOnce(&mut TestProxy),
+    ) -> worker_op::SetOptions {
+        let version = u64::from(PROTOCOL_VERSION);
+        let set_options = to_vec(&(
+            (19u64, false, false, false),
+            (verbosity, max_build_jobs, max_silent_time, 0u64),
+            (build_verbosity, 0u64, 0u64, 1u64),
+            (false, 0u64),
+        ))
+        .unwrap();
+        let client = [
+            to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap(),
+            set_options,
+        ]
+        .concat();
+        let daemon = daemon_sends(&(stderr::Msg::Last(()),));
+
+        let upstream = SharedBuf::default();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            std::io::sink(),
+            Cursor::new(daemon),
+            upstream.clone(),
+        );
+        configure(&mut proxy);
+        proxy.process_connection().unwrap();
+
+        let upstream = upstream.0.lock().unwrap();
+        let handshake = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64)).unwrap();
+        let op = WorkerOp::from_bytes(&upstream[handshake.len()..]).unwrap();
+        let WorkerOp::SetOptions(worker_op::Plain(options), _) = op else {
+            panic!("expected SetOptions, got {op:?}");
+        };
+        options
+    }
+
+    #[test]
+    fn clamp_verbosity() {
+        // Error and vomit.
+        let options = forward_set_options((0, 1, 0, 7), |proxy| {
+            proxy.clamp_verbosity(Some(Verbosity::Info), Some(Verbosity::Debug))
+        });
+        assert_eq!(options.verbosity, Verbosity::Info);
+        assert_eq!(options.build_verbosity, Verbosity::Debug);
+    }
+
+    #[test]
+    fn cap_build_options() {
+        let options = forward_set_options((3, 64, 600, 3), |proxy| {
+            proxy.cap_build_options(Some(8), Some(3600))
+        });
+        assert_eq!(options.max_build_jobs, 8);
+        assert_eq!(options.max_silent_time, 600);
+
+        // No silence limit at all gets the cap.
+        let options = forward_set_options((3, 4, 0, 3), |proxy| {
+            proxy.cap_build_options(Some(8), Some(3600))
+        });
+        assert_eq!(options.max_build_jobs, 4);
+        assert_eq!(options.max_silent_time, 3600);
+
+        let options = forward_set_options((3, 64, 0, 3), |_| {});
+        assert_eq!(options.max_build_jobs, 64);
+        assert_eq!(options.max_silent_time, 0);
+    }
+
+    #[test]
+    fn narinfo() {
+        let path = |p: &str| StorePath(NixString::from(format!("/nix/store/{p}").into_bytes()));
+        let info = ValidPathInfo {
+            deriver: OptionalStorePath(Some(path(
+                "7bcv0n5fp3xn5ygaa1m4l2gdblp4ssk5-hello-2.12.1.drv",
+            ))),
+            // sha256 of the empty string.
+            hash: NarHash {
+                data: ByteBuf::from(
+                    b"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_vec(),
+                ),
+            },
+            references: StorePathSet {
+                paths: vec![
+                    path("3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.37-8"),
+                    path("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1"),
+                ],
+            },
+            registration_time: 0,
+            nar_size: 226560,
+            ultimate: false,
+            sigs: StringSet {
+                paths: vec![NixString::from(b"cache.nixos.org-1:c2lnbmF0dXJl".to_vec())],
+            },
+            content_address: NixString::default(),
+        };
+        let info =
+            ValidPathInfoWithPath::new(path("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1"), info)
+                .unwrap();
+
+        assert_eq!(
+            info.to_narinfo_string("/nix/store").unwrap(),
+            "StorePath: /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1
+NarHash: sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73
+NarSize: 226560
+References: 3n58xw4373jp0ljirf06d8077j15pc4j-glibc-2.37-8 g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1
+Deriver: 7bcv0n5fp3xn5ygaa1m4l2gdblp4ssk5-hello-2.12.1.drv
+Sig: cache.nixos.org-1:c2lnbmF0dXJl
+"
+        );
+        assert!(info.to_narinfo_string("/gnu/store").is_err());
+
+        let mut bad = info.info.clone();
+        bad.references.paths.push(path("not-a-store-path"));
+        assert!(ValidPathInfoWithPath::new(info.path.clone(), bad).is_err());
+    }
+
+    #[test]
+    fn nar_size_checks() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let nar = to_vec(&nar::Nar::Contents(nar::NarFile {
+            contents: NixString::from(b"hello".to_vec()),
+            executable: false,
+        }))
+        .unwrap();
+        let add = |nar_size| {
+            WorkerOp::AddToStoreNar(
+                worker_op::WithFramedSource(worker_op::AddToStoreNar {
+                    path: StorePath(NixString::from(
+                        b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello".to_vec(),
+                    )),
+                    deriver: OptionalStorePath(None),
+                    nar_hash: NixString::default(),
+                    references: StorePathSet::default(),
+                    registration_time: 0,
+                    nar_size,
+                    ultimate: false,
+                    sigs: StringSet::default(),
+                    content_address: NixString::default(),
+                    repair: false,
+                    dont_check_sigs: false,
+                }),
+                worker_op::Resp::new(),
+            )
+        };
+        let run = |nar_size, max_size| {
+            let add = add(nar_size);
+            let mut client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &add)).unwrap();
+            framed_data::FramedData {
+                data: vec![ByteBuf::from(nar.clone())],
+            }
+            .write(&mut client)
+            .unwrap();
+            let daemon = daemon_sends(&(stderr::Msg::Last(()),));
+
+            let upstream = SharedBuf::default();
+            let mut proxy = NixProxy::from_io(
+                Cursor::new(client),
+                Vec::new(),
+                Cursor::new(daemon),
+                upstream.clone(),
+            );
+            proxy.check_nar_sizes(max_size);
+            let result = proxy.process_connection();
+            let upstream = upstream.0.lock().unwrap().clone();
+            (result, upstream)
+        };
+        let forwarded = |nar_size| {
+            [
+                to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &add(nar_size))).unwrap(),
+                to_vec(&(nar.len() as u64)).unwrap(),
+                nar.clone(),
+            ]
+            .concat()
+        };
+
+        let size = nar.len() as u64;
+        let (result, upstream) = run(size, Some(size));
+        result.unwrap();
+        assert_eq!(upstream, [forwarded(size), to_vec(&0u64).unwrap()].concat());
+
+        // A NAR that is shorter than declared is never terminated.
+        let (result, upstream) = run(size + 8, None);
+        match result {
+            Err(Error::NarSizeMismatch {
+                declared, actual, ..
+            }) => assert_eq!((declared, actual), (size + 8, size)),
+            r => panic!("expected NarSizeMismatch, got {r:?}"),
+        }
+        assert_eq!(upstream, forwarded(size + 8));
+
+        let (result, _) = run(size, Some(size - 1));
+        assert!(
+            matches!(result, Err(Error::NarTooLarge { .. })),
+            "{result:?}"
+        );
+    }
+
+    #[cfg(feature = "hash-sha2")]
+    #[test]
+    fn nar_hash_mismatch() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let nar = to_vec(&nar::Nar::Contents(nar::NarFile {
+            contents: NixString::from(b"hello".to_vec()),
+            executable: false,
+        }))
+        .unwrap();
+        let add = |nar_hash: &[u8]| {
+            WorkerOp::AddToStoreNar(
+                worker_op::WithFramedSource(worker_op::AddToStoreNar {
+                    path: StorePath(NixString::from(
+                        b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello".to_vec(),
+                    )),
+                    deriver: OptionalStorePath(None),
+                    nar_hash: NixString::from(nar_hash.to_vec()),
+                    references: StorePathSet::default(),
+                    registration_time: 0,
+                    nar_size: nar.len() as u64,
+                    ultimate: false,
+                    sigs: StringSet::default(),
+                    content_address: NixString::default(),
+                    repair: false,
+                    dont_check_sigs: false,
+                }),
+                worker_op::Resp::new(),
+            )
+        };
+        // sha256 of the empty string, which this NAR certainly isn't.
+        let wrong = add(b"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        let daemon = daemon_sends(&(stderr::Msg::Last(()),));
+        let run = |add: &WorkerOp, policy| {
+            let mut client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, add)).unwrap();
+            framed_data::FramedData {
+                data: vec![ByteBuf::from(nar.clone())],
+            }
+            .write(&mut client)
+            .unwrap();
+            let upstream = SharedBuf::default();
+            let mut proxy = NixProxy::from_io(
+                Cursor::new(client),
+                Vec::new(),
+                Cursor::new(daemon.clone()),
+                upstream.clone(),
+            );
+            proxy.check_nar_hashes::<hash::Sha256>(policy);
+            let result = proxy.process_connection();
+            let upstream = upstream.0.lock().unwrap().clone();
+            (result, upstream)
+        };
+        let forwarded = |add: &WorkerOp| {
+            [
+                to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, add)).unwrap(),
+                to_vec(&(nar.len() as u64)).unwrap(),
+                nar.clone(),
+                to_vec(&0u64).unwrap(),
+            ]
+            .concat()
+        };
+
+        // With a warning, the whole thing is forwarded.
+        let (result, upstream) = run(&wrong, NarHashPolicy::Warn);
+        result.unwrap();
+        assert_eq!(upstream, forwarded(&wrong));
+
+        // Otherwise, the daemon never sees the end of the NAR.
+        let (result, upstream) = run(&wrong, NarHashPolicy::Fail);
+        assert!(
+            matches!(result, Err(Error::NarHashMismatch { .. })),
+            "{result:?}"
+        );
+        assert_eq!(
+            upstream,
+            [
+                to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &wrong)).unwrap(),
+                to_vec(&(nar.len() as u64)).unwrap(),
+                nar.clone(),
+            ]
+            .concat()
+        );
+
+        // A hash that can't be parsed can't be checked: that's also only a warning.
+        let garbled = add(b"not a hash");
+        let (result, upstream) = run(&garbled, NarHashPolicy::Warn);
+        result.unwrap();
+        assert_eq!(upstream, forwarded(&garbled));
+
+        let (result, _) = run(&garbled, NarHashPolicy::Fail);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn path_info_cache() {
+        use crate::test_util::ScriptedDaemon;
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = |name: &str| StorePath(NixString::from(format!("/nix/store/{name}")));
+        let query =
+            |name| WorkerOp::QueryPathInfo(worker_op::Plain(path(name)), worker_op::Resp::new());
+        let info = worker_op::QueryPathInfoResponse {
+            path: Some(worker_op::ValidPathInfo {
+                deriver: OptionalStorePath(None),
+                hash: NarHash::from_bytes(&[0; 32]),
+                references: StorePathSet::default(),
+                registration_time: 0,
+                nar_size: 1234,
+                ultimate: false,
+                sigs: Default::default(),
+                content_address: Default::default(),
+            }),
+        };
+        let missing = worker_op::QueryPathInfoResponse { path: None };
+        // `CollectGarbage` has private fields, so build it from the wire format.
+        let gc: WorkerOp =
+            from_bytes(&to_vec(&(20u64, 2u64, 0u64, false, u64::MAX, 0u64, 0u64, 0u64)).unwrap())
+                .unwrap();
+        let gc_reply = gc.dry_run_reply(PROTOCOL_VERSION).unwrap().unwrap();
+
+        // The second query for foo is a hit, and never reaches the daemon; bar is a miss;
+        // and after the garbage collection, foo has to be asked about again.
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&query("abc-foo"))
+            .reply(&[], &info)
+            .expect_nix(&query("def-bar"))
+            .reply(&[], &missing)
+            .expect_nix(&gc)
+            .respond_nix(&stderr::Msg::Last(()))
+            .respond(gc_reply.clone())
+            .expect_nix(&query("abc-foo"))
+            .reply(&[], &missing);
+        let (daemon_read, daemon_write) = daemon.io();
+
+        let client = to_vec(&(
+            (WORKER_MAGIC_1, version, 0u64, 0u64),
+            query("abc-foo"),
+            query("abc-foo"),
+            query("def-bar"),
+            &gc,
+            query("abc-foo"),
+        ))
+        .unwrap();
+        let mut to_client = Vec::new();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            daemon_read,
+            daemon_write,
+        );
+        proxy.cache_path_info(16, Duration::from_secs(60));
+        let stats = proxy.process_connection().unwrap();
+        daemon.finish();
+        assert_eq!(stats.ops_processed, 5);
+
+        let replies = to_vec(&(
+            (stderr::Msg::Last(()), &info),
+            (stderr::Msg::Last(()), &info),
+            (stderr::Msg::Last(()), &missing),
+        ))
+        .unwrap();
+        let mut rest = to_vec(&stderr::Msg::Last(())).unwrap();
+        rest.extend(gc_reply);
+        rest.extend(to_vec(&(stderr::Msg::Last(()), &missing)).unwrap());
+        assert!(to_client.ends_with(&[replies, rest].concat()));
+    }
+
+    #[test]
+    fn path_info_cache_substitution() {
+        let query = |builders_use_substitutes| {
+            WorkerOp::QueryValidPaths(
+                worker_op::Plain(worker_op::QueryValidPaths {
+                    paths: StorePathSet::default(),
+                    builders_use_substitutes,
+                }),
+                worker_op::Resp::new(),
+            )
+        };
+        assert!(PathInfoCache::is_read_only(&query(false)));
+        // The daemon may substitute missing paths, making them valid.
+        assert!(!PathInfoCache::is_read_only(&query(true)));
+    }
+
+    #[test]
+    fn path_info_cache_expiry() {
+        let key = |name: &str| ("IsValidPath", StorePath(NixString::from(name.to_owned())));
+        let start = Instant::now();
+        let mut cache = PathInfoCache::new(2, Duration::from_secs(10));
+        cache.insert(key("a"), vec![1], start);
+        cache.insert(key("b"), vec![2], start);
+        assert_eq!(cache.get(&key("a"), start), Some(&[1][..]));
+
+        // b is the least recently used, so it makes way for c.
+        cache.insert(key("c"), vec![3], start);
+        assert_eq!(cache.get(&key("b"), start), None);
+        assert_eq!(cache.get(&key("c"), start), Some(&[3][..]));
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(cache.get(&key("a"), later), None);
+    }
+
+    #[test]
+    fn nix_string_text() {
+        let text = NixString::from("héllo".to_owned());
+        assert_eq!(text.try_to_str(), Some("héllo"));
+        assert_eq!(text.to_string_lossy(), "héllo");
+        assert_eq!(format!("{text:?}"), "héllo");
+
+        let binary = NixString::from(b"hi\xff".to_vec());
+        assert_eq!(binary.try_to_str(), None);
+        assert_eq!(binary.to_string_lossy(), "hi\u{fffd}");
+        assert_eq!(format!("{binary:?}"), "0x6869ff");
+
+        let nul = NixString::from(b"a\0b".to_vec());
+        assert_eq!(nul.try_to_str(), Some("a\0b"));
+        assert_eq!(nul.to_string_lossy(), "a\0b");
+        assert_eq!(format!("{nul:?}"), "0x610062");
+    }
+
+    #[test]
+    fn router() {
+        use crate::test_util::ScriptedDaemon;
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let is_valid = |name: &str| {
+            WorkerOp::IsValidPath(
+                worker_op::Plain(StorePath(NixString::from(format!("/nix/store/{name}")))),
+                worker_op::Resp::new(),
+            )
+        };
+        let first = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&is_valid("abc-foo"))
+            .reply(&[], &true);
+        let second = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&is_valid("def-bar"))
+            .reply(&[], &false);
+
+        let client = to_vec(&(
+            (WORKER_MAGIC_1, version, 0u64, 0u64),
+            is_valid("abc-foo"),
+            is_valid("def-bar"),
+        ))
+        .unwrap();
+        let mut to_client = Vec::new();
+        let (read, write) = first.io();
+        let proxy = NixProxy::from_io(Cursor::new(client), &mut to_client, read, write);
+        // Route by the first letter of the path's hash.
+        let mut router = NixRouter::new(proxy, |op| match op {
+            WorkerOp::IsValidPath(path, _) if path.0.as_ref().starts_with(b"/nix/store/d") => 1,
+            _ => 0,
+        });
+        let (read, write) = second.io();
+        assert_eq!(router.add_upstream(read, write), 1);
+        let stats = router.process_connection().unwrap();
+        first.finish();
+        second.finish();
+
+        assert_eq!(stats.ops_processed, 2);
+        let replies = to_vec(&(
+            (stderr::Msg::Last(()), true),
+            (stderr::Msg::Last(()), false),
+        ))
+        .unwrap();
+        assert!(to_client.ends_with(&replies));
+    }
+
+    #[test]
+    fn add_to_store_empty_source() {
+        use crate::test_util::ScriptedDaemon;
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::AddToStore(
+            worker_op::WithFramedSource(worker_op::AddToStore {
+                name: StorePath(NixString::from(b"empty".to_vec())),
+                cam_str: NixString::from(b"text:sha256".to_vec()),
+                refs: StorePathSet::default(),
+                repair: false,
+            }),
+            worker_op::Resp::new(),
+        );
+        let reply = to_vec(&ValidPathInfoWithPath {
+            path: StorePath(NixString::from(b"/nix/store/abc-empty".to_vec())),
+            info: ValidPathInfo {
+                deriver: OptionalStorePath(None),
+                hash: NarHash::from_bytes(&[0; 32]),
+                references: StorePathSet::default(),
+                registration_time: 0,
+                nar_size: 0,
+                ultimate: false,
+                sigs: Default::default(),
+                content_address: NixString::from(b"text:sha256:abc".to_vec()),
+            },
+        })
+        .unwrap();
+        // The op is forwarded with its source, which is nothing but the terminating frame.
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&(&op, 0u64))
+            .respond_nix(&stderr::Msg::Last(()))
+            .respond(reply.clone());
+        let (daemon_read, daemon_write) = daemon.io();
+
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op, 0u64)).unwrap();
+        let mut to_client = Vec::new();
+        let stats = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            daemon_read,
+            daemon_write,
+        )
+        .process_connection()
+        .unwrap();
+        daemon.finish();
+
+        assert_eq!(stats.ops_processed, 1);
+        let mut expected = to_vec(&stderr::Msg::Last(())).unwrap();
+        expected.extend(reply);
+        assert!(to_client.ends_with(&expected));
+    }
+
+    #[test]
+    fn strict_validation() {
+        use crate::test_util::ScriptedDaemon;
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let add_signatures = |sig: &[u8]| {
+            WorkerOp::AddSignatures(
+                worker_op::Plain(worker_op::AddSignatures {
+                    path: path.clone(),
+                    signatures: StringSet {
+                        paths: vec![NixString::from(sig.to_vec())],
+                    },
+                }),
+                worker_op::Resp::new(),
+            )
+        };
+        let good = add_signatures(b"cache.example.org-1:c2lnbmF0dXJl");
+        let bad = add_signatures(b"c2lnbmF0dXJl");
+        assert!(good.validate(PROTOCOL_VERSION).is_ok());
+        assert!(bad.validate(PROTOCOL_VERSION).is_err());
+
+        // Only the good op reaches the daemon.
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&good)
+            .reply(&[], &1u64);
+        let (daemon_read, daemon_write) = daemon.io();
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &bad, &good)).unwrap();
+        let mut to_client = Vec::new();
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            daemon_read,
+            daemon_write,
+        );
+        proxy.set_strict_validation(true);
+        proxy.process_connection().unwrap();
+        daemon.finish();
+
+        let msg = stderr::Msg::Error(stderr::StderrError::new(
+            "Invalid AddSignatures request: malformed signature c2lnbmF0dXJl",
+        ));
+        let replies = to_vec(&(msg, stderr::Msg::Last(()), 1u64)).unwrap();
+        assert!(to_client.ends_with(&replies));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        use crate::test_util::ScriptedDaemon;
+
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&op)
+            .reply(&[], &true);
+        let (daemon_read, daemon_write) = daemon.io();
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let stats = metrics::with_local_recorder(&recorder, || {
+            NixProxy::from_io(
+                Cursor::new(client),
+                std::io::sink(),
+                daemon_read,
+                daemon_write,
+            )
+            .process_connection()
+            .unwrap()
+        });
+        daemon.finish();
+
+        let metrics = snapshotter.snapshot().into_vec();
+        let counter = |name: &str, label: Option<&str>| {
+            metrics
+                .iter()
+                .find(|(key, _, _, _)| {
+                    let key = key.key();
+                    key.name() == name && key.labels().map(|l| l.value()).eq(label)
+                })
+                .map(|(_, _, _, value)| value)
+        };
+        assert_eq!(
+            counter("nix_remote_connections_total", None),
+            Some(&DebugValue::Counter(1))
+        );
+        assert_eq!(
+            counter("nix_remote_ops_total", Some("IsValidPath")),
+            Some(&DebugValue::Counter(1))
+        );
+        assert_eq!(
+            counter("nix_remote_bytes_up_total", None),
+            Some(&DebugValue::Counter(stats.bytes_up))
+        );
+        assert_eq!(
+            counter("nix_remote_bytes_down_total", None),
+            Some(&DebugValue::Counter(stats.bytes_down))
+        );
+        assert!(matches!(
+            counter("nix_remote_op_duration_seconds", Some("IsValidPath")),
+            Some(DebugValue::Histogram(h)) if h.len() == 1
+        ));
+    }
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut now = start;
+        let mut bucket = TokenBucket::new(10, now);
+        for _ in 0..100 {
+            now += bucket.take(now);
+        }
+        // The first 10 ops go through straight away, and the other 90 are spaced out.
+        let elapsed = (now - start).as_secs_f64();
+        assert!((elapsed - 9.0).abs() < 0.01, "{elapsed}");
+
+        // After a break, the bucket refills (but not beyond its capacity).
+        now += Duration::from_secs(60);
+        for _ in 0..10 {
+            assert_eq!(bucket.take(now), Duration::ZERO);
+        }
+        assert!(bucket.take(now) > Duration::ZERO);
+    }
+
+    #[test]
+    fn on_op() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let ops = [
+            WorkerOp::IsValidPath(worker_op::Plain(path.clone()), worker_op::Resp::new()),
+            WorkerOp::EnsurePath(worker_op::Plain(path.clone()), worker_op::Resp::new()),
+            WorkerOp::IsValidPath(worker_op::Plain(path), worker_op::Resp::new()),
+        ];
+        let client = to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &ops)).unwrap();
+        let daemon = daemon_sends(&(
+            (stderr::Msg::Last(()), true),
+            (stderr::Msg::Last(()), 1u64),
+            (stderr::Msg::Last(()), false),
+        ));
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut proxy = NixProxy::from_io(
+            Cursor::new(client),
+            Vec::new(),
+            Cursor::new(daemon),
+            std::io::sink(),
+        );
+        proxy.on_op({
+            let seen = seen.clone();
+            move |op| seen.lock().unwrap().push(op.name())
+        });
+        proxy.process_connection().unwrap();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["IsValidPath", "EnsurePath", "IsValidPath"]
+        );
+    }
+
+    #[test]
+    fn path_conversions() {
+        let bytes = b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1".to_vec();
+        let path = Path(NixString::from(bytes.clone()));
+        let store_path = StorePath::try_from(path.clone()).unwrap();
+        assert_eq!(store_path, StorePath(NixString::from(bytes.clone())));
+        assert_eq!(Path::from(store_path.clone()), path);
+        assert_eq!(NixString::from(store_path), NixString::from(bytes));
+
+        for bad in [
+            &b"/home/user/hello"[..],
+            b"/nix/store",
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q",
+            b"/nix/store/e1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello",
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-.hello",
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello/bin",
+        ] {
+            let path = Path(NixString::from(bad.to_vec()));
+            assert!(StorePath::try_from(path).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn store_path_ignoring_store_dir() {
+        let full = StorePath(NixString::from(
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1".to_vec(),
+        ));
+        let bare = StorePath(NixString::from(
+            b"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1".to_vec(),
+        ));
+        let other = StorePath(NixString::from(
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.2".to_vec(),
+        ));
+
+        assert_eq!(
+            full.hash_part().unwrap(),
+            b"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q"
+        );
+        assert_eq!(bare.hash_part().unwrap(), full.hash_part().unwrap());
+        assert_ne!(full, bare);
+        assert!(full.eq_ignoring_store_dir(&bare));
+        assert!(bare.eq_ignoring_store_dir(&full));
+        assert!(!full.eq_ignoring_store_dir(&other));
+    }
+
+    #[test]
+    fn paths_as_keys() {
+        let path = |s: &str| StorePath(NixString::from(s.as_bytes().to_vec()));
+        let hello = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1";
+        let other = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.2";
+
+        // Equal paths are deduplicated even when they don't share their bytes.
+        let set: std::collections::HashSet<_> = [path(hello), path(other), path(hello)].into();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&path(hello)));
+        assert!(set.contains(&path(other)));
+        assert!(!set.contains(&path(&hello[11..])));
+
+        let set: std::collections::HashSet<_> = [
+            Path(NixString::from(b"/tmp/a".to_vec())),
+            Path(NixString::from(b"/tmp/a".to_vec())),
+        ]
+        .into();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn connect_ssh_command() {
+        // `echo` stands in for ssh, so the "daemon" output is the command line.
+        let opts = SshOptions {
+            ssh_program: "echo".to_owned(),
+            extra_args: vec!["-oBatchMode=yes".to_owned()],
+            ..SshOptions::default()
+        };
+        let mut proxy =
+            NixProxy::connect_ssh(std::io::empty(), std::io::sink(), "builder", opts).unwrap();
+        let mut out = String::new();
+        proxy.proxy.child_out.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "-oBatchMode=yes -- builder nix-daemon --stdio\n");
+
+        for host in ["", "-oProxyCommand=touch /tmp/pwned", "-lroot"] {
+            let Err(err) = NixProxy::connect_ssh(
+                std::io::empty(),
+                std::io::sink(),
+                host,
+                SshOptions::default(),
+            ) else {
+                panic!("{host:?} was accepted");
+            };
+            assert!(err.to_string().contains("invalid ssh host"), "{err}");
+        }
+    }
+
+    #[test]
+    fn client_eof_closes_daemon() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = WorkerOp::IsValidPath(
+            worker_op::Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            worker_op::Resp::new(),
+        );
+        // The daemon answers the handshake and the op, waits for the proxy to hang up,
+        // and then leaves a note saying that it did.
+        let dir = std::env::temp_dir().join(format!("nix-remote-eof-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let replies = [
+            handshake_reply(PROTOCOL_VERSION),
+            to_vec(&(stderr::Msg::Last(()), true)).unwrap(),
+        ]
+        .concat();
+        std::fs::write(dir.join("replies"), replies).unwrap();
+        let script = format!(
+            "cat '{0}/replies'; cat >/dev/null; touch '{0}/closed'",
+            dir.display()
+        );
+        let mut proxy = NixProxy::with_command_env(
+            // The client sends its op and closes its end straight away.
+            Cursor::new(to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap()),
+            Vec::new(),
+            "/bin/sh",
+            ["-c", &script],
+            HashMap::new(),
+            false,
+        )
+        .unwrap();
+        let stats = proxy.process_connection().unwrap();
+        assert_eq!(stats.ops_processed, 1);
+        assert!(proxy
+            .write
+            .inner
+            .ends_with(&to_vec(&(stderr::Msg::Last(()), true)).unwrap()));
+        assert!(dir.join("closed").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn close_kills_lingering_daemon() {
+        // `sleep` doesn't read its input, so it doesn't notice when we close it.
+        let mut cmd = std::process::Command::new("sleep");
+        cmd.arg("30");
+        let mut daemon = DaemonHandle::spawn(cmd).unwrap();
+        let start = Instant::now();
+        daemon.close_within(Duration::from_millis(100)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        let status = daemon.child.as_mut().unwrap().try_wait().unwrap();
+        assert!(status.is_some_and(|s| !s.success()), "{status:?}");
+
+        // A daemon that exits on EOF isn't killed.
+        let mut daemon = DaemonHandle::spawn(std::process::Command::new("cat")).unwrap();
+        daemon.close_within(Duration::from_secs(10)).unwrap();
+        let status = daemon.child.as_mut().unwrap().try_wait().unwrap();
+        assert!(status.is_some_and(|s| s.success()), "{status:?}");
+    }
+
+    #[test]
+    fn store_uri_command() {
+        // `echo` stands in for the daemon, so its output is the command line.
+        for uri in [
+            "local?root=/tmp/store",
+            "/tmp/store",
+            "s3://cache",
+            "daemon",
+        ] {
+            let cmd = DaemonHandle::store_command("echo", uri).unwrap();
+            let mut daemon = DaemonHandle::spawn(cmd).unwrap();
+            let mut out = String::new();
+            daemon.child_out.read_to_string(&mut out).unwrap();
+            assert_eq!(out, format!("--stdio --store {uri}\n"));
+        }
+
+        for uri in ["", "relative/path", "://x", "local store", "bad scheme://x"] {
+            let Err(err) = NixProxy::with_store_uri(std::io::empty(), std::io::sink(), uri) else {
+                panic!("{uri:?} was accepted");
+            };
+            assert!(err.to_string().contains("invalid store URI"), "{err}");
+        }
+    }
+
+    #[test]
+    fn command_env() {
+        // The "daemon" prints the bits of its environment that we care about.
+        let envs = HashMap::from([("NIX_REMOTE".to_owned(), "local?root=/tmp/store".to_owned())]);
+        let mut proxy = NixProxy::with_command_env(
+            std::io::empty(),
+            std::io::sink(),
+            "/bin/sh",
+            ["-c", "printf %s \"$NIX_REMOTE|$HOME\""],
+            envs,
+            true,
+        )
+        .unwrap();
+        let mut out = String::new();
+        proxy.proxy.child_out.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "local?root=/tmp/store|");
     }
 }
@@ -1,9 +1,10 @@
-use nix_remote::NixProxy;
+use nix_remote::{Error, NixProxy};
 
 fn main() {
     let mut proxy = NixProxy::new(std::io::stdin(), std::io::stdout());
 
-    proxy.process_connection().unwrap_or_else(|e| {
-        eprintln!("{e:?}");
-    });
+    match proxy.process_connection() {
+        Ok(_) | Err(Error::ClientDisconnected) => {}
+        Err(e) => eprintln!("{e:?}"),
+    }
 }
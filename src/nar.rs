@@ -3,12 +3,22 @@
 //! The [`Nar`] struct represents a nar archive (essentially a directory tree) in memory.
 //! Since these can be large, it is often preferred to avoid buffering an entire nar in
 //! memory; the `stream` function allows for streaming a `Nar` (represented in the nix wire
-//! format) from a `std::io::Read` to a `std::io::Write`.
+//! format) from a `std::io::Read` to a `std::io::Write`, and the `list` function lists
+//! the entries in a `Nar` without keeping any file contents. `to_tar` converts a `Nar`
+//! to a tar archive in the same streaming way, and `from_tar` converts one back.
+
+use std::{
+    ffi::OsStr,
+    io::{self, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
 
 use serde::{de::SeqAccess, ser::SerializeTuple, Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
 use crate::{
+    hash::{Hasher, HashingWriter},
     serialize::{NixDeserializer, Tee},
     NixString,
 };
@@ -80,11 +90,25 @@ pub trait DirectorySink<'a>: DirectorySinkSuper {
 pub trait FileSink: std::io::Write {
     fn set_executable(&mut self, executable: bool);
     fn add_contents(&mut self, contents: &[u8]);
+
+    /// Called with the length of the file's contents, before they are written.
+    fn start_contents(&mut self, _len: u64) {}
+
+    /// Called once all of the file's contents have been written.
+    ///
+    /// Sinks that do anything with the contents after they have been written should do
+    /// it here, rather than on drop, so that any error is reported.
+    fn finish(self) -> std::io::Result<()>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
 }
 
 impl<'a> EntrySink<'a> for &'a mut Nar {
     type DirectorySink = &'a mut Vec<NarDirectoryEntry>;
-    type FileSink = &'a mut NarFile;
+    type FileSink = NarFileSink<'a>;
 
     fn become_directory(self) -> Self::DirectorySink {
         *self = Nar::Directory(Vec::new());
@@ -100,10 +124,13 @@ impl<'a> EntrySink<'a> for &'a mut Nar {
             contents: NixString::default(),
         });
         // TODO: can we express this better?
-        let Nar::Contents(contents) = self else {
+        let Nar::Contents(file) = self else {
             unreachable!()
         };
-        contents
+        NarFileSink {
+            file,
+            contents: Vec::new(),
+        }
     }
 
     fn become_symlink(self, target: NixString) {
@@ -111,7 +138,7 @@ impl<'a> EntrySink<'a> for &'a mut Nar {
     }
 }
 
-impl<'a> DirectorySinkSuper for &'a mut Vec<NarDirectoryEntry> {
+impl DirectorySinkSuper for &mut Vec<NarDirectoryEntry> {
     type EntrySink<'b> = &'b mut Nar;
 }
 
@@ -131,7 +158,16 @@ impl<'a> DirectorySink<'a> for &'a mut Vec<NarDirectoryEntry> {
     }
 }
 
-impl<'a> std::io::Write for &'a mut NarFile {
+/// Writes the contents of a [`NarFile`].
+///
+/// `NixString`s are immutable, so the contents are buffered here and only
+/// stored in the file by [`FileSink::finish`].
+pub struct NarFileSink<'a> {
+    file: &'a mut NarFile,
+    contents: Vec<u8>,
+}
+
+impl std::io::Write for NarFileSink<'_> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.add_contents(buf);
         Ok(buf.len())
@@ -142,20 +178,25 @@ impl<'a> std::io::Write for &'a mut NarFile {
     }
 }
 
-impl<'a> FileSink for &'a mut NarFile {
+impl FileSink for NarFileSink<'_> {
     fn set_executable(&mut self, executable: bool) {
-        self.executable = executable;
+        self.file.executable = executable;
     }
 
     fn add_contents(&mut self, contents: &[u8]) {
-        self.contents.0.extend_from_slice(contents);
+        self.contents.extend_from_slice(contents);
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        self.file.contents = NixString::from(self.contents);
+        Ok(())
     }
 }
 
 #[derive(Default)]
 struct Null;
 
-impl<'a> std::io::Write for &'a mut Null {
+impl std::io::Write for &mut Null {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         Ok(buf.len())
     }
@@ -165,7 +206,7 @@ impl<'a> std::io::Write for &'a mut Null {
     }
 }
 
-impl<'a> FileSink for &'a mut Null {
+impl FileSink for &mut Null {
     fn set_executable(&mut self, _executable: bool) {}
 
     fn add_contents(&mut self, _contents: &[u8]) {}
@@ -186,7 +227,7 @@ impl<'a> EntrySink<'a> for &'a mut Null {
     fn become_symlink(self, _target: NixString) {}
 }
 
-impl<'a> DirectorySinkSuper for &'a mut Null {
+impl DirectorySinkSuper for &mut Null {
     type EntrySink<'b> = &'b mut Null;
 }
 
@@ -199,6 +240,505 @@ impl<'a> DirectorySink<'a> for &'a mut Null {
     }
 }
 
+/// The kind of an entry in a Nar, as returned by [`list`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NarNodeType {
+    Regular,
+    Executable,
+    Symlink,
+    Directory,
+}
+
+// An `EntrySink` that records the path and type of every entry, and throws away file
+// contents.
+struct ListEntry<'a> {
+    out: &'a mut Vec<(PathBuf, NarNodeType)>,
+    path: PathBuf,
+}
+
+// The type of a regular file, which is changed if the file turns out to be executable.
+struct ListFile<'a>(&'a mut NarNodeType);
+
+impl<'a> EntrySink<'a> for ListEntry<'a> {
+    type DirectorySink = ListEntry<'a>;
+    type FileSink = ListFile<'a>;
+
+    fn become_directory(self) -> Self::DirectorySink {
+        self.out.push((self.path.clone(), NarNodeType::Directory));
+        self
+    }
+
+    fn become_file(self) -> Self::FileSink {
+        self.out.push((self.path, NarNodeType::Regular));
+        ListFile(&mut self.out.last_mut().unwrap().1)
+    }
+
+    fn become_symlink(self, _target: NixString) {
+        self.out.push((self.path, NarNodeType::Symlink));
+    }
+}
+
+impl DirectorySinkSuper for ListEntry<'_> {
+    type EntrySink<'b> = ListEntry<'b>;
+}
+
+impl<'a> DirectorySink<'a> for ListEntry<'a> {
+    fn create_entry<'b>(&'b mut self, name: NixString) -> Self::EntrySink<'b>
+    where
+        'a: 'b,
+    {
+        ListEntry {
+            out: &mut *self.out,
+            path: self.path.join(OsStr::from_bytes(&name.0)),
+        }
+    }
+}
+
+impl std::io::Write for ListFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FileSink for ListFile<'_> {
+    fn set_executable(&mut self, executable: bool) {
+        if executable {
+            *self.0 = NarNodeType::Executable;
+        }
+    }
+
+    fn add_contents(&mut self, _contents: &[u8]) {}
+}
+
+// Where `to_tar` writes the archive. Headers are written from `EntrySink` methods, which
+// can't fail, so the first error is kept and returned once the Nar has been read.
+struct TarOut<'a> {
+    write: &'a mut (dyn Write + 'a),
+    error: &'a mut Option<io::Error>,
+}
+
+impl TarOut<'_> {
+    fn reborrow(&mut self) -> TarOut<'_> {
+        TarOut {
+            write: &mut *self.write,
+            error: &mut *self.error,
+        }
+    }
+
+    fn entry(&mut self, path: &Path, kind: u8, mode: u32, size: u64, link: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = if path.as_os_str().is_empty() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only a Nar with a directory at its root can be converted to tar",
+            ))
+        } else {
+            let mut name = path.as_os_str().as_bytes().to_vec();
+            if kind == TAR_DIRECTORY {
+                name.push(b'/');
+            }
+            write_tar_entry(self.write, &name, kind, mode, size, link)
+        };
+        if let Err(e) = result {
+            *self.error = Some(e);
+        }
+    }
+}
+
+// An `EntrySink` that writes a tar header for every entry. The root directory itself
+// isn't written, so the archive's paths are relative to it.
+struct TarEntry<'a> {
+    out: TarOut<'a>,
+    path: PathBuf,
+}
+
+// Writes a file's contents to the archive, padding them to a whole block at the end.
+struct TarFile<'a> {
+    out: TarOut<'a>,
+    path: PathBuf,
+    executable: bool,
+    len: u64,
+    remaining: u64,
+}
+
+const TAR_REGULAR: u8 = b'0';
+const TAR_SYMLINK: u8 = b'2';
+const TAR_DIRECTORY: u8 = b'5';
+const TAR_PAX: u8 = b'x';
+
+impl<'a> EntrySink<'a> for TarEntry<'a> {
+    type DirectorySink = TarEntry<'a>;
+    type FileSink = TarFile<'a>;
+
+    fn become_directory(mut self) -> Self::DirectorySink {
+        if !self.path.as_os_str().is_empty() {
+            self.out.entry(&self.path, TAR_DIRECTORY, 0o755, 0, b"");
+        }
+        self
+    }
+
+    fn become_file(self) -> Self::FileSink {
+        TarFile {
+            out: self.out,
+            path: self.path,
+            executable: false,
+            len: 0,
+            remaining: 0,
+        }
+    }
+
+    fn become_symlink(mut self, target: NixString) {
+        self.out.entry(&self.path, TAR_SYMLINK, 0o777, 0, &target.0);
+    }
+}
+
+impl DirectorySinkSuper for TarEntry<'_> {
+    type EntrySink<'b> = TarEntry<'b>;
+}
+
+impl<'a> DirectorySink<'a> for TarEntry<'a> {
+    fn create_entry<'b>(&'b mut self, name: NixString) -> Self::EntrySink<'b>
+    where
+        'a: 'b,
+    {
+        TarEntry {
+            out: self.out.reborrow(),
+            path: self.path.join(OsStr::from_bytes(&name.0)),
+        }
+    }
+}
+
+impl Write for TarFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.out.error.is_some() {
+            return Ok(buf.len());
+        }
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file contents are longer than declared",
+            ));
+        }
+        self.out.write.write_all(buf)?;
+        self.remaining -= buf.len() as u64;
+        if self.remaining == 0 {
+            write_tar_padding(self.out.write, self.len)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.write.flush()
+    }
+}
+
+impl FileSink for TarFile<'_> {
+    fn set_executable(&mut self, executable: bool) {
+        self.executable = executable;
+    }
+
+    fn add_contents(&mut self, contents: &[u8]) {
+        if let Err(e) = self.write_all(contents) {
+            *self.out.error = Some(e);
+        }
+    }
+
+    fn start_contents(&mut self, len: u64) {
+        let mode = if self.executable { 0o755 } else { 0o644 };
+        self.out.entry(&self.path, TAR_REGULAR, mode, len, b"");
+        self.len = len;
+        self.remaining = len;
+    }
+}
+
+// Write a ustar header, preceded by a pax extended header for anything that doesn't fit
+// in it.
+fn write_tar_entry(
+    write: &mut dyn Write,
+    name: &[u8],
+    kind: u8,
+    mode: u32,
+    size: u64,
+    link: &[u8],
+) -> io::Result<()> {
+    let mut pax = Vec::new();
+    if name.len() > 100 {
+        pax_record(&mut pax, "path", name);
+    }
+    if link.len() > 100 {
+        pax_record(&mut pax, "linkpath", link);
+    }
+    if size > TAR_MAX_SIZE {
+        pax_record(&mut pax, "size", size.to_string().as_bytes());
+    }
+    if !pax.is_empty() {
+        write.write_all(&tar_header(
+            b"././@PaxHeader",
+            TAR_PAX,
+            0o644,
+            pax.len() as u64,
+            b"",
+        ))?;
+        write.write_all(&pax)?;
+        write_tar_padding(write, pax.len() as u64)?;
+    }
+    write.write_all(&tar_header(name, kind, mode, size.min(TAR_MAX_SIZE), link))
+}
+
+// The largest size that fits in the 11 octal digits of a ustar header.
+const TAR_MAX_SIZE: u64 = 0o77777777777;
+
+fn tar_header(name: &[u8], kind: u8, mode: u32, size: u64, link: &[u8]) -> [u8; 512] {
+    fn octal(field: &mut [u8], value: u64) {
+        let digits = field.len() - 1;
+        field[..digits].copy_from_slice(format!("{value:0digits$o}").as_bytes());
+    }
+    fn truncated(field: &mut [u8], value: &[u8]) {
+        let len = value.len().min(field.len());
+        field[..len].copy_from_slice(&value[..len]);
+    }
+
+    let mut header = [0; 512];
+    truncated(&mut header[0..100], name);
+    octal(&mut header[100..108], mode.into());
+    octal(&mut header[108..116], 0); // uid
+    octal(&mut header[116..124], 0); // gid
+    octal(&mut header[124..136], size);
+    // The modification time that nix gives everything in the store.
+    octal(&mut header[136..148], 1);
+    header[156] = kind;
+    truncated(&mut header[157..257], link);
+    header[257..265].copy_from_slice(b"ustar\x0000");
+
+    // The checksum is computed with its own field filled with spaces, and is written as
+    // six digits, a NUL and a space.
+    header[148..156].fill(b' ');
+    let checksum = header.iter().map(|&b| u64::from(b)).sum();
+    octal(&mut header[148..155], checksum);
+    header
+}
+
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // The record starts with its own length in decimal, including the length itself.
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    out.extend_from_slice(format!("{len} {key}=").as_bytes());
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+fn write_tar_padding(write: &mut dyn Write, len: u64) -> io::Result<()> {
+    let padding = (512 - len % 512) % 512;
+    write.write_all(&[0; 512][..padding as usize])
+}
+
+fn invalid_tar(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Read the entries of a tar archive into the root directory of a Nar.
+fn read_tar(tar: &mut impl Read, root: &mut Vec<NarDirectoryEntry>) -> io::Result<()> {
+    // Names and sizes from pax or GNU headers, which apply to the next entry.
+    let mut long_name = None;
+    let mut long_link = None;
+    let mut pax_size = None;
+    loop {
+        let mut header = [0; 512];
+        tar.read_exact(&mut header)?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        let checksum: u64 = header[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&header[156..])
+            .map(|&b| u64::from(b))
+            .sum();
+        if parse_octal(&header[148..156])? != checksum {
+            return Err(invalid_tar("bad checksum in tar header".to_owned()));
+        }
+
+        let size = pax_size
+            .take()
+            .map_or_else(|| parse_octal(&header[124..136]), Ok)?;
+        let mut data = Vec::new();
+        tar.by_ref().take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let padding = (512 - size % 512) % 512;
+        tar.read_exact(&mut [0; 512][..padding as usize])?;
+
+        let kind = header[156];
+        match kind {
+            TAR_PAX => {
+                for (key, value) in pax_records(&data)? {
+                    match key {
+                        b"path" => long_name = Some(value.to_vec()),
+                        b"linkpath" => long_link = Some(value.to_vec()),
+                        b"size" => pax_size = Some(parse_decimal(value)?),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            // GNU long names, NUL-terminated.
+            b'L' => {
+                long_name = Some(until_nul(&data).to_vec());
+                continue;
+            }
+            b'K' => {
+                long_link = Some(until_nul(&data).to_vec());
+                continue;
+            }
+            // Global pax headers.
+            b'g' => continue,
+            _ => {}
+        }
+
+        let name = long_name.take().unwrap_or_else(|| {
+            let name = until_nul(&header[0..100]);
+            let prefix = until_nul(&header[345..500]);
+            if header[257..263] == *b"ustar\0" && !prefix.is_empty() {
+                [prefix, b"/", name].concat()
+            } else {
+                name.to_vec()
+            }
+        });
+        let link = long_link
+            .take()
+            .unwrap_or_else(|| until_nul(&header[157..257]).to_vec());
+        let node = match kind {
+            TAR_REGULAR | 0 | b'7' => Nar::Contents(NarFile {
+                contents: NixString::from(data),
+                executable: parse_octal(&header[100..108])? & 0o100 != 0,
+            }),
+            TAR_DIRECTORY => Nar::Directory(Vec::new()),
+            TAR_SYMLINK => Nar::Target(NixString::from(link)),
+            _ => {
+                let kind = match kind {
+                    b'1' => "hard link".to_owned(),
+                    b'3' => "character device".to_owned(),
+                    b'4' => "block device".to_owned(),
+                    b'6' => "fifo".to_owned(),
+                    _ => format!("entry of type {:?}", char::from(kind)),
+                };
+                return Err(invalid_tar(format!(
+                    "unsupported tar {kind} at {}",
+                    String::from_utf8_lossy(&name)
+                )));
+            }
+        };
+        insert_tar_entry(root, &name, node)?;
+    }
+}
+
+// Put `node` at `name` (relative to the root), creating any missing parent directories.
+fn insert_tar_entry(
+    mut dir: &mut Vec<NarDirectoryEntry>,
+    name: &[u8],
+    node: Nar,
+) -> io::Result<()> {
+    let mut components: Vec<_> = name
+        .split(|&b| b == b'/')
+        .filter(|c| !c.is_empty() && *c != b".")
+        .collect();
+    if components.contains(&&b".."[..]) {
+        return Err(invalid_tar(format!(
+            "tar entry {} is outside the archive",
+            String::from_utf8_lossy(name)
+        )));
+    }
+    let Some(last) = components.pop() else {
+        // The root itself, which can only be a directory.
+        return match node {
+            Nar::Directory(_) => Ok(()),
+            _ => Err(invalid_tar(
+                "tar entry at the root is not a directory".to_owned(),
+            )),
+        };
+    };
+
+    for component in components {
+        let i = match dir.iter().position(|e| *e.name.0 == *component) {
+            Some(i) => i,
+            None => {
+                dir.push(NarDirectoryEntry {
+                    name: NixString::from(component.to_vec()),
+                    node: Nar::Directory(Vec::new()),
+                });
+                dir.len() - 1
+            }
+        };
+        let Nar::Directory(entries) = &mut dir[i].node else {
+            return Err(invalid_tar(format!(
+                "tar entry {} is inside a non-directory",
+                String::from_utf8_lossy(name)
+            )));
+        };
+        dir = entries;
+    }
+
+    match dir.iter_mut().find(|e| *e.name.0 == *last) {
+        // Listing a directory again doesn't remove what's in it.
+        Some(NarDirectoryEntry {
+            node: Nar::Directory(_),
+            ..
+        }) if matches!(node, Nar::Directory(_)) => {}
+        Some(entry) => entry.node = node,
+        None => dir.push(NarDirectoryEntry {
+            name: NixString::from(last.to_vec()),
+            node,
+        }),
+    }
+    Ok(())
+}
+
+fn until_nul(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+fn parse_octal(field: &[u8]) -> io::Result<u64> {
+    let digits = until_nul(field).trim_ascii();
+    u64::from_str_radix(std::str::from_utf8(digits).unwrap_or("?"), 8)
+        .map_err(|_| invalid_tar(format!("bad number {digits:?} in tar header")))
+}
+
+fn parse_decimal(value: &[u8]) -> io::Result<u64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid_tar(format!("bad number {value:?} in pax header")))
+}
+
+// Split pax extended header data into its `key=value` records.
+fn pax_records(mut data: &[u8]) -> io::Result<Vec<(&[u8], &[u8])>> {
+    let bad = || invalid_tar("bad pax header".to_owned());
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let space = data.iter().position(|&b| b == b' ').ok_or_else(bad)?;
+        let len = parse_decimal(&data[..space])? as usize;
+        if len <= space + 1 || len > data.len() || data[len - 1] != b'\n' {
+            return Err(bad());
+        }
+        let record = &data[space + 1..len - 1];
+        let eq = record.iter().position(|&b| b == b'=').ok_or_else(bad)?;
+        records.push((&record[..eq], &record[eq + 1..]));
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
 trait SerializeTupleExt: SerializeTuple {
     fn serialize_buf(&mut self, s: impl AsRef<[u8]>) -> Result<(), Self::Error> {
         self.serialize_element(&ByteBuf::from(s.as_ref()))
@@ -215,7 +755,7 @@ trait StringReader<'a> {
 
     fn expect_tag(&mut self, s: &str) -> Result<(), Self::Error> {
         let tag = self.expect_string()?;
-        if tag.0 != s.as_bytes() {
+        if *tag.0 != *s.as_bytes() {
             Err(serde::de::Error::custom(format!(
                 "got {tag:?} instead of `{s}`"
             )))
@@ -224,13 +764,15 @@ trait StringReader<'a> {
         }
     }
 
-    // A "streaming" version of `expect_string` that might be optimized for long strings.
+    // A "streaming" version of `expect_string` for file contents, that might be optimized
+    // for long strings.
     //
     // The default impl doesn't do any streaming, it just reads the string into memory using
     // `expect_string` and then writes it out again.
-    fn write_string(&mut self, mut write: impl std::io::Write) -> Result<(), Self::Error> {
-        write
-            .write_all(&self.expect_string()?.0)
+    fn write_contents(&mut self, file: &mut impl FileSink) -> Result<(), Self::Error> {
+        let contents = self.expect_string()?;
+        file.start_contents(contents.0.len() as u64);
+        file.write_all(&contents.0)
             .map_err(|e| serde::de::Error::custom(format!("io error: {e}")))
     }
 }
@@ -252,29 +794,30 @@ fn read_entry<'v, 's, A: StringReader<'v>, S: EntrySink<'s> + 's>(
     seq.expect_tag("(")?;
     seq.expect_tag("type")?;
     let ty = seq.expect_string()?;
-    match ty.0.as_slice() {
+    match &*ty.0 {
         b"regular" => {
             let mut file = sink.become_file();
             // This probably doesn't happen, but the nix source allows multiple settings of "executable"
             let mut tag = seq.expect_string()?;
-            while tag.0 == b"executable" {
+            while *tag.0 == *b"executable" {
                 // Nix expects an empty string
                 seq.expect_tag("")?;
                 file.set_executable(true);
                 tag = seq.expect_string()?
             }
 
-            if tag.0 == "contents" {
-                seq.write_string(file)?;
+            if *tag.0 == *b"contents" {
+                seq.write_contents(&mut file)?;
                 seq.expect_tag(")")?;
-                Ok(())
-            } else if tag.0 == ")" {
-                Ok(())
+            } else if *tag.0 == *b")" {
+                file.start_contents(0);
             } else {
-                Err(serde::de::Error::custom(format!(
+                return Err(serde::de::Error::custom(format!(
                     "expected contents, got {tag:?}"
-                )))
+                )));
             }
+            file.finish()
+                .map_err(|e| serde::de::Error::custom(format!("io error: {e}")))
         }
         b"symlink" => {
             seq.expect_tag("target")?;
@@ -287,9 +830,9 @@ fn read_entry<'v, 's, A: StringReader<'v>, S: EntrySink<'s> + 's>(
             let mut dir = sink.become_directory();
             loop {
                 let tag = seq.expect_string()?;
-                if tag.0 == ")" {
+                if *tag.0 == *b")" {
                     break Ok(());
-                } else if tag.0 == "entry" {
+                } else if *tag.0 == *b"entry" {
                     seq.expect_tag("(")?;
                     seq.expect_tag("name")?;
                     let name = seq.expect_string()?;
@@ -317,22 +860,23 @@ impl<'v> StringReader<'v> for NixDeserializer<'v> {
         NixString::deserialize(self)
     }
 
-    fn write_string(
-        &mut self,
-        mut write: impl std::io::Write,
-    ) -> Result<(), crate::serialize::Error> {
+    fn write_contents(&mut self, file: &mut impl FileSink) -> Result<(), crate::serialize::Error> {
         let len = self.read_u64()? as usize;
+        file.start_contents(len as u64);
         let mut buf = [0; 4096];
         let mut remaining = len;
         while remaining > 0 {
             let max_len = buf.len().min(remaining);
             let written = self.read.read(&mut buf[0..max_len])?;
-            write.write_all(&buf[0..written])?;
+            if written == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            file.write_all(&buf[0..written])?;
 
             remaining -= written;
         }
 
-        if len % 8 > 0 {
+        if !len.is_multiple_of(8) {
             let padding = 8 - len % 8;
             self.read.read_exact(&mut buf[..padding])?;
         }
@@ -353,12 +897,96 @@ pub fn stream<R: std::io::Read, W: std::io::Write>(
     write: W,
 ) -> Result<(), crate::serialize::Error> {
     let mut tee = Tee::new(read, write);
-    let mut de = NixDeserializer { read: &mut tee };
+    let mut de = NixDeserializer::new(&mut tee);
     de.expect_tag("nix-archive-1")?;
     read_entry(&mut de, &mut Null)?;
     Ok(())
 }
 
+/// List the entries in a Nar, in the order they appear, with their paths relative to
+/// the root of the Nar. The root itself is listed first, with an empty path.
+///
+/// Unlike deserializing a [`Nar`], this doesn't keep file contents in memory: they are
+/// read and skipped in small chunks, using their declared lengths.
+pub fn list(
+    r: &mut impl std::io::Read,
+) -> Result<Vec<(PathBuf, NarNodeType)>, crate::serialize::Error> {
+    let mut de = NixDeserializer::new(r);
+    de.expect_tag("nix-archive-1")?;
+    let mut out = Vec::new();
+    let root = ListEntry {
+        out: &mut out,
+        path: PathBuf::new(),
+    };
+    read_entry(&mut de, root)?;
+    Ok(out)
+}
+
+/// Convert a Nar to a tar archive, for use with tools that don't know about Nars.
+///
+/// The archive has an entry for everything below the root directory of the Nar (which
+/// must be a directory), in the order they appear in the Nar. Executable files get mode
+/// 0755 and other files 0644; everything is owned by root, with the modification time
+/// that nix uses in the store. Like [`list`], this streams file contents rather than
+/// keeping them in memory.
+pub fn to_tar(
+    nar: &mut impl std::io::Read,
+    tar: &mut impl Write,
+) -> Result<(), crate::serialize::Error> {
+    let mut de = NixDeserializer::new(nar);
+    de.expect_tag("nix-archive-1")?;
+    let mut error = None;
+    let root = TarEntry {
+        out: TarOut {
+            write: tar,
+            error: &mut error,
+        },
+        path: PathBuf::new(),
+    };
+    read_entry(&mut de, root)?;
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+    // The end of the archive is marked by two empty blocks.
+    tar.write_all(&[0; 1024])?;
+    Ok(())
+}
+
+/// Convert a tar archive to a Nar, for example to add it to the store with
+/// `AddToStoreNar`.
+///
+/// The root of the Nar is a directory containing the archive's entries, which may be
+/// regular files, directories or symlinks. Files are executable if their owner may
+/// execute them; other permissions, owners and times are dropped. Directories that only
+/// appear as the parents of other entries are created, and an entry that appears twice
+/// replaces the earlier one, as when extracting the archive.
+///
+/// Unlike [`to_tar`], this builds the whole [`Nar`] in memory, because its entries have
+/// to be sorted by name.
+pub fn from_tar(
+    tar: &mut impl std::io::Read,
+    nar: &mut impl Write,
+) -> Result<(), crate::serialize::Error> {
+    let mut root = Vec::new();
+    read_tar(tar, &mut root)?;
+    let mut root = Nar::Directory(root);
+    root.sort();
+    crate::to_writer(nar, &root)
+}
+
+/// Stream a Nar from a reader to a writer, hashing it on the way.
+///
+/// Returns the digest and size of the Nar, as nix records them in a path's info.
+pub fn stream_hashing<R: std::io::Read, W: std::io::Write>(
+    read: R,
+    write: W,
+    hasher: impl Hasher,
+) -> Result<(Vec<u8>, u64), crate::serialize::Error> {
+    let mut write = HashingWriter::new(write, hasher);
+    stream(read, &mut write)?;
+    Ok(write.finish())
+}
+
 impl<'de> Deserialize<'de> for Nar {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -399,7 +1027,7 @@ impl Serialize for Nar {
 
 struct Untagged<T>(T);
 
-impl<'a> Serialize for Untagged<&'a Nar> {
+impl Serialize for Untagged<&Nar> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -442,3 +1070,295 @@ impl<'a> Serialize for Untagged<&'a Nar> {
         tup.end()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use super::*;
+
+    fn file(contents: &str, executable: bool) -> Nar {
+        Nar::Contents(NarFile {
+            contents: NixString::from(contents.as_bytes().to_vec()),
+            executable,
+        })
+    }
+
+    fn entry(name: &str, node: Nar) -> NarDirectoryEntry {
+        NarDirectoryEntry {
+            name: NixString::from(name.as_bytes().to_vec()),
+            node,
+        }
+    }
+
+    #[test]
+    fn list_nested() {
+        let nar = Nar::Directory(vec![
+            entry(
+                "bin",
+                Nar::Directory(vec![
+                    entry("hello", file("#!/bin/sh\necho hello\n", true)),
+                    entry("hi", Nar::Target(NixString::from(b"hello".to_vec()))),
+                ]),
+            ),
+            entry(
+                "share",
+                Nar::Directory(vec![entry(
+                    "doc",
+                    Nar::Directory(vec![entry("README", file(&"x".repeat(10_000), false))]),
+                )]),
+            ),
+        ]);
+        let bytes = crate::to_vec(&nar).unwrap();
+
+        let mut read = &bytes[..];
+        let listed = list(&mut read).unwrap();
+        assert!(read.is_empty());
+        let expected = [
+            ("", NarNodeType::Directory),
+            ("bin", NarNodeType::Directory),
+            ("bin/hello", NarNodeType::Executable),
+            ("bin/hi", NarNodeType::Symlink),
+            ("share", NarNodeType::Directory),
+            ("share/doc", NarNodeType::Directory),
+            ("share/doc/README", NarNodeType::Regular),
+        ]
+        .map(|(path, ty)| (PathBuf::from(path), ty));
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn truncated_contents() {
+        // The file says it has 100 bytes, but the NAR ends after 3 of them.
+        let bytes = crate::to_vec(&file(&"x".repeat(100), false)).unwrap();
+        let truncated = &bytes[..bytes.len() - 104 + 3];
+
+        assert!(list(&mut &truncated[..]).is_err());
+        assert!(to_tar(&mut &truncated[..], &mut Vec::new()).is_err());
+        #[cfg(feature = "hash-sha2")]
+        {
+            let hasher = crate::hash::Sha256::default();
+            assert!(stream_hashing(truncated, std::io::sink(), hasher).is_err());
+        }
+    }
+
+    #[test]
+    fn to_tar_nested() {
+        let long_name = "a".repeat(150);
+        let nar = Nar::Directory(vec![
+            entry(
+                "bin",
+                Nar::Directory(vec![
+                    entry("hello", file("#!/bin/sh\necho hello\n", true)),
+                    entry("hi", Nar::Target(NixString::from(b"hello".to_vec()))),
+                ]),
+            ),
+            entry(
+                "share",
+                Nar::Directory(vec![entry(
+                    "doc",
+                    Nar::Directory(vec![
+                        entry("README", file(&"x".repeat(10_000), false)),
+                        entry(&long_name, file("long", false)),
+                        entry("empty", file("", false)),
+                    ]),
+                )]),
+            ),
+        ]);
+        let bytes = crate::to_vec(&nar).unwrap();
+        let mut tar = Vec::new();
+        to_tar(&mut &bytes[..], &mut tar).unwrap();
+        let mut nar_again = Vec::new();
+        from_tar(&mut &tar[..], &mut nar_again).unwrap();
+        assert_eq!(nar_again, bytes);
+
+        let mut archive = tar::Archive::new(&tar[..]);
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let mut e = e.unwrap();
+                let header = e.header();
+                let path = e.path().unwrap().into_owned();
+                let ty = header.entry_type();
+                let mode = header.mode().unwrap();
+                let link = e.link_name().unwrap().map(|l| l.into_owned());
+                let mut contents = Vec::new();
+                std::io::Read::read_to_end(&mut e, &mut contents).unwrap();
+                (path, ty, mode, link, contents)
+            })
+            .collect();
+
+        use tar::EntryType::*;
+        let long_path = format!("share/doc/{long_name}");
+        let expected = [
+            ("bin", Directory, 0o755, None, ""),
+            ("bin/hello", Regular, 0o755, None, "#!/bin/sh\necho hello\n"),
+            ("bin/hi", Symlink, 0o777, Some("hello"), ""),
+            ("share", Directory, 0o755, None, ""),
+            ("share/doc", Directory, 0o755, None, ""),
+            (
+                "share/doc/README",
+                Regular,
+                0o644,
+                None,
+                &"x".repeat(10_000),
+            ),
+            (&long_path, Regular, 0o644, None, "long"),
+            ("share/doc/empty", Regular, 0o644, None, ""),
+        ]
+        .map(|(path, ty, mode, link, contents)| {
+            (
+                PathBuf::from(path),
+                ty,
+                mode,
+                link.map(PathBuf::from),
+                contents.as_bytes().to_vec(),
+            )
+        });
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn to_tar_file_root() {
+        let bytes = crate::to_vec(&file("hello", false)).unwrap();
+        assert!(to_tar(&mut &bytes[..], &mut Vec::new()).is_err());
+    }
+
+    fn tar_entry(
+        builder: &mut tar::Builder<Vec<u8>>,
+        path: &str,
+        ty: tar::EntryType,
+        mode: u32,
+        data: &[u8],
+    ) {
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(ty);
+        header.set_mode(mode);
+        header.set_size(data.len() as u64);
+        if ty == tar::EntryType::Symlink {
+            builder.append_link(&mut header, path, "hello").unwrap();
+        } else {
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+    }
+
+    #[test]
+    fn from_tar_roundtrip() {
+        use tar::EntryType::*;
+        let mut builder = tar::Builder::new(Vec::new());
+        // Out of order, with a "./" prefix, and with "share" only implied by its contents.
+        tar_entry(&mut builder, "./share/doc/README", Regular, 0o600, b"docs");
+        tar_entry(&mut builder, "bin/", Directory, 0o700, b"");
+        tar_entry(&mut builder, "bin/hi", Symlink, 0o777, b"");
+        tar_entry(&mut builder, "bin/hello", Regular, 0o744, b"#!/bin/sh\n");
+        let tar = builder.into_inner().unwrap();
+
+        let mut nar = Vec::new();
+        from_tar(&mut &tar[..], &mut nar).unwrap();
+        let expected = Nar::Directory(vec![
+            entry(
+                "bin",
+                Nar::Directory(vec![
+                    entry("hello", file("#!/bin/sh\n", true)),
+                    entry("hi", Nar::Target(NixString::from(b"hello".to_vec()))),
+                ]),
+            ),
+            entry(
+                "share",
+                Nar::Directory(vec![entry(
+                    "doc",
+                    Nar::Directory(vec![entry("README", file("docs", false))]),
+                )]),
+            ),
+        ]);
+        assert_eq!(nar, crate::to_vec(&expected).unwrap());
+
+        // Converting back gives a tar with the same tree, in sorted order.
+        let mut tar = Vec::new();
+        to_tar(&mut &nar[..], &mut tar).unwrap();
+        let mut nar_again = Vec::new();
+        from_tar(&mut &tar[..], &mut nar_again).unwrap();
+        assert_eq!(nar_again, nar);
+        let paths: Vec<_> = tar::Archive::new(&tar[..])
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().into_owned())
+            .collect();
+        let expected = [
+            "bin",
+            "bin/hello",
+            "bin/hi",
+            "share",
+            "share/doc",
+            "share/doc/README",
+        ]
+        .map(PathBuf::from);
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn from_tar_unsupported() {
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "dir/pipe", tar::EntryType::Fifo, 0o644, b"");
+        let tar = builder.into_inner().unwrap();
+
+        let err = from_tar(&mut &tar[..], &mut Vec::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("unsupported tar fifo at dir/pipe"),
+            "{err}"
+        );
+    }
+
+    // A file that can't be stored once its contents are in.
+    struct FailingFile;
+
+    impl Write for FailingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl FileSink for FailingFile {
+        fn set_executable(&mut self, _executable: bool) {}
+
+        fn add_contents(&mut self, _contents: &[u8]) {}
+
+        fn finish(self) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+    }
+
+    impl<'a> EntrySink<'a> for FailingFile {
+        type DirectorySink = &'a mut Vec<NarDirectoryEntry>;
+        type FileSink = FailingFile;
+
+        fn become_directory(self) -> Self::DirectorySink {
+            unreachable!()
+        }
+
+        fn become_file(self) -> Self::FileSink {
+            self
+        }
+
+        fn become_symlink(self, _target: NixString) {}
+    }
+
+    #[test]
+    fn file_sink_finish_error() {
+        let nar = Nar::Contents(NarFile {
+            contents: NixString::from(b"hello".to_vec()),
+            executable: false,
+        });
+        let bytes = crate::to_vec(&nar).unwrap();
+        let mut read = &bytes[..];
+        let mut de = NixDeserializer::new(&mut read);
+        de.expect_tag("nix-archive-1").unwrap();
+        let err = read_entry(&mut de, FailingFile).unwrap_err();
+        assert!(err.to_string().contains("disk full"), "{err}");
+    }
+}
@@ -0,0 +1,122 @@
+//! Nix's flavor of base-32.
+//!
+//! Nix uses a non-standard base-32 encoding for hashes (including the hash part of store
+//! paths). The alphabet omits `e`, `o`, `t` and `u`, and the bits are taken starting from
+//! the *end* of the byte string, so it isn't compatible with RFC 4648.
+
+/// The characters of nix's base-32 alphabet, in order.
+pub const ALPHABET: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("invalid base-32 character {0:?}")]
+    InvalidChar(char),
+    #[error("base-32 string has non-zero trailing bits")]
+    NonZeroPadding,
+}
+
+/// The length of the base-32 encoding of `len` bytes.
+pub fn encoded_len(len: usize) -> usize {
+    if len == 0 {
+        0
+    } else {
+        (len * 8 - 1) / 5 + 1
+    }
+}
+
+/// The number of bytes encoded by a base-32 string of length `len`.
+pub fn decoded_len(len: usize) -> usize {
+    len * 5 / 8
+}
+
+/// Encode bytes in nix's base-32.
+pub fn encode(bytes: &[u8]) -> String {
+    (0..encoded_len(bytes.len()))
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let i = b / 8;
+            let j = b % 8;
+            // bits from the lower byte
+            let v1 = bytes[i] >> j;
+            // bits from the upper byte
+            let v2 = if i + 1 >= bytes.len() {
+                0
+            } else {
+                bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0)
+            };
+            ALPHABET[((v1 | v2) & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// Decode a string in nix's base-32.
+pub fn decode(s: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut bytes = vec![0u8; decoded_len(s.len())];
+
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or(DecodeError::InvalidChar(c as char))? as u16;
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        let shifted = digit << j;
+
+        if i < bytes.len() {
+            bytes[i] |= shifted as u8;
+        } else if shifted != 0 {
+            return Err(DecodeError::NonZeroPadding);
+        }
+        let carry = (shifted >> 8) as u8;
+        if i + 1 < bytes.len() {
+            bytes[i + 1] |= carry;
+        } else if carry != 0 {
+            return Err(DecodeError::NonZeroPadding);
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sha256_of_empty_string() {
+        let digest = hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        let encoded = "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73";
+        assert_eq!(encode(&digest), encoded);
+        assert_eq!(decode(encoded).unwrap(), digest);
+    }
+
+    #[test]
+    fn store_path_hash() {
+        let hash = "n5wkd9frr45pa74if5gpz9j7mifg27fh";
+        let bytes = decode(hash).unwrap();
+        assert_eq!(bytes.len(), 20);
+        assert_eq!(encode(&bytes), hash);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            decode("n5wkd9frr45pa74if5gpz9j7mifg27fe"),
+            Err(DecodeError::InvalidChar('e'))
+        );
+        // 52 characters hold 260 bits, so the top 4 bits of the first character must be zero.
+        assert_eq!(
+            decode("zmdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"),
+            Err(DecodeError::NonZeroPadding)
+        );
+    }
+}
@@ -13,6 +13,11 @@
 //!   (Nix does this manually for each struct)
 //! - sequences (like `Vec`s) are serialized as a length followed by the concatenation of the
 //!   elements (Nix has functions like `readStrings` for this).
+//! - `Option`s are serialized as an int flag (0 or 1), followed by the value if there is
+//!   one (Nix does this by hand, for example in the reply to `QueryPathInfo`). So there's
+//!   no need for a special wrapper type: an `Option<T>` field is all it takes. Optional store
+//!   paths are the exception, since they're sent as possibly-empty strings instead; see
+//!   [`OptionalStorePath`](crate::OptionalStorePath).
 //!
 //! So for example, the struct
 //! ```ignore
@@ -33,25 +38,54 @@
 //! enums are built on string-valued tags (whereas the nix protocol wants integer tags).
 //! Instead, we have a separate `tagged_serde` macro for transforming enums into tuples.
 
-use std::io::{Read, Write};
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+};
 
 use serde::{de, ser, Serialize};
 
+use crate::{DaemonVersion, PROTOCOL_VERSION};
+
+/// A reader that copies everything it reads into a writer.
 pub struct Tee<R, W> {
     read: R,
     write: W,
+    max_capture: usize,
+    captured: usize,
+    complete: bool,
 }
 
 impl<R: Read, W: Write> Tee<R, W> {
     pub fn new(read: R, write: W) -> Self {
-        Tee { read, write }
+        Tee {
+            read,
+            write,
+            max_capture: usize::MAX,
+            captured: 0,
+            complete: true,
+        }
+    }
+
+    /// Only copy the first `max` bytes; after that, bytes are read without being copied.
+    pub fn with_max_capture(mut self, max: usize) -> Self {
+        self.max_capture = max;
+        self
+    }
+
+    /// Whether everything read so far was copied.
+    pub fn is_complete(&self) -> bool {
+        self.complete
     }
 }
 
 impl<R: Read, W: Write> Read for Tee<R, W> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let n = self.read.read(buf)?;
-        self.write.write_all(&buf[0..n])?;
+        let keep = n.min(self.max_capture - self.captured);
+        self.write.write_all(&buf[0..keep])?;
+        self.captured += keep;
+        self.complete &= keep == n;
         Ok(n)
     }
 }
@@ -64,6 +98,51 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("Custom {0}")]
     Custom(String),
+    #[error("in field `{path}`: {source}")]
+    Field { path: String, source: Box<Error> },
+    #[error("sequence of length {len} is longer than the maximum of {max}")]
+    SetTooLarge { len: u64, max: usize },
+    #[error("stream ended after {got} of the 8 bytes of an integer")]
+    Truncated { got: usize },
+    /// A store path that was rejected by [`NixDeserializer::validate_store_paths`].
+    #[error("invalid store path {path:?}: {reason}")]
+    InvalidStorePath { path: String, reason: &'static str },
+    /// A store path that appeared twice in a [`StorePathSet`](crate::StorePathSet) read
+    /// by a [`NixDeserializer::reject_duplicates`] deserializer.
+    #[error("duplicate store path {path:?} in set")]
+    DuplicateInSet { path: String },
+    /// A store path that was longer than [`MAX_STORE_PATH_LEN`], when store paths are
+    /// being checked.
+    #[error("store path of length {len} is longer than the maximum of {MAX_STORE_PATH_LEN}")]
+    StorePathTooLong { len: u64 },
+}
+
+impl Error {
+    /// The I/O error that caused this one, if any.
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Field { source, .. } => source.io_error(),
+            _ => None,
+        }
+    }
+
+    /// Record that this error happened while deserializing the struct field `name`.
+    ///
+    /// Nested fields are joined with dots, so that the path reads from the outermost
+    /// struct inwards.
+    fn in_field(self, name: &str) -> Error {
+        match self {
+            Error::Field { path, source } => Error::Field {
+                path: format!("{name}.{path}"),
+                source,
+            },
+            e => Error::Field {
+                path: name.to_owned(),
+                source: Box::new(e),
+            },
+        }
+    }
 }
 
 impl de::Error for Error {
@@ -87,22 +166,46 @@ impl ser::Error for Error {
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub trait NixReadExt {
-    fn read_nix<'de, 'a: 'de, D: serde::Deserialize<'de>>(&'a mut self) -> Result<D>;
+    fn read_nix<'de, 'a: 'de, D: serde::Deserialize<'de>>(&'a mut self) -> Result<D> {
+        self.read_nix_versioned(PROTOCOL_VERSION)
+    }
+
+    /// Read a value as it is encoded in the given protocol version.
+    fn read_nix_versioned<'de, 'a: 'de, D: serde::Deserialize<'de>>(
+        &'a mut self,
+        version: DaemonVersion,
+    ) -> Result<D>;
 }
 
 impl<R: Read> NixReadExt for R {
-    fn read_nix<'de, 'a: 'de, D: serde::Deserialize<'de>>(&'a mut self) -> Result<D> {
-        D::deserialize(&mut NixDeserializer { read: self })
+    fn read_nix_versioned<'de, 'a: 'de, D: serde::Deserialize<'de>>(
+        &'a mut self,
+        version: DaemonVersion,
+    ) -> Result<D> {
+        D::deserialize(&mut NixDeserializer::with_version(self, version))
     }
 }
 
 pub trait NixWriteExt {
-    fn write_nix<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<()>;
+    fn write_nix<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<()> {
+        self.write_nix_versioned(val, PROTOCOL_VERSION)
+    }
+
+    /// Write a value as it is encoded in the given protocol version.
+    fn write_nix_versioned<T: Serialize + ?Sized>(
+        &mut self,
+        val: &T,
+        version: DaemonVersion,
+    ) -> Result<()>;
 }
 
 impl<W: Write> NixWriteExt for W {
-    fn write_nix<T: Serialize + ?Sized>(&mut self, val: &T) -> Result<()> {
-        val.serialize(&mut NixSerializer { write: self })?;
+    fn write_nix_versioned<T: Serialize + ?Sized>(
+        &mut self,
+        val: &T,
+        version: DaemonVersion,
+    ) -> Result<()> {
+        val.serialize(&mut NixSerializer::with_version(self, version))?;
         Ok(())
     }
 }
@@ -111,16 +214,224 @@ impl<W: Write> NixWriteExt for W {
 // TODO: should decouple the lifetime of the &mut ref from the lifetime of the Read
 pub struct NixDeserializer<'de> {
     pub read: &'de mut dyn Read,
+    /// The protocol version that the data was written in.
+    pub version: DaemonVersion,
+    /// The longest sequence (e.g. set of store paths) that we're willing to read.
+    ///
+    /// Sequence lengths come from the other end of the connection, so this stops us
+    /// from trying to read (and allocate space for) an absurd number of elements.
+    pub max_set_len: usize,
+    /// Whether to check that each [`StorePath`](crate::StorePath) looks like
+    /// `/nix/store/<hash>-<name>` as it is read. Off by default.
+    pub validate_store_paths: bool,
+    /// Whether to fail on a [`StorePathSet`](crate::StorePathSet) that contains the same
+    /// path twice. Off by default, since nix itself doesn't mind.
+    pub reject_duplicates: bool,
+    // The paths in the set that we're in the middle of reading, if we're checking for
+    // duplicates.
+    seen_paths: Option<HashSet<Vec<u8>>>,
+    // The buffer that `deserialize_bytes` reads into, kept to save an allocation for
+    // each string.
+    scratch: Vec<u8>,
 }
 
+/// The default for [`NixDeserializer::max_set_len`].
+///
+/// This is far more than any real closure, but small enough that a corrupted length
+/// is caught early.
+pub const DEFAULT_MAX_SET_LEN: usize = 1 << 24;
+
+/// The longest store path that a [`NixDeserializer`] reads when it is checking store
+/// paths (see [`NixDeserializer::validate_store_paths`] and
+/// [`NixDeserializer::reject_duplicates`]). This is the usual `PATH_MAX`.
+pub const MAX_STORE_PATH_LEN: u64 = 4096;
+
+// We never preallocate space for more than this many sequence elements; if there are
+// really more, the collection grows as they are read.
+const MAX_PREALLOCATED_ELEMENTS: usize = 4096;
+
 /// A serializer for the nix remote protocol.
 pub struct NixSerializer<'se> {
     pub write: &'se mut dyn Write,
+    /// The protocol version to write the data in.
+    pub version: DaemonVersion,
+    /// If set, paths are rewritten as they are written.
+    pub path_rewrite: Option<&'se PathRewrite>,
+    // Whether we're in the middle of writing a path.
+    in_path: bool,
+}
+
+/// Moves paths from one directory to another, like from `/nix/store` to `/gnu/store`.
+///
+/// This applies to the path types ([`StorePath`](crate::StorePath), [`Path`](crate::Path)
+/// and [`DerivedPath`](crate::DerivedPath)), and not to other strings that might happen
+/// to contain a path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathRewrite {
+    pub from: Vec<u8>,
+    pub to: Vec<u8>,
+}
+
+impl PathRewrite {
+    /// The rewrite that undoes this one.
+    pub fn reversed(&self) -> PathRewrite {
+        PathRewrite {
+            from: self.to.clone(),
+            to: self.from.clone(),
+        }
+    }
+
+    /// Rewrite `path`, if it is in the `from` directory.
+    pub fn apply(&self, path: &[u8]) -> Option<Vec<u8>> {
+        let rest = path.strip_prefix(self.from.as_slice())?;
+        (rest.is_empty() || rest.starts_with(b"/")).then(|| [&self.to[..], rest].concat())
+    }
+}
+
+// The names that path types pass to `serialize_newtype_struct`.
+const PATH: &str = "__nix_remote_path";
+const STORE_PATH: &str = "__nix_remote_store_path";
+// The name that `OptionalStorePath` passes to `deserialize_newtype_struct`.
+const OPTIONAL_STORE_PATH: &str = "__nix_remote_optional_store_path";
+// The name that `StorePathSet` passes to `deserialize_newtype_struct`.
+const STORE_PATH_SET: &str = "__nix_remote_store_path_set";
+
+impl<'de> NixDeserializer<'de> {
+    /// A deserializer for the latest protocol version that we support.
+    pub fn new(read: &'de mut dyn Read) -> Self {
+        Self::with_version(read, PROTOCOL_VERSION)
+    }
+
+    pub fn with_version(read: &'de mut dyn Read, version: DaemonVersion) -> Self {
+        Self {
+            read,
+            version,
+            max_set_len: DEFAULT_MAX_SET_LEN,
+            validate_store_paths: false,
+            reject_duplicates: false,
+            seen_paths: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Check store paths as they are read, failing with [`Error::InvalidStorePath`] on
+    /// the first one that isn't valid.
+    ///
+    /// Missing [`OptionalStorePath`](crate::OptionalStorePath)s (empty strings) are
+    /// still allowed. Other path types aren't checked, since they needn't be in the store.
+    pub fn validate_store_paths(mut self, validate: bool) -> Self {
+        self.validate_store_paths = validate;
+        self
+    }
+
+    /// Fail with [`Error::DuplicateInSet`] on the first path that appears twice in a
+    /// [`StorePathSet`](crate::StorePathSet).
+    ///
+    /// Other sequences (including [`StringSet`](crate::StringSet)s) aren't checked.
+    pub fn reject_duplicates(mut self, reject: bool) -> Self {
+        self.reject_duplicates = reject;
+        self
+    }
+}
+
+impl<'se> NixSerializer<'se> {
+    /// A serializer for the latest protocol version that we support.
+    pub fn new(write: &'se mut dyn Write) -> Self {
+        Self::with_version(write, PROTOCOL_VERSION)
+    }
+
+    pub fn with_version(write: &'se mut dyn Write, version: DaemonVersion) -> Self {
+        Self {
+            write,
+            version,
+            path_rewrite: None,
+            in_path: false,
+        }
+    }
+
+    /// Rewrite paths with `rewrite` as they are written.
+    pub fn with_path_rewrite(mut self, rewrite: &'se PathRewrite) -> Self {
+        self.path_rewrite = Some(rewrite);
+        self
+    }
+}
+
+// Fields that only exist in some protocol versions are passed through serde using these
+// (hopefully unique) names, with the minor version (a `u8`, like `DaemonVersion::minor`)
+// smuggled in as the variant index (when serializing) or the tuple length (when
+// deserializing).
+const SINCE_MINOR: &str = "__nix_remote_since_minor";
+const UNTIL_MINOR: &str = "__nix_remote_until_minor";
+
+/// Serialize a struct field that is only on the wire from protocol version `1.MINOR` onwards.
+///
+/// Use this as `#[serde(serialize_with = "serialize::since_minor::<MINOR, _, _>")]`, together
+/// with [`deserialize_since_minor`]. Older protocol versions skip the field entirely.
+pub fn since_minor<const MINOR: u8, T: Serialize, S: ser::Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_variant(SINCE_MINOR, MINOR.into(), "", value)
+}
+
+/// The deserialization counterpart of [`since_minor`].
+///
+/// When the field isn't on the wire, it gets its default value.
+pub fn deserialize_since_minor<'de, const MINOR: u8, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: de::Deserialize<'de> + Default,
+    D: de::Deserializer<'de>,
+{
+    deserializer.deserialize_tuple_struct(SINCE_MINOR, MINOR.into(), VersionedVisitor::default())
+}
+
+/// Serialize a struct field that is only on the wire before protocol version `1.MINOR`.
+///
+/// This is the opposite of [`since_minor`], for fields that newer protocols dropped.
+pub fn until_minor<const MINOR: u8, T: Serialize, S: ser::Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_newtype_variant(UNTIL_MINOR, MINOR.into(), "", value)
+}
+
+/// The deserialization counterpart of [`until_minor`].
+///
+/// When the field isn't on the wire, it gets its default value.
+pub fn deserialize_until_minor<'de, const MINOR: u8, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: de::Deserialize<'de> + Default,
+    D: de::Deserializer<'de>,
+{
+    deserializer.deserialize_tuple_struct(UNTIL_MINOR, MINOR.into(), VersionedVisitor::default())
+}
+
+// Reads a versioned field, which looks like a sequence with zero or one elements.
+struct VersionedVisitor<T>(std::marker::PhantomData<T>);
+
+impl<T> Default for VersionedVisitor<T> {
+    fn default() -> Self {
+        VersionedVisitor(std::marker::PhantomData)
+    }
+}
+
+impl<'de, T: de::Deserialize<'de> + Default> de::Visitor<'de> for VersionedVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a versioned field")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+        Ok(seq.next_element()?.unwrap_or_default())
+    }
 }
 
 struct Seq<'a, 'de: 'a> {
     deserializer: &'a mut NixDeserializer<'de>,
     len: usize,
+    // If we're deserializing a struct, the names of its fields (for error messages).
+    fields: &'static [&'static str],
 }
 
 impl<'a, 'de: 'a> de::SeqAccess<'de> for Seq<'a, 'de> {
@@ -131,18 +442,25 @@ impl<'a, 'de: 'a> de::SeqAccess<'de> for Seq<'a, 'de> {
         T: de::DeserializeSeed<'de>,
     {
         if self.len > 0 {
+            let field = self
+                .fields
+                .len()
+                .checked_sub(self.len)
+                .map(|i| self.fields[i]);
             self.len -= 1;
-            Ok(Some(de::DeserializeSeed::deserialize(
-                seed,
-                &mut *self.deserializer,
-            )?))
+            de::DeserializeSeed::deserialize(seed, &mut *self.deserializer)
+                .map(Some)
+                .map_err(|e| match field {
+                    Some(name) => e.in_field(name),
+                    None => e,
+                })
         } else {
             Ok(None)
         }
     }
 
     fn size_hint(&self) -> Option<usize> {
-        Some(self.len)
+        Some(self.len.min(MAX_PREALLOCATED_ELEMENTS))
     }
 }
 
@@ -163,35 +481,69 @@ impl<'de> NixDeserializer<'de> {
         // TODO(optimization): don't initialize
         let mut buf = vec![0; len];
         self.read.read_exact(&mut buf)?;
+        self.read_padding(len as u64)?;
+        Ok(buf)
+    }
 
-        if len % 8 > 0 {
-            let padding = 8 - len % 8;
+    // Skip the padding after `len` bytes of data.
+    fn read_padding(&mut self, len: u64) -> Result<()> {
+        if !len.is_multiple_of(8) {
+            let padding = (8 - len % 8) as usize;
             let mut pad_buf = [0; 8];
             self.read.read_exact(&mut pad_buf[..padding])?;
         }
+        Ok(())
+    }
 
-        Ok(buf)
+    /// Read a sequence, passing each element to `f` as soon as it is decoded.
+    ///
+    /// Unlike deserializing a `Vec`, this never holds more than one element in memory.
+    pub fn read_seq_with<T: de::DeserializeOwned>(&mut self, mut f: impl FnMut(T)) -> Result<()> {
+        let len = self.read_u64()?;
+        for _ in 0..len {
+            f(T::deserialize(&mut *self)?);
+        }
+        Ok(())
     }
 }
 
 impl<'se> NixSerializer<'se> {
     pub fn write_byte_buf(&mut self, s: &[u8]) -> Result<()> {
-        let len = s.len();
+        // The length is always 64 bits, whatever the size of `usize`.
+        self.write.write_all(&(s.len() as u64).to_le_bytes())?;
+        self.write.write_all(s)?;
+        self.write_padding(s.len() as u64)
+    }
 
+    /// Write a byte buffer of length `len`, copying its contents from `src` instead of
+    /// holding them all in memory.
+    ///
+    /// Fails with an `UnexpectedEof` I/O error if `src` has fewer than `len` bytes, in
+    /// which case the output is truncated partway through the buffer.
+    pub fn write_byte_buf_from(&mut self, len: u64, src: &mut dyn Read) -> Result<()> {
         self.write.write_all(&len.to_le_bytes())?;
-        self.write.write_all(s)?;
+        let copied = std::io::copy(&mut src.take(len), &mut self.write)?;
+        if copied < len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("expected {len} bytes to write, but only got {copied}"),
+            )
+            .into());
+        }
+        self.write_padding(len)
+    }
 
-        if len % 8 > 0 {
-            let padding = 8 - len % 8;
+    fn write_padding(&mut self, len: u64) -> Result<()> {
+        if !len.is_multiple_of(8) {
+            let padding = (8 - len % 8) as usize;
             let pad_buf = [0; 8];
             self.write.write_all(&pad_buf[..padding])?;
         };
-
         Ok(())
     }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut NixDeserializer<'de> {
+impl<'de> de::Deserializer<'de> for &mut NixDeserializer<'de> {
     type Error = Error;
 
     fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
@@ -299,11 +651,24 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut NixDeserializer<'de> {
         Err(Error::WontImplement("String"))
     }
 
+    // Unlike `deserialize_byte_buf`, this doesn't hand over the buffer, so it can reuse
+    // one. Types that keep the data in something other than a `Vec` (like `NixString`)
+    // use this to avoid allocating twice.
     fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
         V: de::Visitor<'de>,
     {
-        self.deserialize_byte_buf(visitor)
+        let len = self.read_u64()?;
+        let mut buf = std::mem::take(&mut self.scratch);
+        buf.clear();
+        let got = (&mut *self.read).take(len).read_to_end(&mut buf)?;
+        if got as u64 != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        self.read_padding(len)?;
+        let value = visitor.visit_bytes(&buf);
+        self.scratch = buf;
+        value
     }
 
     fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Self::Error>
@@ -345,23 +710,70 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut NixDeserializer<'de> {
 
     fn deserialize_newtype_struct<V>(
         self,
-        _name: &'static str,
+        name: &'static str,
         visitor: V,
     ) -> Result<V::Value, Self::Error>
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_newtype_struct(self)
+        if name == STORE_PATH_SET && self.reject_duplicates {
+            let outer = self.seen_paths.replace(HashSet::new());
+            let set = visitor.visit_newtype_struct(&mut *self);
+            self.seen_paths = outer;
+            return set;
+        }
+        let check_duplicate = name == STORE_PATH && self.seen_paths.is_some();
+        let validate =
+            self.validate_store_paths && (name == STORE_PATH || name == OPTIONAL_STORE_PATH);
+        if !check_duplicate && !validate {
+            return visitor.visit_newtype_struct(self);
+        }
+        // The length comes from the wire, so it mustn't decide how much we allocate.
+        let len = self.read_u64()?;
+        if len > MAX_STORE_PATH_LEN {
+            return Err(Error::StorePathTooLong { len });
+        }
+        let mut path = std::mem::take(&mut self.scratch);
+        path.clear();
+        let got = (&mut *self.read).take(len).read_to_end(&mut path)?;
+        if got as u64 != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        self.read_padding(len)?;
+        if validate && (name == STORE_PATH || !path.is_empty()) {
+            crate::StorePath::check(&path).map_err(|reason| Error::InvalidStorePath {
+                path: String::from_utf8_lossy(&path).into_owned(),
+                reason,
+            })?;
+        }
+        if let Some(seen) = self.seen_paths.as_mut().filter(|_| check_duplicate) {
+            if !seen.insert(path.clone()) {
+                return Err(Error::DuplicateInSet {
+                    path: String::from_utf8_lossy(&path).into_owned(),
+                });
+            }
+        }
+        let value = visitor.visit_newtype_struct(de::value::BytesDeserializer::new(&path));
+        self.scratch = path;
+        value
     }
 
     fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
         V: de::Visitor<'de>,
     {
-        let len = self.read_u64()? as usize;
+        let len = self.read_u64()?;
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.max_set_len)
+            .ok_or(Error::SetTooLarge {
+                len,
+                max: self.max_set_len,
+            })?;
         visitor.visit_seq(Seq {
             deserializer: self,
             len,
+            fields: &[],
         })
     }
 
@@ -372,21 +784,32 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut NixDeserializer<'de> {
         visitor.visit_seq(Seq {
             deserializer: self,
             len,
+            fields: &[],
         })
     }
 
     fn deserialize_tuple_struct<V>(
         self,
-        _name: &'static str,
+        name: &'static str,
         len: usize,
         visitor: V,
     ) -> Result<V::Value, Self::Error>
     where
         V: de::Visitor<'de>,
     {
+        if name == SINCE_MINOR || name == UNTIL_MINOR {
+            let present = (self.version.minor as usize >= len) == (name == SINCE_MINOR);
+            return visitor.visit_seq(Seq {
+                deserializer: self,
+                len: present as usize,
+                fields: &[],
+            });
+        }
+
         visitor.visit_seq(Seq {
             deserializer: self,
             len,
+            fields: &[],
         })
     }
 
@@ -406,7 +829,11 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut NixDeserializer<'de> {
     where
         V: de::Visitor<'de>,
     {
-        self.deserialize_tuple(fields.len(), visitor)
+        visitor.visit_seq(Seq {
+            deserializer: self,
+            len: fields.len(),
+            fields,
+        })
     }
 
     fn deserialize_enum<V>(
@@ -440,9 +867,9 @@ impl<'se> ser::SerializeSeq for &mut NixSerializer<'se> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -456,9 +883,9 @@ impl<'se> ser::SerializeTuple for &mut NixSerializer<'se> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -472,9 +899,9 @@ impl<'se> ser::SerializeTupleStruct for &mut NixSerializer<'se> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -488,9 +915,9 @@ impl<'se> ser::SerializeTupleVariant for &mut NixSerializer<'se> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -504,16 +931,16 @@ impl<'se> ser::SerializeMap for &mut NixSerializer<'se> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         key.serialize(&mut **self)
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -527,13 +954,9 @@ impl<'se> ser::SerializeStruct for &mut NixSerializer<'se> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        _name: &'static str,
-        value: &T,
-    ) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, _name: &'static str, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -547,13 +970,9 @@ impl<'se> ser::SerializeStructVariant for &mut NixSerializer<'se> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        _name: &'static str,
-        value: &T,
-    ) -> Result<(), Self::Error>
+    fn serialize_field<T>(&mut self, _name: &'static str, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -635,6 +1054,11 @@ impl<'se> serde::Serializer for &mut NixSerializer<'se> {
     }
 
     fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        if std::mem::take(&mut self.in_path) {
+            if let Some(path) = self.path_rewrite.and_then(|r| r.apply(v)) {
+                return self.write_byte_buf(&path);
+            }
+        }
         self.write_byte_buf(v)
     }
 
@@ -643,9 +1067,9 @@ impl<'se> serde::Serializer for &mut NixSerializer<'se> {
         Ok(())
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         self.write.write_all(&1u64.to_le_bytes())?;
         value.serialize(self)
@@ -668,27 +1092,34 @@ impl<'se> serde::Serializer for &mut NixSerializer<'se> {
         Err(Error::WontImplement("unit variant"))
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(
+    fn serialize_newtype_struct<T>(
         self,
-        _name: &'static str,
+        name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
+        self.in_path = name == PATH || name == STORE_PATH;
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
-        _name: &'static str,
-        _variant_index: u32,
+        name: &'static str,
+        variant_index: u32,
         _variant: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
+        let minor = self.version.minor as u32;
+        if (name == SINCE_MINOR && minor < variant_index)
+            || (name == UNTIL_MINOR && minor >= variant_index)
+        {
+            return Ok(());
+        }
         value.serialize(self)
     }
 
@@ -741,3 +1172,268 @@ impl<'se> serde::Serializer for &mut NixSerializer<'se> {
         Err(Error::WontImplement("struct variant"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::{
+        worker_op::ValidPathInfo, NarHash, NixString, OptionalStorePath, StorePath, StorePathSet,
+        StringSet, ValidPathInfoWithPath,
+    };
+
+    #[test]
+    fn error_names_field() {
+        let info = ValidPathInfoWithPath {
+            path: StorePath(NixString::from(b"/nix/store/abc-foo".to_vec())),
+            info: ValidPathInfo {
+                deriver: OptionalStorePath(None),
+                hash: NarHash {
+                    data: ByteBuf::from(b"sha256:abc".to_vec()),
+                },
+                references: StorePathSet { paths: vec![] },
+                registration_time: 23,
+                nar_size: 42,
+                ultimate: false,
+                sigs: StringSet { paths: vec![] },
+                content_address: NixString::default(),
+            },
+        };
+        let bytes = crate::to_vec(&info).unwrap();
+        let prefix = crate::to_vec(&(
+            &info.path,
+            &info.info.deriver,
+            &info.info.hash,
+            &info.info.references,
+            info.info.registration_time,
+        ))
+        .unwrap();
+
+        // Cut the data off in the middle of `nar_size`.
+        let err = crate::from_bytes::<ValidPathInfoWithPath>(&bytes[..prefix.len() + 4])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`info.nar_size`"), "{err}");
+    }
+
+    #[test]
+    fn set_too_large() {
+        // The error is reported inside the set's `paths` field.
+        fn too_large(e: Error) -> Option<(u64, usize)> {
+            match e {
+                Error::SetTooLarge { len, max } => Some((len, max)),
+                Error::Field { source, .. } => too_large(*source),
+                _ => None,
+            }
+        }
+
+        let bytes = crate::to_vec(&u64::MAX).unwrap();
+        let err = crate::from_bytes::<StorePathSet>(&bytes).unwrap_err();
+        assert_eq!(too_large(err), Some((u64::MAX, DEFAULT_MAX_SET_LEN)));
+
+        let set = StorePathSet {
+            paths: (0..100_000)
+                .map(|i| StorePath(NixString::from(format!("/nix/store/{i}").into_bytes())))
+                .collect(),
+        };
+        let bytes = crate::to_vec(&set).unwrap();
+        assert_eq!(crate::from_bytes::<StorePathSet>(&bytes).unwrap(), set);
+
+        let mut read = &bytes[..];
+        let mut de = NixDeserializer::new(&mut read);
+        de.max_set_len = 1000;
+        let err = StorePathSet::deserialize(&mut de).unwrap_err();
+        assert_eq!(too_large(err), Some((100_000, 1000)));
+    }
+
+    #[test]
+    fn validate_store_paths() {
+        let read_with = |path: &[u8], validate: bool| {
+            let bytes = crate::to_vec(&StorePath(NixString::from(path.to_vec()))).unwrap();
+            let mut read = &bytes[..];
+            StorePath::deserialize(
+                &mut NixDeserializer::new(&mut read).validate_store_paths(validate),
+            )
+        };
+        let good = b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-1.0";
+        let bad = b"/nix/store/abc-foo";
+        for validate in [false, true] {
+            assert_eq!(read_with(good, validate).unwrap().as_ref(), good);
+        }
+        assert_eq!(read_with(bad, false).unwrap().as_ref(), bad);
+        assert!(matches!(
+            read_with(bad, true),
+            Err(Error::InvalidStorePath { path, reason: "missing hash part" })
+                if path == "/nix/store/abc-foo"
+        ));
+
+        // Missing optional paths are fine, but present ones are still checked.
+        let read_optional = |path: &[u8]| {
+            let bytes = crate::to_vec(&NixString::from(path.to_vec())).unwrap();
+            let mut read = &bytes[..];
+            OptionalStorePath::deserialize(
+                &mut NixDeserializer::new(&mut read).validate_store_paths(true),
+            )
+        };
+        assert_eq!(read_optional(b"").unwrap(), OptionalStorePath(None));
+        assert!(read_optional(good).unwrap().0.is_some());
+        assert!(read_optional(bad).is_err());
+
+        // A huge length is refused before anything is allocated for it.
+        let bytes = (1u64 << 46).to_le_bytes();
+        let mut read = &bytes[..];
+        assert!(matches!(
+            StorePath::deserialize(&mut NixDeserializer::new(&mut read).validate_store_paths(true)),
+            Err(Error::StorePathTooLong { len }) if len == 1 << 46
+        ));
+        // And so is a path that ends early.
+        let bytes = crate::to_vec(&StorePath(NixString::from(good.to_vec()))).unwrap();
+        let mut read = &bytes[..20];
+        assert!(StorePath::deserialize(
+            &mut NixDeserializer::new(&mut read).validate_store_paths(true)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn strings() {
+        // Consecutive strings share the deserializer's buffer, and mustn't see each other.
+        let bytes = crate::to_vec(&(
+            NixString::from(b"a longer string".to_vec()),
+            NixString::from(b"short".to_vec()),
+        ))
+        .unwrap();
+        let strings: (NixString, NixString) = crate::from_bytes(&bytes).unwrap();
+        assert_eq!(&*strings.0 .0, b"a longer string");
+        assert_eq!(&*strings.1 .0, b"short");
+
+        // A string that ends early is an error.
+        let err = crate::from_bytes::<NixString>(&bytes[..12]).unwrap_err();
+        assert!(
+            err.io_error()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn reject_duplicates() {
+        let [foo, bar] =
+            ["foo", "bar"].map(|name| StorePath(NixString::from(format!("/nix/store/abc-{name}"))));
+        let mut set = StorePathSet {
+            paths: vec![foo.clone(), bar.clone(), foo.clone(), bar.clone()],
+        };
+        let bytes = crate::to_vec(&set).unwrap();
+        let read_with = |reject: bool| {
+            let mut read = &bytes[..];
+            StorePathSet::deserialize(
+                &mut NixDeserializer::new(&mut read).reject_duplicates(reject),
+            )
+        };
+
+        // Lenient by default, and the repeats can be dropped afterwards.
+        assert_eq!(crate::from_bytes::<StorePathSet>(&bytes).unwrap(), set);
+        assert_eq!(read_with(false).unwrap(), set);
+        set.dedup_sorted();
+        assert_eq!(set.paths, vec![bar, foo]);
+
+        assert!(matches!(
+            read_with(true),
+            Err(Error::DuplicateInSet { path }) if path == "/nix/store/abc-foo"
+        ));
+        let bytes = crate::to_vec(&set).unwrap();
+        let mut read = &bytes[..];
+        let mut strict = NixDeserializer::new(&mut read).reject_duplicates(true);
+        assert_eq!(StorePathSet::deserialize(&mut strict).unwrap(), set);
+
+        // Each set is checked on its own: a path can be in two different sets.
+        let bytes = crate::to_vec(&(set.clone(), set.clone())).unwrap();
+        let mut read = &bytes[..];
+        let mut strict = NixDeserializer::new(&mut read).reject_duplicates(true);
+        assert_eq!(
+            <(StorePathSet, StorePathSet)>::deserialize(&mut strict).unwrap(),
+            (set.clone(), set)
+        );
+
+        // Paths are read with a bounded length, so a huge one is refused, not allocated.
+        let bytes = crate::to_vec(&(1u64, 1u64 << 46)).unwrap();
+        let mut read = &bytes[..];
+        let mut strict = NixDeserializer::new(&mut read).reject_duplicates(true);
+        assert!(matches!(
+            StorePathSet::deserialize(&mut strict),
+            Err(Error::StorePathTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn path_rewrite() {
+        let rewrite = PathRewrite {
+            from: b"/nix/store".to_vec(),
+            to: b"/gnu/store".to_vec(),
+        };
+        assert_eq!(
+            rewrite.apply(b"/nix/store/abc-foo").unwrap(),
+            b"/gnu/store/abc-foo"
+        );
+        assert_eq!(rewrite.apply(b"/nix/store").unwrap(), b"/gnu/store");
+        assert_eq!(rewrite.apply(b"/nix/store-other/abc-foo"), None);
+        assert_eq!(rewrite.apply(b"abc-foo"), None);
+
+        // Paths are rewritten, but strings that just look like paths are not.
+        let value = (
+            StorePath(NixString::from(b"/nix/store/abc-foo".to_vec())),
+            NixString::from(b"/nix/store/abc-foo".to_vec()),
+        );
+        let mut bytes = Vec::new();
+        value
+            .serialize(&mut NixSerializer::new(&mut bytes).with_path_rewrite(&rewrite))
+            .unwrap();
+        assert_eq!(
+            bytes,
+            crate::to_vec(&(
+                NixString::from(b"/gnu/store/abc-foo".to_vec()),
+                NixString::from(b"/nix/store/abc-foo".to_vec()),
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn option_roundtrip() {
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+
+        let bytes = crate::to_vec(&Some(path.clone())).unwrap();
+        assert_eq!(bytes, crate::to_vec(&(1u64, &path)).unwrap());
+        assert_eq!(
+            crate::from_bytes::<Option<StorePath>>(&bytes).unwrap(),
+            Some(path)
+        );
+
+        let bytes = crate::to_vec(&None::<StorePath>).unwrap();
+        assert_eq!(bytes, crate::to_vec(&0u64).unwrap());
+        assert_eq!(
+            crate::from_bytes::<Option<StorePath>>(&bytes).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn tee_max_capture() {
+        let data = vec![7u8; 2 << 20];
+        let mut captured = Vec::new();
+        let mut tee = Tee::new(&data[..], &mut captured).with_max_capture(1 << 20);
+        let mut forwarded = Vec::new();
+        std::io::copy(&mut tee, &mut forwarded).unwrap();
+        assert!(!tee.is_complete());
+        assert_eq!(forwarded, data);
+        assert_eq!(captured.len(), 1 << 20);
+
+        let mut captured = Vec::new();
+        let mut tee = Tee::new(&data[..1000], &mut captured).with_max_capture(1 << 20);
+        std::io::copy(&mut tee, &mut std::io::sink()).unwrap();
+        assert!(tee.is_complete());
+        assert_eq!(captured, &data[..1000]);
+    }
+}
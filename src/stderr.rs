@@ -6,6 +6,7 @@
 //!   a 64-bit opcode followed by the body of the message. The final message has the opcode `Last`.
 //! - the daemon sends the reply to the worker op.
 
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use tagged_serde::TaggedSerde;
@@ -16,11 +17,14 @@ use crate::{NixString, Result};
 ///
 /// On the wire, they are represented as the opcode followed by the body.
 ///
-// STDERR_READ is... interesting. Fortunately, it appears to have been superceded by FramedSource.
-// It is not used in the current version of the nix protocol
-// Read = 0x64617461,
 #[derive(Debug, TaggedSerde, PartialEq, Clone, Eq)]
 pub enum Msg {
+    /// The daemon wants (up to) this many bytes of source data from the client.
+    ///
+    /// The client replies with a byte buffer, out of band. This has been superseded
+    /// by framed sources, but older clients still use it for ops like `AddToStore`.
+    #[tagged_serde = 0x64617461]
+    Read(u64),
     #[tagged_serde = 0x64617416]
     Write(NixString),
     #[tagged_serde = 0x63787470]
@@ -37,6 +41,61 @@ pub enum Msg {
     Last(()),
 }
 
+impl Msg {
+    /// The opcode that this message is sent with.
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Msg::Read(_) => Opcode::Read,
+            Msg::Write(_) => Opcode::Write,
+            Msg::Error(_) => Opcode::Error,
+            Msg::Next(_) => Opcode::Next,
+            Msg::StartActivity(_) => Opcode::StartActivity,
+            Msg::StopActivity(_) => Opcode::StopActivity,
+            Msg::Result(_) => Opcode::Result,
+            Msg::Last(_) => Opcode::Last,
+        }
+    }
+}
+
+/// The opcodes of the stderr messages, as they appear on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Next = 0x6f6c6d67,
+    Read = 0x64617461,
+    Write = 0x64617416,
+    Last = 0x616c7473,
+    Error = 0x63787470,
+    StartActivity = 0x53545254,
+    StopActivity = 0x53544f50,
+    Result = 0x52534c54,
+}
+
+impl Opcode {
+    /// All of the stderr opcodes.
+    pub const ALL: [Opcode; 8] = [
+        Opcode::Next,
+        Opcode::Read,
+        Opcode::Write,
+        Opcode::Last,
+        Opcode::Error,
+        Opcode::StartActivity,
+        Opcode::StopActivity,
+        Opcode::Result,
+    ];
+
+    /// Look up an opcode by its wire value, failing if it isn't one we know.
+    pub fn from_u64(opcode: u64) -> Result<Opcode> {
+        Ok(Opcode::ALL
+            .into_iter()
+            .find(|op| op.as_u64() == opcode)
+            .ok_or_else(|| anyhow!("unknown stderr opcode {opcode:#x}"))?)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self as u64
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct StderrError {
     typ: ByteBuf,
@@ -47,21 +106,205 @@ pub struct StderrError {
     traces: Vec<Trace>,
 }
 
+impl StderrError {
+    /// An error with just a message, like the ones nix sends for a failed operation.
+    pub fn new(message: impl Into<Vec<u8>>) -> Self {
+        StderrError {
+            typ: ByteBuf::from(b"Error".to_vec()),
+            level: 0,
+            name: ByteBuf::from(b"Error".to_vec()),
+            message: ByteBuf::from(message.into()),
+            have_pos: 0,
+            traces: Vec::new(),
+        }
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// How severe the error is, as a nix verbosity level (0 is the most severe).
+    pub fn level(&self) -> u64 {
+        self.level
+    }
+
+    /// The error's traces, outermost first.
+    pub fn traces(&self) -> impl Iterator<Item = &[u8]> {
+        self.traces.iter().map(|t| t.trace.as_slice())
+    }
+}
+
+impl std::fmt::Display for StderrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.message))
+    }
+}
+
+impl std::error::Error for StderrError {}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct StderrStartActivity {
-    act: u64,
-    lvl: u64,
-    typ: u64,
-    s: ByteBuf,
-    fields: LoggerFields,
-    parent: u64,
+    pub act: u64,
+    pub lvl: u64,
+    pub typ: ActivityType,
+    pub s: ByteBuf,
+    pub fields: LoggerFields,
+    /// The id of the enclosing activity, or 0 for a top-level activity.
+    pub parent: u64,
+}
+
+impl StderrStartActivity {
+    /// Interpret the logger fields according to the activity type.
+    pub fn activity(&self) -> Result<Activity> {
+        let fields = &self.fields.fields;
+        let string = |i: usize| match fields.get(i) {
+            Some(LoggerField::String(s)) => Ok(NixString::from_bytes(s)),
+            f => Err(anyhow!(
+                "expected a string field at {i} of {:?}, got {f:?}",
+                self.typ
+            )),
+        };
+        let int = |i: usize| match fields.get(i) {
+            Some(LoggerField::Int(n)) => Ok(*n),
+            f => Err(anyhow!(
+                "expected an int field at {i} of {:?}, got {f:?}",
+                self.typ
+            )),
+        };
+
+        Ok(match self.typ {
+            ActivityType::CopyPath => Activity::CopyPath {
+                path: string(0)?,
+                from: string(1)?,
+                to: string(2)?,
+            },
+            ActivityType::FileTransfer => Activity::FileTransfer { uri: string(0)? },
+            ActivityType::Build => Activity::Build {
+                drv_path: string(0)?,
+                machine: string(1)?,
+                round: int(2)?,
+                total_rounds: int(3)?,
+            },
+            ActivityType::Substitute => Activity::Substitute {
+                path: string(0)?,
+                substituter: string(1)?,
+            },
+            ActivityType::QueryPathInfo => Activity::QueryPathInfo {
+                path: string(0)?,
+                substituter: string(1)?,
+            },
+            ActivityType::PostBuildHook => Activity::PostBuildHook {
+                drv_path: string(0)?,
+            },
+            typ => Activity::Other(typ),
+        })
+    }
+}
+
+/// The kind of activity announced by a `StartActivity` message.
+///
+/// These numbers match nix's `ActivityType`. Types that newer daemons send (and nix's
+/// own `actUnknown`, which is 0) decode as [`ActivityType::Unknown`].
+#[derive(Debug, TaggedSerde, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ActivityType {
+    #[tagged_serde = 100]
+    CopyPath,
+    #[tagged_serde = 101]
+    FileTransfer,
+    #[tagged_serde = 102]
+    Realise,
+    #[tagged_serde = 103]
+    CopyPaths,
+    #[tagged_serde = 104]
+    Builds,
+    #[tagged_serde = 105]
+    Build,
+    #[tagged_serde = 106]
+    OptimiseStore,
+    #[tagged_serde = 107]
+    VerifyPaths,
+    #[tagged_serde = 108]
+    Substitute,
+    #[tagged_serde = 109]
+    QueryPathInfo,
+    #[tagged_serde = 110]
+    PostBuildHook,
+    #[tagged_serde = 111]
+    BuildWaiting,
+    /// A type that we don't know about, with its number.
+    #[tagged_serde_fallback]
+    Unknown(u64),
+}
+
+/// An activity, with its logger fields decoded according to its type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Activity {
+    CopyPath {
+        path: NixString,
+        from: NixString,
+        to: NixString,
+    },
+    FileTransfer {
+        uri: NixString,
+    },
+    Build {
+        drv_path: NixString,
+        machine: NixString,
+        round: u64,
+        total_rounds: u64,
+    },
+    Substitute {
+        path: NixString,
+        substituter: NixString,
+    },
+    QueryPathInfo {
+        path: NixString,
+        substituter: NixString,
+    },
+    PostBuildHook {
+        drv_path: NixString,
+    },
+    /// An activity type that doesn't carry any fields we know about.
+    Other(ActivityType),
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct StderrResult {
-    act: u64,
-    typ: u64,
-    fields: LoggerFields,
+    /// The activity that this is a result of.
+    pub act: u64,
+    pub typ: ResultType,
+    pub fields: LoggerFields,
+}
+
+/// The kind of result reported by a `Result` message.
+///
+/// These numbers match nix's `ResultType`. Types that newer daemons send decode as
+/// [`ResultType::Unknown`].
+#[derive(Debug, TaggedSerde, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResultType {
+    #[tagged_serde = 100]
+    FileLinked,
+    #[tagged_serde = 101]
+    BuildLogLine,
+    #[tagged_serde = 102]
+    UntrustedPath,
+    #[tagged_serde = 103]
+    CorruptedPath,
+    #[tagged_serde = 104]
+    SetPhase,
+    #[tagged_serde = 105]
+    Progress,
+    #[tagged_serde = 106]
+    SetExpected,
+    #[tagged_serde = 107]
+    PostBuildLogLine,
+    #[tagged_serde = 108]
+    FetchStatus,
+    /// A type that we don't know about, with its number.
+    #[tagged_serde_fallback]
+    Unknown(u64),
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
@@ -71,14 +314,169 @@ struct Trace {
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
-struct LoggerFields {
-    fields: Vec<LoggerField>,
+pub struct LoggerFields {
+    pub fields: Vec<LoggerField>,
 }
 
+/// A field of a `StartActivity` or `Result` message.
+///
+/// Unlike the activity and result types, an unknown field type can't fall back to
+/// anything: it would say how to read the field's body, so without it the rest of the
+/// message can't be decoded, and it fails instead.
 #[derive(Debug, TaggedSerde, Clone, PartialEq, Eq)]
-enum LoggerField {
+pub enum LoggerField {
     #[tagged_serde = 0]
     Int(u64),
     #[tagged_serde = 1]
     String(ByteBuf),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opcodes() {
+        let wire = [
+            (Opcode::Next, 0x6f6c6d67),
+            (Opcode::Read, 0x64617461),
+            (Opcode::Write, 0x64617416),
+            (Opcode::Last, 0x616c7473),
+            (Opcode::Error, 0x63787470),
+            (Opcode::StartActivity, 0x53545254),
+            (Opcode::StopActivity, 0x53544f50),
+            (Opcode::Result, 0x52534c54),
+        ];
+        assert_eq!(wire.map(|(op, _)| op), Opcode::ALL);
+        for (op, n) in wire {
+            assert_eq!(op.as_u64(), n);
+            assert_eq!(Opcode::from_u64(n).unwrap(), op);
+        }
+        assert!(Opcode::from_u64(0x64617462).is_err());
+
+        // The opcodes agree with the ones that `Msg` is serialized with.
+        let msgs = [
+            Msg::Read(4096),
+            Msg::Write(NixString::default()),
+            Msg::Error(StderrError::new("oops")),
+            Msg::Next(NixString::default()),
+            Msg::StopActivity(1),
+            Msg::Last(()),
+        ];
+        for msg in msgs {
+            let bytes = crate::to_vec(&msg).unwrap();
+            let n: u64 = crate::from_bytes(&bytes[..8]).unwrap();
+            assert_eq!(msg.opcode().as_u64(), n, "{msg:?}");
+        }
+    }
+
+    #[test]
+    fn unknown_activity_type() {
+        let bytes = crate::to_vec(&(
+            0x53545254u64,
+            12u64,
+            3u64,
+            112u64,
+            NixString::from(b"doing something new".to_vec()),
+            1u64,
+            (0u64, 42u64),
+            0u64,
+            Msg::Last(()),
+        ))
+        .unwrap();
+
+        // The rest of the stream still decodes.
+        let mut read = &bytes[..];
+        let msg: Msg = crate::NixReadExt::read_nix(&mut read).unwrap();
+        let Msg::StartActivity(start) = &msg else {
+            panic!("expected StartActivity, got {msg:?}");
+        };
+        assert_eq!(start.typ, ActivityType::Unknown(112));
+        assert_eq!(
+            start.activity().unwrap(),
+            Activity::Other(ActivityType::Unknown(112))
+        );
+        let last: Msg = crate::NixReadExt::read_nix(&mut read).unwrap();
+        assert_eq!(last, Msg::Last(()));
+
+        // And it encodes as it came.
+        let mut encoded = crate::to_vec(&msg).unwrap();
+        encoded.extend(crate::to_vec(&last).unwrap());
+        assert_eq!(encoded, bytes);
+    }
+
+    #[test]
+    fn unknown_result_type() {
+        let bytes = crate::to_vec(&(
+            0x52534c54u64,
+            12u64,
+            109u64,
+            1u64,
+            (1u64, NixString::from(b"something new".to_vec())),
+            Msg::Last(()),
+        ))
+        .unwrap();
+
+        let mut read = &bytes[..];
+        let msg: Msg = crate::NixReadExt::read_nix(&mut read).unwrap();
+        let Msg::Result(result) = &msg else {
+            panic!("expected Result, got {msg:?}");
+        };
+        assert_eq!(result.act, 12);
+        assert_eq!(result.typ, ResultType::Unknown(109));
+        let last: Msg = crate::NixReadExt::read_nix(&mut read).unwrap();
+        assert_eq!(last, Msg::Last(()));
+
+        let mut encoded = crate::to_vec(&msg).unwrap();
+        encoded.extend(crate::to_vec(&last).unwrap());
+        assert_eq!(encoded, bytes);
+
+        // Known types still decode as themselves.
+        let line = crate::to_vec(&(0x52534c54u64, 12u64, 101u64, 0u64)).unwrap();
+        let Msg::Result(result) = crate::from_bytes(&line).unwrap() else {
+            panic!("expected Result");
+        };
+        assert_eq!(result.typ, ResultType::BuildLogLine);
+    }
+
+    #[test]
+    fn unknown_logger_field_type() {
+        let bytes = crate::to_vec(&(0x52534c54u64, 12u64, 101u64, 1u64, (2u64, 0u64))).unwrap();
+        let err = crate::from_bytes::<Msg>(&bytes).unwrap_err();
+        assert!(err.to_string().contains("LoggerField"), "{err}");
+    }
+
+    #[test]
+    fn decode_build_activity() {
+        let bytes = crate::to_vec(&(
+            0x53545254u64,
+            12u64,
+            3u64,
+            105u64,
+            NixString::from(b"building '/nix/store/abc-foo.drv'".to_vec()),
+            4u64,
+            (1u64, NixString::from(b"/nix/store/abc-foo.drv".to_vec())),
+            (1u64, NixString::from(b"ssh://builder".to_vec())),
+            (0u64, 1u64),
+            (0u64, 2u64),
+            7u64,
+        ))
+        .unwrap();
+
+        let msg: Msg = crate::from_bytes(&bytes).unwrap();
+        let Msg::StartActivity(start) = msg else {
+            panic!("expected StartActivity, got {msg:?}");
+        };
+        assert_eq!(start.typ, ActivityType::Build);
+        assert_eq!(start.parent, 7);
+        assert_eq!(
+            start.activity().unwrap(),
+            Activity::Build {
+                drv_path: NixString::from(b"/nix/store/abc-foo.drv".to_vec()),
+                machine: NixString::from(b"ssh://builder".to_vec()),
+                round: 1,
+                total_rounds: 2,
+            }
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Helpers for testing code that talks to a nix daemon through a [`NixProxy`].
+//!
+//! This module is always available to this crate's own tests; other crates can use it
+//! by enabling the `test-util` feature. With the `tokio` feature, the scripted daemon
+//! also works with async code.
+//!
+//! [`NixProxy`]: crate::NixProxy
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use crate::{stderr, DaemonVersion, NixString, WORKER_MAGIC_1, WORKER_MAGIC_2};
+
+enum Step {
+    /// The proxy should send exactly these bytes (the ones that haven't arrived yet).
+    Expect(VecDeque<u8>),
+    /// Once everything expected so far has arrived, the daemon sends these bytes.
+    Respond(VecDeque<u8>),
+}
+
+#[derive(Default)]
+struct Script {
+    steps: VecDeque<Step>,
+    // How many bytes the proxy has sent, for error messages.
+    received: u64,
+    failure: Option<String>,
+}
+
+impl Script {
+    fn fail(&mut self, msg: String) -> io::Error {
+        let err = io::Error::new(io::ErrorKind::InvalidData, msg.clone());
+        self.failure.get_or_insert(msg);
+        err
+    }
+}
+
+/// A fake nix daemon that plays back a fixed conversation.
+///
+/// The conversation is a sequence of bytes that the proxy is expected to send, and
+/// canned responses (including stderr messages) to send back. Plug it into a proxy
+/// with [`ScriptedDaemon::io`] and [`NixProxy::from_io`], run the proxy, and then call
+/// [`ScriptedDaemon::finish`] to check that the whole conversation happened.
+///
+/// If the proxy sends anything other than the expected bytes, or tries to read a
+/// response before it has sent what the script expects, its I/O fails and
+/// [`ScriptedDaemon::finish`] panics with a description of what went wrong.
+///
+/// [`NixProxy::from_io`]: crate::NixProxy::from_io
+#[derive(Clone, Default)]
+pub struct ScriptedDaemon {
+    script: Arc<Mutex<Script>>,
+}
+
+impl ScriptedDaemon {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expect the proxy to send exactly `bytes` next.
+    pub fn expect(self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.push(Step::Expect(bytes.into().into()))
+    }
+
+    /// Expect the proxy to send `value`, in the wire format.
+    pub fn expect_nix(self, value: &impl Serialize) -> Self {
+        self.expect(crate::to_vec(value).unwrap())
+    }
+
+    /// Send `bytes` to the proxy.
+    pub fn respond(self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.push(Step::Respond(bytes.into().into()))
+    }
+
+    /// Send `value` to the proxy, in the wire format.
+    pub fn respond_nix(self, value: &impl Serialize) -> Self {
+        self.respond(crate::to_vec(value).unwrap())
+    }
+
+    /// Reply to an op: send the stderr messages `msgs`, then `Last`, and then `reply`.
+    pub fn reply(self, msgs: &[stderr::Msg], reply: &impl Serialize) -> Self {
+        let mut bytes = Vec::new();
+        for msg in msgs {
+            crate::to_writer(&mut bytes, msg).unwrap();
+        }
+        crate::to_writer(&mut bytes, &stderr::Msg::Last(())).unwrap();
+        crate::to_writer(&mut bytes, reply).unwrap();
+        self.respond(bytes)
+    }
+
+    /// Go through the handshake, with both sides using protocol `version`.
+    ///
+    /// The proxy never speaks anything newer than its own version, so `version` can't
+    /// be newer either.
+    pub fn handshake(self, version: DaemonVersion) -> Self {
+        let mut reply = handshake_reply(version);
+        // The daemon waits for the client's version before saying anything more.
+        let rest = reply.split_off(16);
+        self.expect_nix(&WORKER_MAGIC_1)
+            .respond(reply)
+            .expect_nix(&(u64::from(version), 0u64, 0u64))
+            .respond(rest)
+    }
+
+    /// The reading and writing halves of the daemon's connection to the proxy.
+    pub fn io(&self) -> (ScriptedRead, ScriptedWrite) {
+        (
+            ScriptedRead(self.script.clone()),
+            ScriptedWrite(self.script.clone()),
+        )
+    }
+
+    /// Panic unless the proxy sent everything it was expected to, and read all of the
+    /// responses.
+    pub fn finish(&self) {
+        let script = self.script.lock().unwrap();
+        if let Some(failure) = &script.failure {
+            panic!("scripted daemon: {failure}");
+        }
+        match script.steps.front() {
+            None => {}
+            Some(Step::Expect(bytes)) => panic!(
+                "scripted daemon: still expecting {:?} after {} bytes",
+                bytes, script.received
+            ),
+            Some(Step::Respond(bytes)) => {
+                panic!("scripted daemon: {} bytes were never read", bytes.len())
+            }
+        }
+    }
+
+    fn push(self, step: Step) -> Self {
+        self.script.lock().unwrap().steps.push_back(step);
+        self
+    }
+}
+
+/// Everything that a daemon sends in the handshake with protocol `version`, from its
+/// magic number to the `Last` stderr message, as [`ScriptedDaemon::handshake`] sends it.
+///
+/// This is for daemons that don't wait for the proxy, like a canned reply.
+pub fn handshake_reply(version: DaemonVersion) -> Vec<u8> {
+    assert!(
+        version <= crate::PROTOCOL_VERSION,
+        "the scripted handshake doesn't support protocol {version}"
+    );
+    let mut reply = crate::to_vec(&(WORKER_MAGIC_2, u64::from(version))).unwrap();
+    if version.minor >= crate::IDENTITY_MINOR {
+        crate::to_writer(&mut reply, &NixString::from(b"scripted-daemon".to_vec())).unwrap();
+    }
+    crate::to_writer(&mut reply, &stderr::Msg::Last(())).unwrap();
+    reply
+}
+
+/// The daemon's output: the responses in a [`ScriptedDaemon`].
+pub struct ScriptedRead(Arc<Mutex<Script>>);
+
+/// The daemon's input, which is checked against a [`ScriptedDaemon`].
+pub struct ScriptedWrite(Arc<Mutex<Script>>);
+
+impl Read for ScriptedRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut script = self.0.lock().unwrap();
+        let received = script.received;
+        match script.steps.front_mut() {
+            None => Ok(0),
+            Some(Step::Respond(bytes)) => {
+                let n = bytes.read(buf)?;
+                if bytes.is_empty() {
+                    script.steps.pop_front();
+                }
+                Ok(n)
+            }
+            Some(Step::Expect(bytes)) => {
+                let msg = format!(
+                    "after {received} bytes, the proxy read a response while the daemon was \
+                     still expecting {bytes:?}"
+                );
+                Err(script.fail(msg))
+            }
+        }
+    }
+}
+
+impl Write for ScriptedWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut script = self.0.lock().unwrap();
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let received = script.received;
+            match script.steps.front_mut() {
+                Some(Step::Expect(bytes)) => {
+                    let n = rest.len().min(bytes.len());
+                    let expected: Vec<u8> = bytes.drain(..n).collect();
+                    if expected != rest[..n] {
+                        let msg = format!(
+                            "after {received} bytes, expected {expected:?} but the proxy sent {:?}",
+                            &rest[..n]
+                        );
+                        return Err(script.fail(msg));
+                    }
+                    if bytes.is_empty() {
+                        script.steps.pop_front();
+                    }
+                    script.received += n as u64;
+                    rest = &rest[n..];
+                }
+                _ => {
+                    let msg = format!("after {received} bytes, the proxy sent unexpected {rest:?}");
+                    return Err(script.fail(msg));
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The script is all in memory, so the async versions never have to wait.
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for ScriptedRead {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        let n = self.get_mut().read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for ScriptedWrite {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        std::task::Poll::Ready(self.get_mut().write(buf))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        worker_op::{Plain, Resp, WorkerOp},
+        NixProxy, StorePath, PROTOCOL_VERSION,
+    };
+
+    fn is_valid_path() -> WorkerOp {
+        WorkerOp::IsValidPath(
+            Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            Resp::new(),
+        )
+    }
+
+    #[test]
+    fn is_valid_path_through_proxy() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let op = is_valid_path();
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&op)
+            .reply(&[stderr::Msg::Next(NixString::from(b"hi".to_vec()))], &true);
+        let (daemon_read, daemon_write) = daemon.io();
+
+        let client = crate::to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, &op)).unwrap();
+        let mut to_client = Vec::new();
+        NixProxy::from_io(
+            Cursor::new(client),
+            &mut to_client,
+            daemon_read,
+            daemon_write,
+        )
+        .process_connection()
+        .unwrap();
+        daemon.finish();
+
+        // The client gets the daemon's stderr messages and its reply.
+        let reply = crate::to_vec(&(
+            stderr::Msg::Next(NixString::from(b"hi".to_vec())),
+            stderr::Msg::Last(()),
+            true,
+        ))
+        .unwrap();
+        assert!(to_client.ends_with(&reply));
+    }
+
+    #[test]
+    #[should_panic(expected = "the proxy sent")]
+    fn unexpected_request() {
+        let version = u64::from(PROTOCOL_VERSION);
+        let daemon = ScriptedDaemon::new()
+            .handshake(PROTOCOL_VERSION)
+            .expect_nix(&WorkerOp::QueryAllValidPaths(Plain(()), Resp::new()))
+            .reply(&[], &crate::StorePathSet { paths: vec![] });
+        let (daemon_read, daemon_write) = daemon.io();
+
+        let client =
+            crate::to_vec(&(WORKER_MAGIC_1, version, 0u64, 0u64, is_valid_path())).unwrap();
+        let result = NixProxy::from_io(Cursor::new(client), io::sink(), daemon_read, daemon_write)
+            .process_connection();
+        assert!(result.is_err());
+        daemon.finish();
+    }
+}
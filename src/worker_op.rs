@@ -1,19 +1,25 @@
 //! Worker ops from the Nix protocol.
 
 use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap};
 use std::io::Read;
 use std::io::Write;
 use std::ops::{Deref, DerefMut};
 use tagged_serde::TaggedSerde;
 
+use crate::content_address::CaMethod;
 use crate::framed_data;
 use crate::nar::Nar;
 use crate::{
-    serialize::{NixDeserializer, NixSerializer},
-    NarHash, NixString, Result, StorePath, StorePathSet, StringSet, ValidPathInfoWithPath,
+    serialize::{NixDeserializer, NixSerializer, PathRewrite, Tee},
+    Counted, DaemonVersion, Error, NarHash, NixString, OptionalStorePath, Result, StorePath,
+    StorePathSet, StringSet, ValidPathInfoWithPath, PROTOCOL_VERSION,
 };
 use crate::{DerivedPath, Path, PathSet, Realisation, RealisationSet};
 
+// The longest response that `proxy_response` keeps a copy of for its self-check.
+const MAX_SELF_CHECK_LEN: usize = 1 << 20;
+
 /// A zero-sized marker type. Its job is to mark the expected response
 /// type for each worker op.
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -24,11 +30,23 @@ pub struct Resp<T> {
 }
 
 impl<T> Resp<T> {
+    pub fn new() -> Self {
+        Resp {
+            marker: std::marker::PhantomData,
+        }
+    }
+
     pub fn ty(&self, v: T) -> T {
         v
     }
 }
 
+impl<T> Default for Resp<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct Plain<T>(pub T);
@@ -41,10 +59,21 @@ impl<T> Deref for Plain<T> {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+/// The header of an op that is followed by a framed source.
+///
+/// The framed source itself is streamed separately (see [`Stream`]) and isn't stored
+/// here, so comparing two of these only compares their headers.
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct WithFramedSource<T>(pub T);
 
+// Only the header gets printed, so logging an op never dumps its data.
+impl<T: std::fmt::Debug> std::fmt::Debug for WithFramedSource<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WithFramedSource({:?}, +framed source)", self.0)
+    }
+}
+
 impl<T> Deref for WithFramedSource<T> {
     type Target = T;
 
@@ -61,12 +90,21 @@ impl<T> DerefMut for WithFramedSource<T> {
 
 pub trait Stream {
     fn stream(&self, read: &mut impl Read, write: &mut impl Write) -> anyhow::Result<()>;
+
+    /// Whether the data that [`Stream::stream`] copies is framed data.
+    fn has_framed_source(&self) -> bool {
+        false
+    }
 }
 
 impl<T> Stream for WithFramedSource<T> {
     fn stream(&self, read: &mut impl Read, write: &mut impl Write) -> anyhow::Result<()> {
         framed_data::stream(read, write)
     }
+
+    fn has_framed_source(&self) -> bool {
+        true
+    }
 }
 
 impl<T> Stream for Plain<T> {
@@ -75,6 +113,91 @@ impl<T> Stream for Plain<T> {
     }
 }
 
+/// Checks that a request is well-formed, beyond what decoding it already checks.
+///
+/// Requests are valid unless their type says otherwise.
+pub trait Validate {
+    /// Check that this request makes sense for protocol `version`.
+    fn validate(&self, _version: DaemonVersion) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Validate for () {}
+impl Validate for NixString {}
+impl Validate for StorePath {}
+impl Validate for StorePathSet {}
+impl Validate for AddToStore {}
+impl Validate for SetOptions {}
+impl Validate for QueryValidPaths {}
+impl Validate for VerifyStore {}
+impl Validate for AddToStoreNar {}
+impl Validate for QueryMissing {}
+impl Validate for RegisterDrvOutput {}
+impl Validate for AddMultipleToStore {}
+impl Validate for AddBuildLog {}
+impl Validate for Path {}
+impl Validate for AddPermRoot {}
+
+impl Validate for CollectGarbage {
+    fn validate(&self, _version: DaemonVersion) -> Result<()> {
+        CollectGarbage::validate(self)
+    }
+}
+
+impl Validate for BuildPaths {
+    fn validate(&self, version: DaemonVersion) -> Result<()> {
+        // Only one of the lists is sent, so anything in the other one would be dropped.
+        if version.minor >= 30 && !self.paths.is_empty() {
+            return Err(invalid(
+                "BuildPaths",
+                "plain paths are only sent before 1.30",
+            ));
+        }
+        if version.minor < 30 && !self.derived_paths.is_empty() {
+            return Err(invalid(
+                "BuildPaths",
+                "derived paths are only sent from 1.30",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for BuildDerivation {
+    fn validate(&self, _version: DaemonVersion) -> Result<()> {
+        if self.derivation.outputs.is_empty() {
+            return Err(invalid("BuildDerivation", "the derivation has no outputs"));
+        }
+        Ok(())
+    }
+}
+
+impl Validate for AddSignatures {
+    fn validate(&self, _version: DaemonVersion) -> Result<()> {
+        // Signatures look like `key-name:base64-signature`.
+        for sig in &self.signatures.paths {
+            match sig.0.iter().position(|&b| b == b':') {
+                Some(i) if i > 0 && i + 1 < sig.0.len() => {}
+                _ => {
+                    return Err(invalid(
+                        "AddSignatures",
+                        format!("malformed signature {sig:?}"),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(op: &str, reason: impl Into<String>) -> Error {
+    Error::InvalidOp {
+        op: op.to_owned(),
+        reason: reason.into(),
+    }
+}
+
 /// The worker ops of the nix protocol.
 ///
 /// The second argument in each variant is a tag denoting the expected return value.
@@ -82,19 +205,32 @@ impl<T> Stream for Plain<T> {
 /// On the wire, they are represented as the opcode followed by the body.
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 #[derive(Debug, TaggedSerde, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum WorkerOp {
     #[tagged_serde = 1]
     IsValidPath(Plain<StorePath>, Resp<bool>),
+    /// The paths that a path refers to (not to be confused with `QueryReferrers`, the
+    /// paths that refer to it).
+    ///
+    /// This is obsolete: nix gets references from `QueryPathInfo` instead. The proxy
+    /// always refuses it, at every protocol version, rather than forwarding it. It's
+    /// here so that old clients get an error and old captures can be decoded.
+    #[tagged_serde = 5]
+    QueryReferences(Plain<StorePath>, Resp<StorePathSet>),
     #[tagged_serde = 6]
     QueryReferrers(Plain<StorePath>, Resp<StorePathSet>),
     #[tagged_serde = 7]
     AddToStore(WithFramedSource<AddToStore>, Resp<ValidPathInfoWithPath>),
+    // The daemon has replied with a `u64` (always 1) since `BuildPaths` was added, so
+    // unlike `BuildResult`, the reply doesn't depend on the protocol version.
     #[tagged_serde = 9]
     BuildPaths(Plain<BuildPaths>, Resp<u64>),
     #[tagged_serde = 10]
     EnsurePath(Plain<StorePath>, Resp<u64>),
     #[tagged_serde = 11]
     AddTempRoot(Plain<StorePath>, Resp<u64>),
+    #[tagged_serde = 12]
+    AddIndirectRoot(Plain<Path>, Resp<u64>),
     #[tagged_serde = 14]
     FindRoots(Plain<()>, Resp<FindRootsResponse>),
     #[tagged_serde = 19]
@@ -130,7 +266,7 @@ pub enum WorkerOp {
     #[tagged_serde = 41]
     QueryDerivationOutputMap(Plain<StorePath>, Resp<DerivationOutputMap>),
     #[tagged_serde = 42]
-    RegisterDrvOutput(Plain<Realisation>, Resp<()>),
+    RegisterDrvOutput(Plain<RegisterDrvOutput>, Resp<()>),
     #[tagged_serde = 43]
     QueryRealisation(Plain<NixString>, Resp<RealisationSet>),
     #[tagged_serde = 44]
@@ -139,48 +275,129 @@ pub enum WorkerOp {
     AddBuildLog(WithFramedSource<AddBuildLog>, Resp<u64>),
     #[tagged_serde = 46]
     BuildPathsWithResults(Plain<BuildPaths>, Resp<Vec<(DerivedPath, BuildResult)>>),
+    /// Make `gc_root` a permanent garbage collector root for a path; the reply is the
+    /// root's path.
+    #[tagged_serde = 47]
+    AddPermRoot(Plain<AddPermRoot>, Resp<Path>),
 }
 
+// Calls the given macro with the list of ops, in the form `Name = opcode (Request) -> Response`.
 macro_rules! for_each_op {
     ($macro_name:ident !) => {
-        $macro_name!(
-            IsValidPath,
-            QueryReferrers,
-            AddToStore,
-            BuildPaths,
-            EnsurePath,
-            AddTempRoot,
-            FindRoots,
-            SetOptions,
-            CollectGarbage,
-            QueryAllValidPaths,
-            QueryPathInfo,
-            QueryPathFromHashPart,
-            QueryValidPaths,
-            QuerySubstitutablePaths,
-            QueryValidDerivers,
-            OptimiseStore,
-            VerifyStore,
-            BuildDerivation,
-            AddSignatures,
-            NarFromPath,
-            AddToStoreNar,
-            QueryMissing,
-            QueryDerivationOutputMap,
-            RegisterDrvOutput,
-            QueryRealisation,
-            AddMultipleToStore,
-            AddBuildLog,
-            BuildPathsWithResults
-        )
+        // Braces, so that this also works where an item is expected.
+        $macro_name! {
+            IsValidPath = 1 (StorePath) -> bool,
+            QueryReferences = 5 (StorePath) -> StorePathSet,
+            QueryReferrers = 6 (StorePath) -> StorePathSet,
+            AddToStore = 7 (AddToStore) -> ValidPathInfoWithPath,
+            BuildPaths = 9 (BuildPaths) -> u64,
+            EnsurePath = 10 (StorePath) -> u64,
+            AddTempRoot = 11 (StorePath) -> u64,
+            AddIndirectRoot = 12 (Path) -> u64,
+            FindRoots = 14 (()) -> FindRootsResponse,
+            SetOptions = 19 (SetOptions) -> (),
+            CollectGarbage = 20 (CollectGarbage) -> CollectGarbageResponse,
+            QueryAllValidPaths = 23 (()) -> StorePathSet,
+            QueryPathInfo = 26 (StorePath) -> QueryPathInfoResponse,
+            QueryPathFromHashPart = 29 (NixString) -> OptionalStorePath,
+            QueryValidPaths = 31 (QueryValidPaths) -> StorePathSet,
+            QuerySubstitutablePaths = 32 (StorePathSet) -> StorePathSet,
+            QueryValidDerivers = 33 (StorePath) -> StorePathSet,
+            OptimiseStore = 34 (()) -> u64,
+            VerifyStore = 35 (VerifyStore) -> bool,
+            BuildDerivation = 36 (BuildDerivation) -> BuildResult,
+            AddSignatures = 37 (AddSignatures) -> u64,
+            NarFromPath = 38 (StorePath) -> Nar,
+            AddToStoreNar = 39 (AddToStoreNar) -> (),
+            QueryMissing = 40 (QueryMissing) -> QueryMissingResponse,
+            QueryDerivationOutputMap = 41 (StorePath) -> DerivationOutputMap,
+            RegisterDrvOutput = 42 (RegisterDrvOutput) -> (),
+            QueryRealisation = 43 (NixString) -> RealisationSet,
+            AddMultipleToStore = 44 (AddMultipleToStore) -> (),
+            AddBuildLog = 45 (AddBuildLog) -> u64,
+            BuildPathsWithResults = 46 (BuildPaths) -> Vec<(DerivedPath, BuildResult)>,
+            AddPermRoot = 47 (AddPermRoot) -> Path
+        }
+    };
+}
+
+/// A description of a worker op, for tooling that needs to cover all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpInfo {
+    /// The name of the op, as returned by [`WorkerOp::name`].
+    pub name: &'static str,
+    pub opcode: u64,
+    /// The name of the request type, not counting any framed source.
+    pub request: &'static str,
+    /// The name of the response type.
+    pub response: &'static str,
+}
+
+/// Every worker op, in order of opcode.
+pub const OP_TABLE: &[OpInfo] = {
+    macro_rules! table {
+        ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+            &[$(OpInfo {
+                name: stringify!($name),
+                opcode: $opcode,
+                request: stringify!($req),
+                response: stringify!($resp),
+            }),*]
+        };
+    }
+
+    for_each_op!(table!)
+};
+
+/// The opcodes of every worker op that we can decode, in increasing order.
+///
+/// Like [`OP_TABLE`], this comes from the list of ops, so it's always up to date.
+pub fn supported_opcodes() -> &'static [u64] {
+    macro_rules! opcodes {
+        ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+            &[$($opcode),*]
+        };
+    }
+
+    for_each_op!(opcodes!)
+}
+
+// Checks, at compile time, that `for_each_op!` lists every `WorkerOp` variant with the
+// request and response types that the variant has.
+const _: fn(&WorkerOp) = |op| {
+    fn check<Req, Res, R: Deref<Target = Req>>(_req: &R, _resp: &Resp<Res>) {}
+
+    macro_rules! check_types {
+        ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+            match op {
+                $(WorkerOp::$name(req, resp) => check::<$req, $resp, _>(req, resp),)*
+            }
+        };
+    }
+
+    for_each_op!(check_types!)
+};
+
+macro_rules! response_value {
+    ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+        /// A reply to any worker op, as decoded by [`WorkerOp::decode_response`].
+        ///
+        /// There is one variant for each op, holding that op's response type.
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        #[non_exhaustive]
+        pub enum ResponseValue {
+            $($name($resp),)*
+        }
     };
 }
 
+for_each_op!(response_value!);
+
 impl Stream for WorkerOp {
     fn stream(&self, read: &mut impl Read, write: &mut impl Write) -> anyhow::Result<()> {
         eprintln!("streaming worker op");
         macro_rules! stream {
-            ($($name:ident),*) => {
+            ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
                 match self {
                     $(WorkerOp::$name(op, _resp) => {
                         op.stream(read, write)?;
@@ -192,31 +409,261 @@ impl Stream for WorkerOp {
         for_each_op!(stream!);
         Ok(())
     }
+
+    fn has_framed_source(&self) -> bool {
+        macro_rules! has_framed_source {
+            ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+                match self {
+                    $(WorkerOp::$name(op, _resp) => op.has_framed_source(),)*
+                }
+            };
+        }
+
+        for_each_op!(has_framed_source!)
+    }
 }
 
 impl WorkerOp {
-    pub fn proxy_response(&self, mut read: impl Read, mut write: impl Write) -> Result<()> {
-        let mut deser = NixDeserializer { read: &mut read };
-        let mut ser = NixSerializer { write: &mut write };
-        let mut dbg_buf = Vec::new();
-        let mut dbg_ser = NixSerializer {
-            write: &mut dbg_buf,
-        };
+    /// The name of this op, like `"IsValidPath"`.
+    pub fn name(&self) -> &'static str {
+        macro_rules! name {
+            ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+                match self {
+                    $(WorkerOp::$name(..) => stringify!($name),)*
+                }
+            };
+        }
+
+        for_each_op!(name!)
+    }
+
+    /// Check that this op is well-formed for protocol `version` (see [`Validate`]).
+    pub fn validate(&self, version: DaemonVersion) -> Result<()> {
+        macro_rules! validate {
+            ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+                match self {
+                    $(WorkerOp::$name(op, _resp) => Validate::validate(&**op, version),)*
+                }
+            };
+        }
+
+        for_each_op!(validate!)
+    }
+
+    /// Encode this op (its opcode and body) as bytes.
+    ///
+    /// Framed sources aren't part of the op itself (they are streamed separately, see
+    /// [`Stream`]), so for ops like `AddToStore` the result is only the header.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(crate::to_vec(self)?)
+    }
+
+    /// Decode an op from bytes, as encoded by [`WorkerOp::to_bytes`].
+    ///
+    /// Like [`WorkerOp::to_bytes`], this doesn't deal with framed sources. It fails
+    /// unless `bytes` holds exactly one op.
+    pub fn from_bytes(bytes: &[u8]) -> Result<WorkerOp> {
+        let mut read = bytes;
+        let op = WorkerOp::deserialize(&mut NixDeserializer::new(&mut read))?;
+        if !read.is_empty() {
+            Err(anyhow::anyhow!(
+                "{} bytes left over after {}",
+                read.len(),
+                op.name()
+            ))?;
+        }
+        Ok(op)
+    }
+
+    /// Decode `bytes` as the daemon's reply to this op in protocol `version`, not
+    /// including the stderr messages that come before it.
+    ///
+    /// This is the offline counterpart of [`WorkerOp::proxy_response`], for replaying
+    /// captured replies. It fails unless `bytes` holds exactly one reply.
+    pub fn decode_response(&self, bytes: &[u8], version: DaemonVersion) -> Result<ResponseValue> {
+        let mut read = bytes;
+        let mut de = NixDeserializer::with_version(&mut read, version);
+        macro_rules! decode {
+            ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
+                match self {
+                    $(WorkerOp::$name(_, resp) => {
+                        ResponseValue::$name(resp.ty(<_>::deserialize(&mut de)?))
+                    })*
+                }
+            };
+        }
+
+        let value = for_each_op!(decode!);
+        if !read.is_empty() {
+            Err(anyhow::anyhow!(
+                "{} bytes left over after the reply to {}",
+                read.len(),
+                self.name()
+            ))?;
+        }
+        Ok(value)
+    }
+
+    /// A made-up, successful reply to this op, if it modifies the store.
+    ///
+    /// This is for proxying in dry-run mode, where mutating ops are answered without
+    /// being forwarded to the daemon. Ops that only query the store return `None`.
+    ///
+    /// The reply is encoded for protocol `version`, which should be the one negotiated
+    /// with the client.
+    ///
+    /// `AddToStore` can't be answered this way: its reply is the path that the data
+    /// ends up at, and that depends on the data, which we don't hash. It returns an
+    /// error, which is meant to be passed on to the client.
+    pub fn dry_run_reply(&self, version: DaemonVersion) -> Option<Result<Vec<u8>>> {
+        fn reply(value: &impl Serialize, version: DaemonVersion) -> Option<Result<Vec<u8>>> {
+            let mut bytes = Vec::new();
+            value
+                .serialize(&mut NixSerializer::with_version(&mut bytes, version))
+                .expect("serializing to memory can't fail");
+            Some(Ok(bytes))
+        }
+
+        match self {
+            WorkerOp::BuildPaths(_, resp)
+            | WorkerOp::EnsurePath(_, resp)
+            | WorkerOp::AddTempRoot(_, resp)
+            | WorkerOp::AddIndirectRoot(_, resp)
+            | WorkerOp::OptimiseStore(_, resp)
+            | WorkerOp::AddSignatures(_, resp)
+            | WorkerOp::AddBuildLog(_, resp) => reply(&resp.ty(1), version),
+            WorkerOp::AddToStoreNar(_, resp)
+            | WorkerOp::RegisterDrvOutput(_, resp)
+            | WorkerOp::AddMultipleToStore(_, resp) => reply(&resp.ty(()), version),
+            WorkerOp::AddToStore(..) => Some(Err(anyhow::anyhow!(
+                "AddToStore can't be faked in dry-run mode"
+            )
+            .into())),
+            WorkerOp::AddPermRoot(op, resp) => reply(&resp.ty(op.gc_root.clone()), version),
+            WorkerOp::CollectGarbage(_, resp) => reply(
+                &resp.ty(CollectGarbageResponse {
+                    paths: PathSet { paths: Vec::new() },
+                    bytes_freed: 0,
+                    _obsolete: 0,
+                }),
+                version,
+            ),
+            WorkerOp::VerifyStore(op, resp) if op.repair => reply(&resp.ty(false), version),
+            WorkerOp::BuildDerivation(_, resp) => reply(
+                &resp.ty(BuildResult {
+                    status: BuildStatus::Built,
+                    error_msg: NixString::default(),
+                    times_built: 0,
+                    is_non_deterministic: false,
+                    start_time: 0,
+                    stop_time: 0,
+                    built_outputs: DrvOutputs::default(),
+                }),
+                version,
+            ),
+            WorkerOp::BuildPathsWithResults(_, resp) => reply(&resp.ty(Vec::new()), version),
+            _ => None,
+        }
+    }
+
+    /// Decode a stream of consecutive worker ops, as sent by a client.
+    ///
+    /// Any framed sources following an op are read and discarded. The iterator ends
+    /// on EOF between ops; EOF in the middle of an op is an error. After the first
+    /// error, the iterator yields nothing more.
+    pub fn iter_reader<R: Read>(read: R) -> impl Iterator<Item = Result<WorkerOp>> {
+        let mut read = Counted::new(read);
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            read.count = 0;
+            let op = match WorkerOp::deserialize(&mut NixDeserializer::new(&mut read)) {
+                Ok(op) => op,
+                Err(crate::serialize::Error::Io(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof && read.count == 0 =>
+                {
+                    done = true;
+                    return None;
+                }
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e.into()));
+                }
+            };
+            if let Err(e) = op.stream(&mut read, &mut std::io::sink()) {
+                done = true;
+                return Some(Err(e.into()));
+            }
+            Some(Ok(op))
+        })
+    }
+
+    /// Read this op's response from `read`, and forward it to `write`.
+    ///
+    /// The daemon sends stderr messages (ending with `Last`) before the response; those
+    /// must have been dealt with already, as [`NixProxy`](crate::NixProxy) does.
+    ///
+    /// As a self-check, the response is decoded and re-encoded before forwarding. If the
+    /// re-encoded bytes differ from what we read, we return [`Error::RoundtripMismatch`]
+    /// without forwarding anything. Responses longer than 1 MiB skip the self-check,
+    /// rather than keeping a second copy of them in memory.
+    pub fn proxy_response(&self, read: impl Read, write: impl Write) -> Result<()> {
+        self.proxy_response_with(read, write, PROTOCOL_VERSION, None, SelfCheckPolicy::Fail)
+    }
+
+    /// Like [`WorkerOp::proxy_response`], but for protocol `version`, rewriting the
+    /// paths in the response (after the self-check) with `rewrite`, and handling a
+    /// failed self-check according to `self_check`.
+    pub fn proxy_response_with(
+        &self,
+        mut read: impl Read,
+        mut write: impl Write,
+        version: DaemonVersion,
+        rewrite: Option<&PathRewrite>,
+        self_check: SelfCheckPolicy,
+    ) -> Result<()> {
         macro_rules! respond {
-            ($($name:ident),*) => {
+            ($($name:ident = $opcode:literal ($req:ty) -> $resp:ty),*) => {
                 #[allow(unreachable_patterns)]
                 match self {
                     // Special case for NarFromPath because the response could be large
                     // and needs to be streamed instead of read into memory.
                     WorkerOp::NarFromPath(_inner, _resp) => {
-                      crate::nar::stream(&mut deser.read, &mut ser.write)?;
+                      crate::nar::stream(&mut read, &mut write)?;
                     }
                     $(WorkerOp::$name(_inner, resp) => {
-                        let reply = resp.ty(<_>::deserialize(&mut deser)?);
+                        let mut expected = Vec::new();
+                        let mut tee = Tee::new(&mut read, &mut expected)
+                            .with_max_capture(MAX_SELF_CHECK_LEN);
+                        let mut de = NixDeserializer::with_version(&mut tee, version);
+                        let reply = resp.ty(<_>::deserialize(&mut de)?);
                         eprintln!("read reply {reply:?}");
 
-                        reply.serialize(&mut dbg_ser)?;
-                        reply.serialize(&mut ser)?;
+                        let mut got = Vec::new();
+                        reply.serialize(&mut NixSerializer::with_version(&mut got, version))?;
+                        if tee.is_complete() && got != expected {
+                            let err = Error::RoundtripMismatch {
+                                op: self.name().to_owned(),
+                                expected,
+                                got: got.clone(),
+                            };
+                            crate::instrument::self_check_mismatch(self.name());
+                            match self_check {
+                                SelfCheckPolicy::Fail => return Err(err),
+                                SelfCheckPolicy::Panic => panic!("{err}"),
+                                SelfCheckPolicy::Warn => eprintln!("warning: {err}"),
+                                SelfCheckPolicy::Ignore => {}
+                            }
+                        }
+                        if let Some(rewrite) = rewrite {
+                            got.clear();
+                            let mut ser = NixSerializer::with_version(&mut got, version)
+                                .with_path_rewrite(rewrite);
+                            reply.serialize(&mut ser)?;
+                        }
+                        write.write_all(&got)?;
                     },)*
                 }
             };
@@ -227,11 +674,29 @@ impl WorkerOp {
     }
 }
 
+/// What to do when a daemon response changes after being decoded and re-encoded.
+///
+/// Some differences are harmless: nix doesn't always encode things the way we do (any
+/// non-zero number is a `true` boolean, for example), and the daemon doesn't care.
+/// Whenever the response is forwarded anyway, the client gets our re-encoding of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelfCheckPolicy {
+    /// Don't forward the response, and return [`Error::RoundtripMismatch`].
+    Fail,
+    /// Panic, which is mostly useful in tests.
+    Panic,
+    /// Log a warning, and forward the response.
+    #[default]
+    Warn,
+    /// Forward the response without saying anything.
+    Ignore,
+}
+
 type Time = u64;
-type OptionalStorePath = StorePath;
 
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
-#[derive(Debug, Clone, Copy, TaggedSerde, PartialEq, Eq)]
+/// How much nix logs, from least to most.
+#[derive(Debug, Clone, Copy, TaggedSerde, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
 pub enum Verbosity {
     #[tagged_serde = 0]
     Error,
@@ -249,6 +714,18 @@ pub enum Verbosity {
     Debug,
     #[tagged_serde = 7]
     Vomit,
+    /// A level that is newer than this crate. It sorts above all of the known ones.
+    #[tagged_serde_fallback]
+    Unknown(u64),
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for Verbosity {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // An unknown level that is really a known one would decode as the known one.
+        let tag = u.int_in_range(0..=20u64)?;
+        Ok(crate::from_bytes(&crate::to_vec(&tag).unwrap()).unwrap())
+    }
 }
 
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
@@ -266,20 +743,70 @@ pub struct SetOptions {
     _print_build_trace: u64,
     pub build_cores: u64,
     pub use_substitutes: bool,
+    /// Any other settings, as key/value pairs. Clients older than protocol 1.12 don't send
+    /// these, and they decode as empty.
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<12, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<12, _, _>"
+    )]
     pub options: Vec<(NixString, NixString)>,
 }
 
+/// The argument of `RegisterDrvOutput`.
+///
+/// Since protocol 1.31 this is a whole realisation (as JSON). Before that, it was just the
+/// derivation output (like `sha256:...!out`) and its store path; whichever isn't on the wire
+/// decodes as empty.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RegisterDrvOutput {
+    #[serde(
+        serialize_with = "crate::serialize::until_minor::<31, _, _>",
+        deserialize_with = "crate::serialize::deserialize_until_minor::<31, _, _>"
+    )]
+    pub drv_output: NixString,
+    #[serde(
+        serialize_with = "crate::serialize::until_minor::<31, _, _>",
+        deserialize_with = "crate::serialize::deserialize_until_minor::<31, _, _>"
+    )]
+    pub out_path: StorePath,
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<31, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<31, _, _>"
+    )]
+    pub realisation: Realisation,
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for RegisterDrvOutput {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Only the fields of the current protocol version survive a roundtrip.
+        Ok(RegisterDrvOutput {
+            drv_output: NixString::default(),
+            out_path: StorePath::default(),
+            realisation: u.arbitrary()?,
+        })
+    }
+}
+
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 pub struct AddToStore {
     pub name: StorePath,
-    pub cam_str: StorePath,
+    /// The content addressing method, as a string; see [`AddToStore::ca_method`].
+    pub cam_str: NixString,
     pub refs: StorePathSet,
     pub repair: bool,
 }
 
+impl AddToStore {
+    pub fn ca_method(&self) -> Result<CaMethod> {
+        CaMethod::parse(self.cam_str.as_ref())
+    }
+}
+
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 #[derive(Debug, Clone, Copy, TaggedSerde, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum BuildMode {
     #[tagged_serde = 0]
     Normal,
@@ -289,13 +816,38 @@ pub enum BuildMode {
     Check,
 }
 
+/// The argument of `BuildPaths` and `BuildPathsWithResults`.
+///
+/// Since protocol 1.30 the things to build are derived paths (like `/nix/store/...-foo.drv!out`),
+/// which can say which outputs are wanted. Before that, they were plain store paths; whichever
+/// isn't on the wire decodes as empty.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct BuildPaths {
+    #[serde(
+        serialize_with = "crate::serialize::until_minor::<30, _, _>",
+        deserialize_with = "crate::serialize::deserialize_until_minor::<30, _, _>"
+    )]
     pub paths: Vec<StorePath>,
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<30, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<30, _, _>"
+    )]
+    pub derived_paths: Vec<DerivedPath>,
     pub build_mode: BuildMode,
 }
 
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for BuildPaths {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Only the fields of the current protocol version survive a roundtrip.
+        Ok(BuildPaths {
+            paths: Vec::new(),
+            derived_paths: u.arbitrary()?,
+            build_mode: u.arbitrary()?,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct QueryMissing {
@@ -318,8 +870,79 @@ pub struct QueryMissingResponse {
     pub nar_size: u64,
 }
 
+impl QueryMissingResponse {
+    /// Count the paths in each category.
+    pub fn summary(&self) -> ClosureSummary {
+        ClosureSummary {
+            will_build: self.will_build.paths.len(),
+            will_substitute: self.will_substitute.paths.len(),
+            unknown: self.unknown.paths.len(),
+            download_size: self.download_size,
+            nar_size: self.nar_size,
+        }
+    }
+
+    /// Whether anything needs to be built or substituted.
+    pub fn needs_action(&self) -> bool {
+        !self.will_build.paths.is_empty() || !self.will_substitute.paths.is_empty()
+    }
+
+    /// A copy with each set of paths sorted (see [`StorePathSet::sort`]), for output
+    /// that doesn't depend on the order the daemon sent them in.
+    pub fn sorted(&self) -> Self {
+        let mut sorted = self.clone();
+        sorted.will_build.sort();
+        sorted.will_substitute.sort();
+        sorted.unknown.sort();
+        sorted
+    }
+}
+
+/// The numbers from a [`QueryMissingResponse`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClosureSummary {
+    pub will_build: usize,
+    pub will_substitute: usize,
+    pub unknown: usize,
+    pub download_size: u64,
+    pub nar_size: u64,
+}
+
+impl ClosureSummary {
+    /// The sizes of the substitutions, the way nix prints them (e.g. `1.50 MiB download,
+    /// 4.00 MiB unpacked`).
+    pub fn size_string(&self) -> String {
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        format!(
+            "{:.2} MiB download, {:.2} MiB unpacked",
+            mib(self.download_size),
+            mib(self.nar_size)
+        )
+    }
+}
+
+impl std::fmt::Display for ClosureSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let paths = |n: usize| {
+            if n == 1 {
+                "1 path".to_owned()
+            } else {
+                format!("{n} paths")
+            }
+        };
+        write!(
+            f,
+            "{} to build, {} to fetch ({}), {} unknown",
+            paths(self.will_build),
+            paths(self.will_substitute),
+            self.size_string(),
+            paths(self.unknown)
+        )
+    }
+}
+
 #[derive(Debug, Clone, Copy, TaggedSerde, PartialEq, Eq)]
-#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+#[non_exhaustive]
 pub enum BuildStatus {
     #[tagged_serde = 0]
     Built,
@@ -351,6 +974,18 @@ pub enum BuildStatus {
     ResolvesToAlreadyValid,
     #[tagged_serde = 14]
     NoSubstituters,
+    /// A status that is newer than this crate.
+    #[tagged_serde_fallback]
+    Unknown(u64),
+}
+
+#[cfg(test)]
+impl<'a> arbitrary::Arbitrary<'a> for BuildStatus {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        // An unknown status that is really a known one would decode as the known one.
+        let tag = u.int_in_range(0..=20u64)?;
+        Ok(crate::from_bytes(&crate::to_vec(&tag).unwrap()).unwrap())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
@@ -358,10 +993,33 @@ pub enum BuildStatus {
 pub struct BuildResult {
     pub status: BuildStatus,
     pub error_msg: NixString,
+    // Daemons older than protocol 1.29 don't send these four, and they decode as zero.
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<29, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<29, _, _>"
+    )]
     pub times_built: u64,
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<29, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<29, _, _>"
+    )]
     pub is_non_deterministic: bool,
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<29, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<29, _, _>"
+    )]
     pub start_time: Time,
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<29, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<29, _, _>"
+    )]
     pub stop_time: Time,
+    /// The realisations of the outputs that were built. Daemons older than protocol 1.28
+    /// don't send these, and they decode as empty.
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<28, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<28, _, _>"
+    )]
     pub built_outputs: DrvOutputs,
 }
 
@@ -382,12 +1040,58 @@ pub struct CollectGarbage {
     _obsolete2: u64,
 }
 
+impl CollectGarbage {
+    /// A request to do `action`, without any limit on how much to free.
+    pub fn new(action: GcAction, paths_to_delete: StorePathSet) -> Self {
+        CollectGarbage {
+            action,
+            paths_to_delete,
+            ignore_liveness: false,
+            max_freed: u64::MAX,
+            _obsolete0: 0,
+            _obsolete1: 0,
+            _obsolete2: 0,
+        }
+    }
+
+    /// Check that there are paths to delete if, and only if, the action is
+    /// `DeleteSpecific`.
+    pub fn validate(&self) -> Result<()> {
+        let paths = self.paths_to_delete.paths.len();
+        if (self.action == GcAction::DeleteSpecific) != (paths > 0) {
+            return Err(Error::InvalidGcRequest {
+                action: self.action,
+                paths,
+            });
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct DerivationOutputMap {
     pub paths: Vec<(NixString, OptionalStorePath)>,
 }
 
+impl DerivationOutputMap {
+    /// The outputs by name, with `None` for outputs whose path isn't known.
+    ///
+    /// Fails with [`Error::DuplicateOutput`] if the daemon sent the same output twice.
+    pub fn to_map(&self) -> Result<HashMap<NixString, Option<StorePath>>> {
+        let mut map = HashMap::with_capacity(self.paths.len());
+        for (name, path) in &self.paths {
+            match map.entry(name.clone()) {
+                Entry::Occupied(_) => return Err(Error::DuplicateOutput(name.clone())),
+                Entry::Vacant(entry) => {
+                    entry.insert(path.0.clone());
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct CollectGarbageResponse {
@@ -398,6 +1102,7 @@ pub struct CollectGarbageResponse {
 
 #[derive(Debug, Copy, Clone, TaggedSerde, Default, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
+#[non_exhaustive]
 pub enum GcAction {
     #[tagged_serde = 0]
     ReturnLive,
@@ -436,6 +1141,11 @@ pub struct FindRootsResponse {
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct QueryValidPaths {
     pub paths: StorePathSet,
+    /// Only sent from protocol 1.27; older clients never ask for substitution here.
+    #[serde(
+        serialize_with = "crate::serialize::since_minor::<27, _, _>",
+        deserialize_with = "crate::serialize::deserialize_since_minor::<27, _, _>"
+    )]
     pub builders_use_substitutes: bool,
 }
 
@@ -459,6 +1169,60 @@ pub struct ValidPathInfo {
     pub content_address: RenderedContentAddress, // Can be empty
 }
 
+impl ValidPathInfo {
+    /// The store paths that this path refers to.
+    pub fn references_iter(&self) -> impl Iterator<Item = &StorePath> {
+        self.references.paths.iter()
+    }
+
+    /// What nix signs for `path` with this info: `1;<path>;sha256:<hash>;<size>;<refs>`,
+    /// with the NAR hash in base-32 and the references sorted and separated by commas.
+    pub fn fingerprint(&self, path: &StorePath) -> Result<Vec<u8>> {
+        let mut refs: Vec<&[u8]> = self.references_iter().map(AsRef::as_ref).collect();
+        refs.sort();
+        let hash = crate::nixbase32::encode(&self.hash.digest()?);
+        Ok([
+            b"1;",
+            path.as_ref(),
+            format!(";sha256:{hash};{};", self.nar_size).as_bytes(),
+            &refs.join(&b',')[..],
+        ]
+        .concat())
+    }
+
+    /// Whether any of the signatures on this info for `path` is a valid signature by
+    /// one of `trusted_keys`, which are keyed by name (like `cache.nixos.org-1`).
+    ///
+    /// Signatures by other keys are ignored, as are malformed ones. This only fails if
+    /// the info itself is malformed, so that there's no fingerprint to check against.
+    #[cfg(feature = "signatures")]
+    pub fn verify_signatures(
+        &self,
+        path: &StorePath,
+        trusted_keys: &HashMap<String, ed25519_dalek::VerifyingKey>,
+    ) -> Result<bool> {
+        use base64::Engine;
+
+        let fingerprint = self.fingerprint(path)?;
+        Ok(self.sigs.paths.iter().any(|sig| {
+            let Some((name, sig)) = std::str::from_utf8(sig.as_ref())
+                .ok()
+                .and_then(|sig| sig.split_once(':'))
+            else {
+                return false;
+            };
+            let Some(key) = trusted_keys.get(name) else {
+                return false;
+            };
+            base64::engine::general_purpose::STANDARD
+                .decode(sig)
+                .ok()
+                .and_then(|sig| ed25519_dalek::Signature::from_slice(&sig).ok())
+                .is_some_and(|sig| key.verify_strict(&fingerprint, &sig).is_ok())
+        }))
+    }
+}
+
 type RenderedContentAddress = NixString;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -481,6 +1245,13 @@ pub struct AddBuildLog {
     pub path: StorePath,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(test, derive(arbitrary::Arbitrary))]
+pub struct AddPermRoot {
+    pub store_path: StorePath,
+    pub gc_root: Path,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[cfg_attr(test, derive(arbitrary::Arbitrary))]
 pub struct BuildDerivation {
@@ -508,12 +1279,75 @@ pub struct DerivationOutput {
     pub hash_or_impure: NixString,
 }
 
+/// The different kinds of derivation outputs, as decoded by [`DerivationOutput::parse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DerivationOutputType {
+    /// The output path is computed from the derivation's inputs.
+    InputAddressed(StorePath),
+    /// A fixed-output derivation, whose output hash is known in advance.
+    Fixed {
+        method: CaMethod,
+        /// The expected hash, rendered in base-16.
+        hash: NixString,
+    },
+    /// A content-addressed output whose hash isn't known until it is built.
+    FloatingCa { method: CaMethod },
+    /// An input-addressed output whose path can't be computed yet, because it depends
+    /// on floating content-addressed outputs.
+    Deferred,
+    /// An impure output, which is content-addressed but never cached.
+    Impure { method: CaMethod },
+}
+
+impl DerivationOutput {
+    /// Figure out what kind of output this is.
+    ///
+    /// This follows `parseDerivationOutput` in nix: if there is a hash algorithm, the
+    /// output is content-addressed and the hash (or lack thereof, or the word `impure`)
+    /// tells us which kind. Otherwise, the output is input-addressed (or deferred, if
+    /// it has no path).
+    pub fn parse(&self) -> Result<DerivationOutputType> {
+        let path: &[u8] = self.store_path.as_ref();
+        let method: &[u8] = self.method_or_hash.as_ref();
+        if method.is_empty() {
+            return Ok(if path.is_empty() {
+                DerivationOutputType::Deferred
+            } else {
+                DerivationOutputType::InputAddressed(self.store_path.clone())
+            });
+        }
+
+        let method = CaMethod::parse_derivation_output(method)?;
+        let hash: &[u8] = self.hash_or_impure.as_ref();
+        if !hash.is_empty() && hash != b"impure" {
+            return Ok(DerivationOutputType::Fixed {
+                method,
+                hash: self.hash_or_impure.clone(),
+            });
+        }
+
+        if !path.is_empty() {
+            Err(anyhow::anyhow!(
+                "floating or impure output has a store path"
+            ))?;
+        }
+        if hash.is_empty() {
+            Ok(DerivationOutputType::FloatingCa { method })
+        } else {
+            Ok(DerivationOutputType::Impure { method })
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use arbtest::arbtest;
     use serde_bytes::ByteBuf;
 
-    use crate::{serialize::NixSerializer, worker_op::SetOptions};
+    use crate::{
+        serialize::NixSerializer, worker_op::SetOptions, DaemonVersion, NixReadExt, NixWriteExt,
+        PROTOCOL_VERSION,
+    };
 
     use super::*;
 
@@ -533,19 +1367,822 @@ mod tests {
             build_cores: 77,
             use_substitutes: false,
             options: vec![(
-                NixString(ByteBuf::from(b"buf1".to_owned())),
-                NixString(ByteBuf::from(b"buf2".to_owned())),
+                NixString::from(b"buf1".to_vec()),
+                NixString::from(b"buf2".to_vec()),
             )],
         };
         let mut cursor = std::io::Cursor::new(Vec::new());
-        let mut serializer = NixSerializer { write: &mut cursor };
+        let mut serializer = NixSerializer::new(&mut cursor);
         options.serialize(&mut serializer).unwrap();
 
         cursor.set_position(0);
-        let mut deserializer = NixDeserializer { read: &mut cursor };
+        let mut deserializer = NixDeserializer::new(&mut cursor);
         assert_eq!(options, SetOptions::deserialize(&mut deserializer).unwrap());
     }
 
+    #[test]
+    fn test_set_options_versions() {
+        let old = DaemonVersion {
+            major: 1,
+            minor: 10,
+        };
+        let new = DaemonVersion {
+            major: 1,
+            minor: 30,
+        };
+        let mut options = SetOptions {
+            keep_failing: false,
+            keep_going: true,
+            try_fallback: false,
+            verbosity: Verbosity::Info,
+            max_build_jobs: 4,
+            max_silent_time: 0,
+            _use_build_hook: 1,
+            build_verbosity: Verbosity::Error,
+            _log_type: 0,
+            _print_build_trace: 0,
+            build_cores: 8,
+            use_substitutes: true,
+            options: vec![],
+        };
+
+        let mut old_bytes = Vec::new();
+        old_bytes.write_nix_versioned(&options, old).unwrap();
+        let mut new_bytes = Vec::new();
+        new_bytes.write_nix_versioned(&options, new).unwrap();
+        // The only difference is the length of the (empty) options list.
+        assert_eq!(new_bytes.len(), old_bytes.len() + 8);
+
+        let from_old: SetOptions = (&old_bytes[..]).read_nix_versioned(old).unwrap();
+        let from_new: SetOptions = (&new_bytes[..]).read_nix_versioned(new).unwrap();
+        assert_eq!(from_old, options);
+        assert_eq!(from_new, options);
+
+        options.options = vec![(
+            NixString::from(b"sandbox".to_vec()),
+            NixString::from(b"false".to_vec()),
+        )];
+        let mut new_bytes = Vec::new();
+        new_bytes.write_nix_versioned(&options, new).unwrap();
+        let from_new: SetOptions = (&new_bytes[..]).read_nix_versioned(new).unwrap();
+        assert_eq!(from_new, options);
+    }
+
+    #[test]
+    fn test_register_drv_output_versions() {
+        let old = DaemonVersion {
+            major: 1,
+            minor: 30,
+        };
+        let new = DaemonVersion {
+            major: 1,
+            minor: 31,
+        };
+        let drv_output = NixString::from(b"sha256:abc!out".to_vec());
+        let out_path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let realisation = Realisation(NixString::from(br#"{"id":"sha256:abc!out"}"#.to_vec()));
+
+        let old_bytes = crate::to_vec(&(42u64, &drv_output, &out_path)).unwrap();
+        let op: WorkerOp = (&old_bytes[..]).read_nix_versioned(old).unwrap();
+        let WorkerOp::RegisterDrvOutput(Plain(reg), _) = &op else {
+            panic!("expected RegisterDrvOutput, got {op:?}");
+        };
+        assert_eq!(reg.drv_output, drv_output);
+        assert_eq!(reg.out_path, out_path);
+        assert_eq!(reg.realisation, Realisation::default());
+        let mut bytes = Vec::new();
+        bytes.write_nix_versioned(&op, old).unwrap();
+        assert_eq!(bytes, old_bytes);
+
+        let new_bytes = crate::to_vec(&(42u64, &realisation)).unwrap();
+        let op: WorkerOp = (&new_bytes[..]).read_nix_versioned(new).unwrap();
+        let WorkerOp::RegisterDrvOutput(Plain(reg), _) = &op else {
+            panic!("expected RegisterDrvOutput, got {op:?}");
+        };
+        assert_eq!(reg.realisation, realisation);
+        assert_eq!(reg.drv_output, NixString::default());
+        let mut bytes = Vec::new();
+        bytes.write_nix_versioned(&op, new).unwrap();
+        assert_eq!(bytes, new_bytes);
+    }
+
+    #[test]
+    fn test_build_paths_versions() {
+        let old = DaemonVersion {
+            major: 1,
+            minor: 29,
+        };
+        let new = DaemonVersion {
+            major: 1,
+            minor: 30,
+        };
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let derived = DerivedPath(NixString::from(b"/nix/store/abc-foo.drv!out,dev".to_vec()));
+
+        let old_bytes = crate::to_vec(&(9u64, vec![&path], 1u64)).unwrap();
+        let op: WorkerOp = (&old_bytes[..]).read_nix_versioned(old).unwrap();
+        let WorkerOp::BuildPaths(Plain(build), _) = &op else {
+            panic!("expected BuildPaths, got {op:?}");
+        };
+        assert_eq!(build.paths, vec![path]);
+        assert!(build.derived_paths.is_empty());
+        assert_eq!(build.build_mode, BuildMode::Repair);
+        let mut bytes = Vec::new();
+        bytes.write_nix_versioned(&op, old).unwrap();
+        assert_eq!(bytes, old_bytes);
+
+        let new_bytes = crate::to_vec(&(46u64, vec![&derived], 0u64)).unwrap();
+        let op: WorkerOp = (&new_bytes[..]).read_nix_versioned(new).unwrap();
+        let WorkerOp::BuildPathsWithResults(Plain(build), _) = &op else {
+            panic!("expected BuildPathsWithResults, got {op:?}");
+        };
+        assert_eq!(build.derived_paths, vec![derived]);
+        assert!(build.paths.is_empty());
+        assert_eq!(build.build_mode, BuildMode::Normal);
+        let mut bytes = Vec::new();
+        bytes.write_nix_versioned(&op, new).unwrap();
+        assert_eq!(bytes, new_bytes);
+    }
+
+    #[test]
+    fn test_build_result_versions() {
+        let result = BuildResult {
+            status: BuildStatus::Built,
+            error_msg: NixString::default(),
+            times_built: 1,
+            is_non_deterministic: false,
+            start_time: 100,
+            stop_time: 200,
+            built_outputs: DrvOutputs::default(),
+        };
+        // Before 1.29, the timings aren't sent.
+        let untimed = BuildResult {
+            times_built: 0,
+            start_time: 0,
+            stop_time: 0,
+            ..result.clone()
+        };
+        let status = (0u64, NixString::default());
+        let timings = (1u64, false, 100u64, 200u64);
+        let no_outputs = 0u64;
+        let cases = [
+            (27, untimed.clone(), crate::to_vec(&status).unwrap()),
+            (
+                28,
+                untimed,
+                crate::to_vec(&(status.clone(), no_outputs)).unwrap(),
+            ),
+            (
+                29,
+                result.clone(),
+                crate::to_vec(&(status, timings, no_outputs)).unwrap(),
+            ),
+        ];
+
+        for (minor, expected, wire) in cases {
+            let version = DaemonVersion { major: 1, minor };
+            let decoded: BuildResult = (&wire[..]).read_nix_versioned(version).unwrap();
+            assert_eq!(decoded, expected, "1.{minor}");
+            let mut bytes = Vec::new();
+            bytes.write_nix_versioned(&result, version).unwrap();
+            assert_eq!(bytes, wire, "1.{minor}");
+        }
+        assert_eq!(
+            crate::to_vec(&result).unwrap(),
+            crate::to_vec(&(
+                (0u64, NixString::default()),
+                (1u64, false, 100u64, 200u64),
+                0u64
+            ))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_build_paths_reply_versions() {
+        let op = WorkerOp::BuildPaths(
+            Plain(BuildPaths {
+                paths: Vec::new(),
+                derived_paths: Vec::new(),
+                build_mode: BuildMode::Normal,
+            }),
+            Resp::new(),
+        );
+        // The reply is followed by the next message, which must be left alone.
+        let bytes = crate::to_vec(&(1u64, 42u64)).unwrap();
+        for version in [crate::MIN_UPSTREAM_VERSION, PROTOCOL_VERSION] {
+            let mut read = &bytes[..];
+            let mut write = Vec::new();
+            op.proxy_response_with(&mut read, &mut write, version, None, SelfCheckPolicy::Fail)
+                .unwrap();
+            assert_eq!(write, crate::to_vec(&1u64).unwrap(), "{version}");
+            assert_eq!(read, crate::to_vec(&42u64).unwrap(), "{version}");
+        }
+    }
+
+    #[test]
+    fn test_derivation_output_map() {
+        let name = |s: &str| NixString::from(s.as_bytes().to_vec());
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let mut outputs = DerivationOutputMap {
+            paths: vec![
+                (name("out"), OptionalStorePath(Some(path.clone()))),
+                (name("dev"), OptionalStorePath(None)),
+            ],
+        };
+        let map = outputs.to_map().unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&name("out")], Some(path.clone()));
+        assert_eq!(map[&name("dev")], None);
+
+        outputs
+            .paths
+            .push((name("out"), OptionalStorePath(Some(path))));
+        let err = outputs.to_map().unwrap_err();
+        assert!(
+            matches!(&err, Error::DuplicateOutput(n) if *n == name("out")),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let op = WorkerOp::IsValidPath(Plain(path.clone()), Resp::new());
+        let bytes = crate::to_vec(&true).unwrap();
+        assert_eq!(
+            op.decode_response(&bytes, PROTOCOL_VERSION).unwrap(),
+            ResponseValue::IsValidPath(true)
+        );
+        // Trailing bytes mean that the reply wasn't what we expected.
+        let bytes = crate::to_vec(&(true, 0u64)).unwrap();
+        assert!(op.decode_response(&bytes, PROTOCOL_VERSION).is_err());
+
+        let info = QueryPathInfoResponse {
+            path: Some(ValidPathInfo {
+                deriver: OptionalStorePath(None),
+                hash: NarHash::from_bytes(&[0; 32]),
+                references: StorePathSet {
+                    paths: vec![path.clone()],
+                },
+                registration_time: 1700000000,
+                nar_size: 1024,
+                ultimate: true,
+                sigs: StringSet::default(),
+                content_address: NixString::default(),
+            }),
+        };
+        let op = WorkerOp::QueryPathInfo(Plain(path), Resp::new());
+        let bytes = crate::to_vec(&info).unwrap();
+        assert_eq!(
+            op.decode_response(&bytes, PROTOCOL_VERSION).unwrap(),
+            ResponseValue::QueryPathInfo(info)
+        );
+        assert!(op.decode_response(&bytes[..8], PROTOCOL_VERSION).is_err());
+    }
+
+    fn signed_path_info() -> (StorePath, ValidPathInfo) {
+        let path = StorePath(NixString::from(
+            b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo".to_vec(),
+        ));
+        let dep = |name: &str| StorePath(NixString::from(format!("/nix/store/{name}")));
+        let info = ValidPathInfo {
+            deriver: OptionalStorePath(None),
+            hash: NarHash::from_bytes(&[0; 32]),
+            references: StorePathSet {
+                paths: vec![
+                    dep("n1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar"),
+                    dep("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"),
+                ],
+            },
+            registration_time: 0,
+            nar_size: 120,
+            ultimate: false,
+            sigs: StringSet::default(),
+            content_address: NixString::default(),
+        };
+        (path, info)
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let (path, info) = signed_path_info();
+        assert_eq!(
+            String::from_utf8(info.fingerprint(&path).unwrap()).unwrap(),
+            "1;/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo;\
+             sha256:0000000000000000000000000000000000000000000000000000;120;\
+             /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo,\
+             /nix/store/n1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar"
+        );
+    }
+
+    #[cfg(feature = "signatures")]
+    #[test]
+    fn test_verify_signatures() {
+        use base64::Engine;
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let (path, mut info) = signed_path_info();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other_key = SigningKey::from_bytes(&[8; 32]);
+        let trusted = HashMap::from([("cache.example.org-1".to_owned(), key.verifying_key())]);
+        let sign = |name: &str, key: &SigningKey, info: &ValidPathInfo| {
+            let sig = key.sign(&info.fingerprint(&path).unwrap());
+            let sig = base64::engine::general_purpose::STANDARD.encode(sig.to_bytes());
+            NixString::from(format!("{name}:{sig}"))
+        };
+
+        let good = sign("cache.example.org-1", &key, &info);
+        let untrusted = sign("other.example.org-1", &other_key, &info);
+        let wrong_key = sign("cache.example.org-1", &other_key, &info);
+        let garbage = NixString::from(b"cache.example.org-1:not base64".to_vec());
+
+        info.sigs.paths = vec![untrusted.clone(), wrong_key, garbage];
+        assert!(!info.verify_signatures(&path, &trusted).unwrap());
+        info.sigs.paths.push(good);
+        assert!(info.verify_signatures(&path, &trusted).unwrap());
+
+        // The signature covers the size, so changing it breaks the signature.
+        info.nar_size += 1;
+        assert!(!info.verify_signatures(&path, &trusted).unwrap());
+    }
+
+    #[test]
+    fn test_unknown_build_status() {
+        let bytes = crate::to_vec(&99u64).unwrap();
+        let status: BuildStatus = crate::from_bytes(&bytes).unwrap();
+        assert_eq!(status, BuildStatus::Unknown(99));
+        assert_eq!(crate::to_vec(&status).unwrap(), bytes);
+
+        let status: BuildStatus = crate::from_bytes(&crate::to_vec(&14u64).unwrap()).unwrap();
+        assert_eq!(status, BuildStatus::NoSubstituters);
+    }
+
+    #[test]
+    fn test_unknown_verbosity() {
+        let bytes = crate::to_vec(&8u64).unwrap();
+        let verbosity: Verbosity = crate::from_bytes(&bytes).unwrap();
+        assert_eq!(verbosity, Verbosity::Unknown(8));
+        assert_eq!(crate::to_vec(&verbosity).unwrap(), bytes);
+        assert!(verbosity > Verbosity::Vomit);
+
+        let verbosity: Verbosity = crate::from_bytes(&crate::to_vec(&7u64).unwrap()).unwrap();
+        assert_eq!(verbosity, Verbosity::Vomit);
+    }
+
+    #[test]
+    fn test_query_missing_sorted() {
+        let paths = |names: &[&str]| StorePathSet {
+            paths: names
+                .iter()
+                .map(|n| StorePath(NixString::from(format!("/nix/store/{n}").into_bytes())))
+                .collect(),
+        };
+        let response = |will_build: &[&str], unknown: &[&str]| QueryMissingResponse {
+            will_build: paths(will_build),
+            will_substitute: paths(&[]),
+            unknown: paths(unknown),
+            download_size: 0,
+            nar_size: 0,
+        };
+
+        let a = response(
+            &["ccc-c.drv", "aaa-a.drv", "bbb-b.drv"],
+            &["eee-e", "ddd-d"],
+        );
+        let b = response(
+            &["bbb-b.drv", "ccc-c.drv", "aaa-a.drv"],
+            &["ddd-d", "eee-e"],
+        );
+        assert_ne!(a, b);
+        assert_eq!(a.sorted(), b.sorted());
+        assert_eq!(
+            a.sorted(),
+            response(
+                &["aaa-a.drv", "bbb-b.drv", "ccc-c.drv"],
+                &["ddd-d", "eee-e"]
+            )
+        );
+    }
+
+    #[test]
+    fn test_query_references() {
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let bytes = crate::to_vec(&(5u64, &path)).unwrap();
+        let op: WorkerOp = crate::from_bytes(&bytes).unwrap();
+        assert_eq!(op, WorkerOp::QueryReferences(Plain(path), Resp::new()));
+        assert_eq!(op.to_bytes().unwrap(), bytes);
+        assert!(op.dry_run_reply(PROTOCOL_VERSION).is_none());
+    }
+
+    #[test]
+    fn test_roundtrip_mismatch() {
+        let op = WorkerOp::IsValidPath(
+            Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            Resp::new(),
+        );
+
+        let mut forwarded = Vec::new();
+        op.proxy_response(&crate::to_vec(&1u64).unwrap()[..], &mut forwarded)
+            .unwrap();
+        assert_eq!(forwarded, crate::to_vec(&true).unwrap());
+
+        // Any non-zero value decodes as `true`, but `true` always encodes as 1.
+        let mut forwarded = Vec::new();
+        let err = op
+            .proxy_response(&crate::to_vec(&2u64).unwrap()[..], &mut forwarded)
+            .unwrap_err();
+        match err {
+            Error::RoundtripMismatch { op, expected, got } => {
+                assert_eq!(op, "IsValidPath");
+                assert_eq!(expected, crate::to_vec(&2u64).unwrap());
+                assert_eq!(got, crate::to_vec(&1u64).unwrap());
+            }
+            e => panic!("unexpected error {e:?}"),
+        }
+        assert!(forwarded.is_empty());
+
+        for policy in [SelfCheckPolicy::Warn, SelfCheckPolicy::Ignore] {
+            let mut forwarded = Vec::new();
+            op.proxy_response_with(
+                &crate::to_vec(&2u64).unwrap()[..],
+                &mut forwarded,
+                PROTOCOL_VERSION,
+                None,
+                policy,
+            )
+            .unwrap();
+            assert_eq!(forwarded, crate::to_vec(&true).unwrap(), "{policy:?}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Response to IsValidPath changed after a roundtrip")]
+    fn test_roundtrip_mismatch_panic() {
+        let op = WorkerOp::IsValidPath(
+            Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            Resp::new(),
+        );
+        let _ = op.proxy_response_with(
+            &crate::to_vec(&2u64).unwrap()[..],
+            std::io::sink(),
+            PROTOCOL_VERSION,
+            None,
+            SelfCheckPolicy::Panic,
+        );
+    }
+
+    #[test]
+    fn test_iter_reader() {
+        let add = WorkerOp::AddToStore(
+            WithFramedSource(AddToStore {
+                name: StorePath(NixString::from(b"foo".to_vec())),
+                cam_str: NixString::from(b"fixed:r:sha256".to_vec()),
+                refs: StorePathSet::default(),
+                repair: false,
+            }),
+            Resp::new(),
+        );
+        let is_valid = WorkerOp::IsValidPath(
+            Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            Resp::new(),
+        );
+
+        let mut bytes = crate::to_vec(&add).unwrap();
+        framed_data::FramedData {
+            data: vec![
+                ByteBuf::from(b"hello".to_vec()),
+                ByteBuf::from(vec![0; 100]),
+            ],
+        }
+        .write(&mut bytes)
+        .unwrap();
+        bytes.extend(crate::to_vec(&is_valid).unwrap());
+
+        let ops = WorkerOp::iter_reader(&bytes[..])
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(ops, vec![add, is_valid]);
+
+        // A truncated op is an error, not the end of the stream.
+        let mut ops = WorkerOp::iter_reader(&bytes[..bytes.len() - 4]);
+        assert!(ops.next().unwrap().is_ok());
+        assert!(ops.next().unwrap().is_err());
+        assert!(ops.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_derivation_output() {
+        use crate::content_address::{FileIngestionMethod, HashAlgo};
+
+        fn output(
+            store_path: &str,
+            method_or_hash: &str,
+            hash_or_impure: &str,
+        ) -> DerivationOutput {
+            DerivationOutput {
+                store_path: StorePath(NixString::from(store_path.as_bytes().to_vec())),
+                method_or_hash: NixString::from(method_or_hash.as_bytes().to_vec()),
+                hash_or_impure: NixString::from(hash_or_impure.as_bytes().to_vec()),
+            }
+        }
+        let path = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo";
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        assert_eq!(
+            output(path, "", "").parse().unwrap(),
+            DerivationOutputType::InputAddressed(StorePath(NixString::from(
+                path.as_bytes().to_vec()
+            )))
+        );
+        assert_eq!(
+            output(path, "r:sha256", hash).parse().unwrap(),
+            DerivationOutputType::Fixed {
+                method: CaMethod::Fixed(FileIngestionMethod::Recursive, HashAlgo::Sha256),
+                hash: NixString::from(hash.as_bytes().to_vec()),
+            }
+        );
+        assert_eq!(
+            output("", "text:sha256", "").parse().unwrap(),
+            DerivationOutputType::FloatingCa {
+                method: CaMethod::Text(HashAlgo::Sha256)
+            }
+        );
+        assert_eq!(
+            output("", "", "").parse().unwrap(),
+            DerivationOutputType::Deferred
+        );
+        assert_eq!(
+            output("", "sha1", "impure").parse().unwrap(),
+            DerivationOutputType::Impure {
+                method: CaMethod::Fixed(FileIngestionMethod::Flat, HashAlgo::Sha1)
+            }
+        );
+
+        assert!(output(path, "r:sha256", "").parse().is_err());
+        assert!(output("", "r:sha3", hash).parse().is_err());
+    }
+
+    #[test]
+    fn test_dry_run_reply() {
+        let gc = WorkerOp::CollectGarbage(
+            Plain(CollectGarbage {
+                action: GcAction::DeleteDead,
+                paths_to_delete: StorePathSet::default(),
+                ignore_liveness: false,
+                max_freed: u64::MAX,
+                _obsolete0: 0,
+                _obsolete1: 0,
+                _obsolete2: 0,
+            }),
+            Resp::new(),
+        );
+        let reply: CollectGarbageResponse =
+            crate::from_bytes(&gc.dry_run_reply(PROTOCOL_VERSION).unwrap().unwrap()).unwrap();
+        assert_eq!(reply.paths.paths, vec![]);
+        assert_eq!(reply.bytes_freed, 0);
+
+        let gc_root = Path(NixString::from(b"/home/user/result".to_vec()));
+        let add_root = WorkerOp::AddPermRoot(
+            Plain(AddPermRoot {
+                store_path: StorePath(NixString::from(b"/nix/store/abc-foo".to_vec())),
+                gc_root: gc_root.clone(),
+            }),
+            Resp::new(),
+        );
+        let reply: Path =
+            crate::from_bytes(&add_root.dry_run_reply(PROTOCOL_VERSION).unwrap().unwrap()).unwrap();
+        assert_eq!(reply, gc_root);
+
+        let add = WorkerOp::AddToStore(
+            WithFramedSource(AddToStore {
+                name: StorePath(NixString::from(b"foo".to_vec())),
+                cam_str: NixString::from(b"text:sha256".to_vec()),
+                refs: StorePathSet::default(),
+                repair: false,
+            }),
+            Resp::new(),
+        );
+        assert!(add.dry_run_reply(PROTOCOL_VERSION).unwrap().is_err());
+
+        let query = WorkerOp::QueryAllValidPaths(Plain(()), Resp::new());
+        assert!(query.dry_run_reply(PROTOCOL_VERSION).is_none());
+
+        // The reply is encoded for the version that the client speaks: before 1.28, a
+        // `BuildResult` is just the status and the error message.
+        let build = WorkerOp::BuildDerivation(
+            Plain(BuildDerivation {
+                store_path: StorePath(NixString::from(b"/nix/store/abc-foo.drv".to_vec())),
+                derivation: Derivation {
+                    outputs: Vec::new(),
+                    input_sources: StorePathSet::default(),
+                    platform: NixString::from(b"x86_64-linux".to_vec()),
+                    builder: Path(NixString::from(b"/bin/sh".to_vec())),
+                    args: StringSet::default(),
+                    env: Vec::new(),
+                },
+                build_mode: BuildMode::Normal,
+            }),
+            Resp::new(),
+        );
+        let old = DaemonVersion {
+            major: 1,
+            minor: 27,
+        };
+        assert_eq!(
+            build.dry_run_reply(old).unwrap().unwrap(),
+            crate::to_vec(&(0u64, NixString::default())).unwrap()
+        );
+        let reply = build.dry_run_reply(PROTOCOL_VERSION).unwrap().unwrap();
+        let mut read = &reply[..];
+        let result: BuildResult = read.read_nix().unwrap();
+        assert_eq!(result.status, BuildStatus::Built);
+        assert!(read.is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let version = PROTOCOL_VERSION;
+        let gc = |action, paths| {
+            WorkerOp::CollectGarbage(Plain(CollectGarbage::new(action, paths)), Resp::new())
+        };
+        assert!(gc(GcAction::DeleteDead, StorePathSet::default())
+            .validate(version)
+            .is_ok());
+        let err = gc(GcAction::DeleteSpecific, StorePathSet::default())
+            .validate(version)
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidGcRequest { .. }), "{err:?}");
+
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let build = WorkerOp::BuildPaths(
+            Plain(BuildPaths {
+                paths: vec![path],
+                derived_paths: Vec::new(),
+                build_mode: BuildMode::Normal,
+            }),
+            Resp::new(),
+        );
+        let old = DaemonVersion {
+            major: 1,
+            minor: 29,
+        };
+        assert!(build.validate(old).is_ok());
+        assert!(build.validate(version).is_err());
+    }
+
+    #[test]
+    fn test_gc_validate() {
+        let some_paths = StorePathSet {
+            paths: vec![StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))],
+        };
+        let actions = [
+            GcAction::ReturnLive,
+            GcAction::ReturnDead,
+            GcAction::DeleteDead,
+            GcAction::DeleteSpecific,
+        ];
+        for action in actions {
+            let specific = action == GcAction::DeleteSpecific;
+            let empty = CollectGarbage::new(action, StorePathSet::default());
+            assert_eq!(empty.validate().is_ok(), !specific, "{action:?}");
+            let non_empty = CollectGarbage::new(action, some_paths.clone());
+            assert_eq!(non_empty.validate().is_ok(), specific, "{action:?}");
+        }
+
+        let err = CollectGarbage::new(GcAction::DeleteDead, some_paths)
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid garbage collection request: DeleteDead with 1 paths to delete"
+        );
+    }
+
+    #[test]
+    fn test_query_missing_summary() {
+        let paths = |names: &[&str]| StorePathSet {
+            paths: names
+                .iter()
+                .map(|n| StorePath(NixString::from(format!("/nix/store/{n}").into_bytes())))
+                .collect(),
+        };
+        let missing = QueryMissingResponse {
+            will_build: paths(&["aaa-foo.drv"]),
+            will_substitute: paths(&["bbb-bar", "ccc-baz"]),
+            unknown: paths(&[]),
+            download_size: 3 << 19,
+            nar_size: 4 << 20,
+        };
+
+        let summary = missing.summary();
+        assert_eq!(
+            summary,
+            ClosureSummary {
+                will_build: 1,
+                will_substitute: 2,
+                unknown: 0,
+                download_size: 3 << 19,
+                nar_size: 4 << 20,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "1 path to build, 2 paths to fetch (1.50 MiB download, 4.00 MiB unpacked), 0 paths unknown"
+        );
+        assert!(missing.needs_action());
+
+        let nothing = QueryMissingResponse {
+            will_build: paths(&[]),
+            will_substitute: paths(&[]),
+            unknown: paths(&["ddd-qux"]),
+            download_size: 0,
+            nar_size: 0,
+        };
+        assert!(!nothing.needs_action());
+    }
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let op = WorkerOp::EnsurePath(
+            Plain(StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()))),
+            Resp::new(),
+        );
+        let bytes = op.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            crate::to_vec(&(10u64, NixString::from(b"/nix/store/abc-foo".to_vec()))).unwrap()
+        );
+        assert_eq!(WorkerOp::from_bytes(&bytes).unwrap(), op);
+    }
+
+    #[test]
+    fn test_framed_source_debug() {
+        let header = AddBuildLog {
+            path: StorePath(NixString::from(vec![b'a'; 1000])),
+        };
+        let debug = format!("{:?}", WithFramedSource(header.clone()));
+        assert_eq!(
+            debug,
+            format!("WithFramedSource({header:?}, +framed source)")
+        );
+    }
+
+    #[test]
+    fn test_decoded_ops_compare_equal() {
+        let path = StorePath(NixString::from(b"/nix/store/abc-foo".to_vec()));
+        let bytes =
+            crate::to_vec(&WorkerOp::IsValidPath(Plain(path.clone()), Resp::new())).unwrap();
+
+        let first: WorkerOp = crate::from_bytes(&bytes).unwrap();
+        let second: WorkerOp = crate::from_bytes(&bytes).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, WorkerOp::IsValidPath(Plain(path), Resp::new()));
+        assert_ne!(
+            first,
+            WorkerOp::IsValidPath(
+                Plain(StorePath(NixString::from(b"/nix/store/abc-bar".to_vec()))),
+                Resp::new()
+            )
+        );
+    }
+
+    #[test]
+    fn test_op_table() {
+        assert_eq!(OP_TABLE.len(), 31);
+        assert_eq!(
+            OP_TABLE[0],
+            OpInfo {
+                name: "IsValidPath",
+                opcode: 1,
+                request: "StorePath",
+                response: "bool",
+            }
+        );
+
+        // The opcodes are written out twice; check that they agree.
+        arbtest(|u| {
+            let op: WorkerOp = u.arbitrary()?;
+            let info = OP_TABLE.iter().find(|i| i.name == op.name()).unwrap();
+            let bytes = op.to_bytes().unwrap();
+            assert_eq!(bytes[..8], info.opcode.to_le_bytes());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_supported_opcodes() {
+        let opcodes = supported_opcodes();
+        assert_eq!(opcodes.len(), 31);
+        assert!(opcodes.contains(&1));
+        assert!(opcodes.contains(&47));
+        assert!(opcodes.windows(2).all(|w| w[0] < w[1]));
+        assert!(opcodes
+            .iter()
+            .zip(OP_TABLE)
+            .all(|(&opcode, info)| opcode == info.opcode));
+    }
+
     #[test]
     fn test_roundtrip() {
         arbtest(|u| {
@@ -558,4 +2195,33 @@ mod tests {
             Ok(())
         });
     }
+
+    // Every op survives a round trip through the oldest protocol that we proxy, once the
+    // fields that it doesn't have are dropped.
+    #[test]
+    fn test_roundtrip_min_version() {
+        let version = crate::MIN_UPSTREAM_VERSION;
+        arbtest(|u| {
+            let op: WorkerOp = u.arbitrary()?;
+            let mut bytes = Vec::new();
+            bytes.write_nix_versioned(&op, version).unwrap();
+            let decoded: WorkerOp = (&bytes[..]).read_nix_versioned(version).unwrap();
+            let mut again = Vec::new();
+            again.write_nix_versioned(&decoded, version).unwrap();
+            assert_eq!(bytes, again);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn from_bytes_rejects_trailing_bytes() {
+        let op = WorkerOp::QueryAllValidPaths(Plain(()), Resp::new());
+        let mut bytes = op.to_bytes().unwrap();
+        assert_eq!(WorkerOp::from_bytes(&bytes).unwrap(), op);
+
+        bytes.extend(0u64.to_le_bytes());
+        let err = WorkerOp::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("8 bytes left over"), "{err}");
+    }
 }
@@ -3,7 +3,12 @@ use proc_macro2::Span;
 use quote::quote;
 use syn::{parse_macro_input, DeriveInput, Fields, FieldsUnnamed, Ident};
 
-#[proc_macro_derive(TaggedSerde, attributes(tagged_serde))]
+/// Derive `Serialize` and `Deserialize` for an enum whose variants are tagged with
+/// `#[tagged_serde = TAG]`.
+///
+/// One variant holding just a `u64` can instead be marked `#[tagged_serde_fallback]`.
+/// Unknown tags decode into it, instead of failing, and it encodes as the tag it holds.
+#[proc_macro_derive(TaggedSerde, attributes(tagged_serde, tagged_serde_fallback))]
 pub fn derive(input: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(input);
     let ident = input.ident;
@@ -16,7 +21,14 @@ pub fn derive(input: TokenStream) -> TokenStream {
         // panic!("not an enum");
     };
 
-    let variants = input.variants.iter().map(|v| {
+    let is_fallback = |v: &syn::Variant| {
+        v.attrs
+            .iter()
+            .any(|attr| attr.meta.path().is_ident("tagged_serde_fallback"))
+    };
+    let fallback = input.variants.iter().find(|v| is_fallback(v)).map(|v| &v.ident);
+
+    let variants = input.variants.iter().filter(|v| !is_fallback(v)).map(|v| {
         let variant_name = &v.ident;
 
         let tag = v
@@ -57,7 +69,7 @@ pub fn derive(input: TokenStream) -> TokenStream {
         }
     });
 
-    let deser_variants = input.variants.iter().map(|v| {
+    let deser_variants = input.variants.iter().filter(|v| !is_fallback(v)).map(|v| {
         let variant_name = &v.ident;
 
         let tag = v
@@ -109,6 +121,23 @@ pub fn derive(input: TokenStream) -> TokenStream {
         }
     });
 
+    let (ser_fallback, deser_fallback) = match fallback {
+        Some(variant_name) => (
+            quote! {
+                #ident::#variant_name(tag) => tag.serialize(serializer),
+            },
+            quote! {
+                _ => Ok(#ident::#variant_name(tag)),
+            },
+        ),
+        None => (
+            quote! {},
+            quote! {
+                _ => Err(A::Error::custom(format!("unknown tag {} when deserializing {}", tag, stringify!(#ident)))),
+            },
+        ),
+    };
+
     // FIXME don't hardcode u64 in the deserializer tag
     let output = quote! {
         impl ::serde::Serialize for #ident {
@@ -117,7 +146,8 @@ pub fn derive(input: TokenStream) -> TokenStream {
                 S: ::serde::Serializer,
             {
                 match self {
-                    #( #variants ),*
+                    #( #variants, )*
+                    #ser_fallback
                 }
             }
         }
@@ -143,7 +173,7 @@ pub fn derive(input: TokenStream) -> TokenStream {
                             .ok_or_else(|| A::Error::custom("failed to read logger field tag"))?;
                         match tag {
                             #( #deser_variants ),*
-                            _ => Err(A::Error::custom(format!("unknown tag {} when deserializing {}", tag, stringify!(#ident)))),
+                            #deser_fallback
                         }
                     }
                 }
@@ -0,0 +1,126 @@
+//! The exact bytes of worker op requests, as a nix client sends them.
+//!
+//! Unlike the roundtrip tests, these pin down the encoding itself, so that a change to
+//! the serializer (or to an op's fields) that still roundtrips can't silently change
+//! what goes over the wire. The expected bytes are written out by hand, one 64-bit word
+//! per line, in the order that nix's `worker-protocol` code writes them.
+
+use nix_remote::{
+    serialize::{NixReadExt, NixWriteExt},
+    worker_op::{Plain, Resp, SetOptions, Verbosity, WorkerOp},
+    DaemonVersion, NixString, StorePath,
+};
+
+/// Parse whitespace-separated hex bytes, ignoring `#` comments.
+fn hex(s: &str) -> Vec<u8> {
+    s.lines()
+        .flat_map(|line| line.split('#').next().unwrap().split_whitespace())
+        .flat_map(|word| {
+            (0..word.len())
+                .step_by(2)
+                .map(move |i| u8::from_str_radix(&word[i..i + 2], 16).unwrap())
+        })
+        .collect()
+}
+
+fn path() -> StorePath {
+    StorePath(NixString::from(
+        b"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo".to_vec(),
+    ))
+}
+
+// The path above, as a string: its length (47), the bytes, and one byte of padding.
+const PATH: &str = "
+    2f00000000000000
+    2f6e69782f73746f  # /nix/sto
+    72652f6731773768  # re/g1w7h
+    7933716731773768  # y3qg1w7h
+    7933716731773768  # y3qg1w7h
+    7933716731773768  # y3qg1w7h
+    7933712d666f6f00  # y3q-foo
+";
+
+#[test]
+fn golden_ops() {
+    let table = [
+        (
+            WorkerOp::IsValidPath(Plain(path()), Resp::new()),
+            hex(&format!("0100000000000000 {PATH}")),
+        ),
+        (
+            WorkerOp::QueryPathInfo(Plain(path()), Resp::new()),
+            hex(&format!("1a00000000000000 {PATH}")),
+        ),
+    ];
+    for (op, expected) in table {
+        assert_eq!(op.to_bytes().unwrap(), expected, "{op:?}");
+        assert_eq!(WorkerOp::from_bytes(&expected).unwrap(), op);
+    }
+}
+
+#[test]
+fn golden_set_options() {
+    let fields = "
+        1300000000000000  # SetOptions
+        0100000000000000  # keep failing
+        0000000000000000  # keep going
+        0100000000000000  # try fallback
+        0300000000000000  # verbosity: info
+        0400000000000000  # max build jobs
+        2c01000000000000  # max silent time: 300
+        0000000000000000  # use build hook (obsolete)
+        0000000000000000  # build verbosity: error
+        0000000000000000  # log type (obsolete)
+        0000000000000000  # print build trace (obsolete)
+        0800000000000000  # build cores
+        0100000000000000  # use substitutes
+    ";
+    let options = "
+        0100000000000000  # one option
+        0500000000000000 636f726573000000  # cores
+        0100000000000000 3800000000000000  # 8
+    ";
+    let versions = [
+        // Before 1.12 there are no extra options.
+        (11, hex(fields)),
+        (12, hex(&format!("{fields} {options}"))),
+        (34, hex(&format!("{fields} {options}"))),
+    ];
+
+    for (minor, expected) in versions {
+        let version = DaemonVersion { major: 1, minor };
+        let op: WorkerOp = (&expected[..]).read_nix_versioned(version).unwrap();
+        let WorkerOp::SetOptions(Plain(set), _) = &op else {
+            panic!("expected SetOptions, got {op:?}");
+        };
+        check_set_options(set, minor >= 12);
+
+        let mut bytes = Vec::new();
+        bytes.write_nix_versioned(&op, version).unwrap();
+        assert_eq!(bytes, expected, "1.{minor}");
+        if minor == 34 {
+            assert_eq!(op.to_bytes().unwrap(), expected);
+        }
+    }
+}
+
+fn check_set_options(set: &SetOptions, has_options: bool) {
+    assert!(set.keep_failing);
+    assert!(!set.keep_going);
+    assert!(set.try_fallback);
+    assert_eq!(set.verbosity, Verbosity::Info);
+    assert_eq!(set.max_build_jobs, 4);
+    assert_eq!(set.max_silent_time, 300);
+    assert_eq!(set.build_verbosity, Verbosity::Error);
+    assert_eq!(set.build_cores, 8);
+    assert!(set.use_substitutes);
+    let options = if has_options {
+        vec![(
+            NixString::from(b"cores".to_vec()),
+            NixString::from(b"8".to_vec()),
+        )]
+    } else {
+        vec![]
+    };
+    assert_eq!(set.options, options);
+}
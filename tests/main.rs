@@ -4,7 +4,7 @@ use expect_test::{expect, Expect};
 use nix_remote::{
     serialize::{NixReadExt, NixWriteExt},
     worker_op::{BuildMode, BuildResult},
-    DerivedPath, NixString, Realisation, StorePath, ValidPathInfoWithPath,
+    DerivedPath, NixString, OptionalStorePath, Realisation, StorePath, ValidPathInfoWithPath,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
@@ -231,8 +231,8 @@ fn valid_path_info() {
                         /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar,
                     ),
                     info: ValidPathInfo {
-                        deriver: StorePath(
-                            ,
+                        deriver: OptionalStorePath(
+                            None,
                         ),
                         hash: NarHash {
                             data: [
@@ -319,8 +319,12 @@ fn valid_path_info() {
                         /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar,
                     ),
                     info: ValidPathInfo {
-                        deriver: StorePath(
-                            /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                        deriver: OptionalStorePath(
+                            Some(
+                                StorePath(
+                                    /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv,
+                                ),
+                            ),
                         ),
                         hash: NarHash {
                             data: [
@@ -417,8 +421,8 @@ fn valid_path_info() {
                         /nix/store/n5wkd9frr45pa74if5gpz9j7mifg27fh-foo,
                     ),
                     info: ValidPathInfo {
-                        deriver: StorePath(
-                            ,
+                        deriver: OptionalStorePath(
+                            None,
                         ),
                         hash: NarHash {
                             data: [
@@ -602,15 +606,19 @@ fn set() {
 
 #[test]
 fn optional_store_path() {
-    check::<(StorePath, StorePath)>(
+    check::<(OptionalStorePath, OptionalStorePath)>(
         include_bytes!("data/worker-protocol/optional-store-path.bin"),
         expect![[r#"
             (
-                StorePath(
-                    ,
+                OptionalStorePath(
+                    None,
                 ),
-                StorePath(
-                    /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-bar,
+                OptionalStorePath(
+                    Some(
+                        StorePath(
+                            /nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-bar,
+                        ),
+                    ),
                 ),
             )
         "#]],
@@ -0,0 +1,92 @@
+//! Serving clients over sockets, with [`NixProxy::over_tcp`] and [`NixProxy::over_unix`].
+//!
+//! These proxy to the local `nix-daemon`, so this lives in its own test binary: it puts
+//! a fake daemon on the `PATH`, which would affect other tests that spawn commands.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+};
+
+use nix_remote::{stderr, to_vec, NixProxy, NixString};
+
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+const WORKER_MAGIC_2: u64 = 0x6478696f;
+const VERSION: u64 = (1 << 8) | 34;
+
+/// Put a `nix-daemon` on the `PATH` that completes the handshake and then waits.
+fn install_fake_daemon() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("nix-remote-fake-daemon-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let handshake = to_vec(&(
+        WORKER_MAGIC_2,
+        VERSION,
+        NixString::from(b"fake".to_vec()),
+        stderr::Msg::Last(()),
+    ))
+    .unwrap();
+    std::fs::write(dir.join("handshake"), handshake).unwrap();
+
+    let script = dir.join("nix-daemon");
+    let contents = format!(
+        "#!/bin/sh\ncat '{}'\nexec cat >/dev/null\n",
+        dir.join("handshake").display()
+    );
+    std::fs::write(&script, contents).unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![dir];
+    paths.extend(std::env::split_paths(&path));
+    std::env::set_var("PATH", std::env::join_paths(&paths).unwrap());
+    paths.swap_remove(0)
+}
+
+/// Shake hands with the proxy, and check that it answers.
+fn client_handshake(mut stream: impl Read + Write) {
+    stream
+        .write_all(&to_vec(&(WORKER_MAGIC_1, VERSION, 0u64, 0u64)).unwrap())
+        .unwrap();
+    let expected = to_vec(&(
+        WORKER_MAGIC_2,
+        VERSION,
+        NixString::from(b"rust-nix-bazel-0.1.0".to_vec()),
+        stderr::Msg::Last(()),
+    ))
+    .unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, expected);
+}
+
+#[test]
+fn proxy_over_sockets() {
+    let dir = install_fake_daemon();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let proxy = std::thread::spawn(move || {
+        NixProxy::over_tcp(server)
+            .unwrap()
+            .process_connection()
+            .unwrap()
+    });
+    client_handshake(&client);
+    client.shutdown(std::net::Shutdown::Both).unwrap();
+    assert_eq!(proxy.join().unwrap().ops_processed, 0);
+
+    let (client, server) = UnixStream::pair().unwrap();
+    let proxy = std::thread::spawn(move || {
+        NixProxy::over_unix(server)
+            .unwrap()
+            .process_connection()
+            .unwrap()
+    });
+    client_handshake(&client);
+    client.shutdown(std::net::Shutdown::Both).unwrap();
+    assert_eq!(proxy.join().unwrap().ops_processed, 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
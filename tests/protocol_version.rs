@@ -0,0 +1,45 @@
+//! Overriding the advertised protocol version through the environment.
+//!
+//! This lives in its own test binary because it sets an environment variable, which
+//! would affect any proxies created concurrently by other tests.
+
+use std::io::Cursor;
+
+use nix_remote::{to_vec, NixProxy};
+
+const WORKER_MAGIC_1: u64 = 0x6e697863;
+const WORKER_MAGIC_2: u64 = 0x6478696f;
+
+// The version that the proxy advertises when it isn't overridden.
+const DEFAULT_VERSION: u64 = (1 << 8) | 34;
+
+// Run a handshake with a client that speaks `client_version`, and return the version
+// that the proxy advertised.
+fn advertised(client_version: u64) -> u64 {
+    let client = to_vec(&(WORKER_MAGIC_1, client_version, 0u64, 0u64)).unwrap();
+    let mut to_client = Vec::new();
+    let mut proxy = NixProxy::from_io(
+        Cursor::new(client),
+        &mut to_client,
+        std::io::empty(),
+        std::io::sink(),
+    );
+    proxy.handshake().unwrap();
+    drop(proxy);
+
+    assert_eq!(to_client[..8], to_vec(&WORKER_MAGIC_2).unwrap()[..]);
+    u64::from_le_bytes(to_client[8..16].try_into().unwrap())
+}
+
+#[test]
+fn protocol_version_from_env() {
+    std::env::set_var("NIX_REMOTE_RUST_PROTOCOL_VERSION", "1.30");
+    let version = (1 << 8) | 30;
+    assert_eq!(advertised(version), version);
+
+    // Versions that we don't speak, and ones that aren't versions at all, are ignored.
+    for ignored in ["1.99", "1.20", "2.0", "latest"] {
+        std::env::set_var("NIX_REMOTE_RUST_PROTOCOL_VERSION", ignored);
+        assert_eq!(advertised(DEFAULT_VERSION), DEFAULT_VERSION, "{ignored}");
+    }
+}